@@ -0,0 +1,395 @@
+//! Access log, a record per finished TCP session or UDP association
+//!
+//! The records are written by a thread of their own, so the relays never wait for the disk, and
+//! they are dropped if it falls too far behind. A file is rotated by renaming it to `<path>.1`,
+//! shifting the older ones up to `<path>.<max_files>`.
+
+use crate::{
+    metrics::METRICS,
+    session::{CloseReason, Session},
+    utils::config::{AccessLogConfig, AccessLogFormat, AccessLogTarget},
+};
+use serde::Serialize;
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+/// Records queued for the writer thread, the new ones are dropped when it is full
+const QUEUE_SIZE: usize = 4096;
+
+/// Sink of the access records, the pending records are written before it is dropped
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// Always `Some` until dropped
+    sender: Option<mpsc::SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    /// Open the target of `config`, the file is created if it does not exist
+    pub fn open(config: &AccessLogConfig) -> io::Result<AccessLog> {
+        let mut output = match config.target {
+            AccessLogTarget::Stdout => Output::Stdout,
+            AccessLogTarget::File(ref path) => Output::File(RotatingFile::open(
+                path.clone(),
+                config.max_size,
+                config.max_files,
+            )?),
+        };
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        let writer = thread::Builder::new()
+            .name("access-log".to_owned())
+            .spawn(move || {
+                while let Ok(line) = receiver.recv() {
+                    let mut result = output.write_line(&line);
+                    // write the pending records at once
+                    while let Ok(line) = receiver.try_recv() {
+                        result = result.and_then(|_| output.write_line(&line));
+                    }
+                    if let Err(e) = result.and_then(|_| output.flush()) {
+                        log::error!("write access log error: {}", e);
+                    }
+                }
+            })?;
+        Ok(AccessLog {
+            format: config.format,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Record finished `session`
+    pub fn record(&self, session: &Session) {
+        let line = AccessRecord::new(session, SystemTime::now()).format(self.format);
+        if let Some(ref sender) = self.sender
+            && let Err(mpsc::TrySendError::Full(_)) = sender.try_send(line)
+        {
+            // the writer thread only stops when `self` is dropped, so it can only be full
+            METRICS.inc_access_log_drop();
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // the writer thread stops once the channel is closed and drained
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            // not blocking the runtime on reload, which still waits for it on shutdown
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(move || writer.join())),
+                Err(_) => {
+                    let _ = writer.join();
+                }
+            }
+        }
+    }
+}
+
+/// A line of the access log
+#[derive(Debug, Serialize)]
+struct AccessRecord {
+    /// RFC 3339, UTC
+    start: String,
+    end: String,
+    duration_ms: u64,
+    protocol: &'static str,
+    listener: SocketAddr,
+    client: SocketAddr,
+    /// Original destination
+    destination: SocketAddr,
+    domain: Option<String>,
+    outbound: String,
+    upstream: Option<String>,
+    uploaded: u64,
+    downloaded: u64,
+    close_reason: CloseReason,
+    error: Option<String>,
+}
+
+impl AccessRecord {
+    fn new(session: &Session, end: SystemTime) -> AccessRecord {
+        let info = session.info();
+        let (close_reason, error) = session.close_reason();
+        let start = session.started_at();
+        AccessRecord {
+            start: humantime::format_rfc3339_millis(start).to_string(),
+            end: humantime::format_rfc3339_millis(end).to_string(),
+            duration_ms: end.duration_since(start).unwrap_or_default().as_millis() as u64,
+            protocol: info.protocol,
+            listener: info.listener,
+            client: info.client,
+            destination: info.destination,
+            domain: info.domain,
+            outbound: info.outbound,
+            upstream: info.upstream,
+            uploaded: info.uploaded,
+            downloaded: info.downloaded,
+            close_reason,
+            error,
+        }
+    }
+
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            AccessLogFormat::Logfmt => self.logfmt(),
+        }
+    }
+
+    /// `key=value` pairs, the absent values are omitted
+    fn logfmt(&self) -> String {
+        let mut line = String::new();
+        let mut field = |key: &str, value: &str| {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            let _ = write!(line, "{key}=");
+            let quote = value.is_empty()
+                || value
+                    .chars()
+                    .any(|c| c == ' ' || c == '"' || c == '=' || c.is_control());
+            match quote {
+                true => {
+                    line.push('"');
+                    for c in value.chars() {
+                        match c {
+                            '"' => line.push_str("\\\""),
+                            '\\' => line.push_str("\\\\"),
+                            '\n' => line.push_str("\\n"),
+                            c => line.push(c),
+                        }
+                    }
+                    line.push('"');
+                }
+                false => line.push_str(value),
+            }
+        };
+        field("start", &self.start);
+        field("end", &self.end);
+        field("duration_ms", &self.duration_ms.to_string());
+        field("protocol", self.protocol);
+        field("listener", &self.listener.to_string());
+        field("client", &self.client.to_string());
+        field("destination", &self.destination.to_string());
+        if let Some(ref domain) = self.domain {
+            field("domain", domain);
+        }
+        field("outbound", &self.outbound);
+        if let Some(ref upstream) = self.upstream {
+            field("upstream", upstream);
+        }
+        field("uploaded", &self.uploaded.to_string());
+        field("downloaded", &self.downloaded.to_string());
+        field("close_reason", self.close_reason.as_str());
+        if let Some(ref error) = self.error {
+            field("error", error);
+        }
+        line
+    }
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(line.as_bytes())?;
+                stdout.write_all(b"\n")
+            }
+            Output::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Stdout => io::stdout().flush(),
+            Output::File(file) => file.file.flush(),
+        }
+    }
+}
+
+/// A file rotated by size
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// Current size of the file
+    size: u64,
+    /// 0 disables rotation
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file: BufWriter::new(file),
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Move the file to `<path>.1`, or remove it if no rotated file is kept, and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for i in (1..self.max_files).rev() {
+            match fs::rename(rotated_path(&self.path, i), rotated_path(&self.path, i + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        match self.max_files {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated_path(&self.path, 1))?,
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// `<path>.<i>`
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut name = OsString::from(path);
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+#[test]
+fn test_access_record() {
+    use crate::{
+        router::Protocol,
+        session::{SessionMeta, Sessions},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let sessions = Arc::new(Sessions::new());
+    let session = sessions.register(SessionMeta {
+        protocol: Protocol::Tcp,
+        listener: "127.0.0.1:12345".parse().unwrap(),
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "1.2.3.4:443".parse().unwrap(),
+        domain: Some("www.example.com".to_owned()),
+        outbound: "proxy".to_owned(),
+        upstream: Some("socks5 127.0.0.1:1080".to_owned()),
+    });
+    session.add_uploaded(10);
+    session.add_downloaded(20);
+    session.set_close_reason(CloseReason::Error, Some("connection reset".to_owned()));
+    session.close(CloseReason::Admin);
+
+    let end = session.started_at() + Duration::from_millis(1500);
+    let record = AccessRecord::new(&session, end);
+    let json: serde_json::Value =
+        serde_json::from_str(&record.format(AccessLogFormat::Json)).unwrap();
+    assert_eq!(json["duration_ms"], 1500);
+    assert_eq!(json["domain"], "www.example.com");
+    assert_eq!(json["close_reason"], "error");
+
+    let logfmt = record.format(AccessLogFormat::Logfmt);
+    assert!(logfmt.starts_with("start="));
+    assert!(logfmt.ends_with(
+        " duration_ms=1500 protocol=tcp listener=127.0.0.1:12345 client=192.168.1.2:40000 \
+         destination=1.2.3.4:443 domain=www.example.com outbound=proxy \
+         upstream=\"socks5 127.0.0.1:1080\" uploaded=10 downloaded=20 close_reason=error \
+         error=\"connection reset\""
+    ));
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = std::env::temp_dir().join(format!("rustsocks-access-log-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+
+    let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+    for line in ["1111", "2222", "3333", "4444", "5555"] {
+        file.write_line(line).unwrap();
+    }
+    file.file.flush().unwrap();
+    let read = |i| match i {
+        0 => fs::read_to_string(&path).unwrap(),
+        i => fs::read_to_string(rotated_path(&path, i)).unwrap(),
+    };
+    assert_eq!(read(0), "5555\n");
+    assert_eq!(read(1), "3333\n4444\n");
+    assert_eq!(read(2), "1111\n2222\n");
+    assert!(!rotated_path(&path, 3).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_drop_in_runtime() {
+    use crate::{
+        router::Protocol,
+        session::{SessionMeta, Sessions},
+    };
+    use std::{sync::Arc, time::Duration};
+
+    let dir = std::env::temp_dir().join(format!("rustsocks-access-drop-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("access.log");
+    let log = AccessLog::open(&AccessLogConfig {
+        target: AccessLogTarget::File(path.clone()),
+        format: AccessLogFormat::Json,
+        max_size: 0,
+        max_files: 0,
+    })
+    .unwrap();
+
+    let sessions = Arc::new(Sessions::new());
+    let session = sessions.register(SessionMeta {
+        protocol: Protocol::Tcp,
+        listener: "127.0.0.1:12345".parse().unwrap(),
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "1.2.3.4:443".parse().unwrap(),
+        domain: None,
+        outbound: "direct".to_owned(),
+        upstream: None,
+    });
+    log.record(&session);
+    // the writer is joined on a blocking thread, the pending record is still written
+    drop(log);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while fs::read_to_string(&path).unwrap().lines().count() < 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,284 @@
+//! Local admin API over HTTP/1.1, on a TCP address or a Unix socket
+//!
+//! - `GET /sessions`: active TCP sessions and UDP associations in JSON
+//! - `DELETE /sessions/<id>`: close a session
+//! - `DELETE /sessions`: close all the sessions
+//! - `GET /metrics`: Prometheus metrics, see `crate::metrics`
+//!
+//! There is no authentication, it should only be reachable by the administrators.
+
+use crate::{
+    metrics::METRICS,
+    session::{CloseReason, SessionInfo, Sessions},
+    utils::config::AdminAddr,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
+    sync::watch,
+    time,
+};
+
+/// The maximum size of a request header
+const MAXIMUM_REQUEST_SIZE: usize = 8192;
+const MAXIMUM_HEADERS: usize = 32;
+/// The maximum time of reading a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listening socket of the admin API
+pub enum AdminListener {
+    Tcp(TcpListener),
+    /// The socket file is removed when the API stops
+    Unix(UnixListener, PathBuf),
+}
+
+impl AdminListener {
+    /// Listen on `addr`, replacing the stale Unix socket file left by a previous run
+    pub async fn bind(addr: &AdminAddr) -> io::Result<AdminListener> {
+        match addr {
+            AdminAddr::Tcp(addr) => TcpListener::bind(addr).await.map(AdminListener::Tcp),
+            AdminAddr::Unix(path) => {
+                if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                Ok(AdminListener::Unix(listener, path.clone()))
+            }
+        }
+    }
+}
+
+/// Serve the admin API on `listener`
+///
+/// Once `shutdown` is set (or its sender is dropped), `listener` and `shutdown` are dropped, so
+/// `watch::Sender::closed()` resolves when the listening address can be bound again.
+pub async fn run(
+    listener: AdminListener,
+    sessions: Arc<Sessions>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            accept_result = accept(&listener, sessions.clone()) => {
+                if let Err(e) = accept_result {
+                    log::error!("admin API accept error: {}", e);
+                }
+            }
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    if let AdminListener::Unix(_, ref path) = listener {
+        remove_socket_file(path);
+    }
+}
+
+/// Accept a connection and serve it in a new task
+async fn accept(listener: &AdminListener, sessions: Arc<Sessions>) -> io::Result<()> {
+    match listener {
+        AdminListener::Tcp(listener) => {
+            let (stream, peer_addr) = listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &sessions).await {
+                    log::debug!("admin API connection from {} error: {}", peer_addr, e);
+                }
+            });
+        }
+        AdminListener::Unix(listener, _) => {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &sessions).await {
+                    log::debug!("admin API connection error: {}", e);
+                }
+            });
+        }
+    }
+    Ok(())
+}
+
+fn remove_socket_file(path: &Path) {
+    if let Err(e) = std::fs::remove_file(path) {
+        log::warn!("remove admin socket {} error: {}", path.display(), e);
+    }
+}
+
+/// A response of the admin API
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(status: u16, body: &T) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(body).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, &json!({ "error": message }))
+    }
+}
+
+/// Serve one request on `stream`, then close it
+async fn serve<S>(mut stream: S, sessions: &Sessions) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let response = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok((method, path))) => {
+            log::debug!("admin API request {} {}", method, path);
+            handle_request(&method, &path, sessions)
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+            Response::error(400, &e.to_string())
+        }
+        Ok(Err(e)) => return Err(e),
+        Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    };
+    let header = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request header, returns the method and the path. The body is ignored
+async fn read_request<S>(stream: &mut S) -> io::Result<(String, String)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAXIMUM_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                // method and path are always set in a complete request
+                let method = req.method.unwrap_or_default().to_owned();
+                let path = req.path.unwrap_or_default().to_owned();
+                return Ok((method, path));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAXIMUM_REQUEST_SIZE => {}
+            Ok(httparse::Status::Partial) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request header too large",
+                ));
+            }
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+fn handle_request(method: &str, path: &str, sessions: &Sessions) -> Response {
+    // the query string is not used
+    let path = path.split('?').next().unwrap_or_default();
+    let path = path.trim_end_matches('/');
+    match (method, path) {
+        ("GET", "/sessions") => {
+            let (tcp, udp): (Vec<SessionInfo>, Vec<SessionInfo>) = sessions
+                .list()
+                .into_iter()
+                .partition(|s| s.protocol == "tcp");
+            Response::json(200, &json!({ "tcp": tcp, "udp": udp }))
+        }
+        ("DELETE", "/sessions") => {
+            let closed = sessions.close_all(None, CloseReason::Admin);
+            log::info!("admin API closed all the {} sessions", closed);
+            Response::json(200, &json!({ "closed": closed }))
+        }
+        (_, "/sessions") => Response::error(405, "method not allowed"),
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: METRICS.render(sessions),
+        },
+        (_, "/metrics") => Response::error(405, "method not allowed"),
+        (method, path) => match path.strip_prefix("/sessions/") {
+            Some(id) => {
+                let Ok(id) = id.parse() else {
+                    return Response::error(404, "session not found");
+                };
+                if method != "DELETE" {
+                    return Response::error(405, "method not allowed");
+                }
+                match sessions.close(id, CloseReason::Admin) {
+                    true => {
+                        log::info!("admin API closed session {}", id);
+                        Response::json(200, &json!({ "closed": 1 }))
+                    }
+                    false => Response::error(404, "session not found"),
+                }
+            }
+            None => Response::error(404, "not found"),
+        },
+    }
+}
+
+#[test]
+fn test_handle_request() {
+    use crate::{router::Protocol, session::SessionMeta};
+
+    let sessions = Arc::new(Sessions::new());
+    let session = sessions.register(SessionMeta {
+        protocol: Protocol::Udp,
+        listener: "127.0.0.1:12345".parse().unwrap(),
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "8.8.8.8:53".parse().unwrap(),
+        domain: None,
+        outbound: "direct".to_owned(),
+        upstream: None,
+    });
+
+    let response = handle_request("GET", "/sessions/", &sessions);
+    assert_eq!(response.status, 200);
+    let body: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+    assert_eq!(body["tcp"].as_array().unwrap().len(), 0);
+    assert_eq!(body["udp"][0]["id"], session.id);
+    assert_eq!(body["udp"][0]["destination"], "8.8.8.8:53");
+
+    let path = format!("/sessions/{}", session.id);
+    assert_eq!(handle_request("GET", &path, &sessions).status, 405);
+    assert_eq!(handle_request("DELETE", &path, &sessions).status, 200);
+    assert_eq!(
+        handle_request("DELETE", "/sessions/0", &sessions).status,
+        404
+    );
+    assert_eq!(handle_request("POST", "/sessions", &sessions).status, 405);
+    assert_eq!(handle_request("GET", "/", &sessions).status, 404);
+
+    let response = handle_request("GET", "/metrics", &sessions);
+    assert_eq!(response.status, 200);
+    assert!(response.body.contains("rustsocks_udp_associations 1\n"));
+}
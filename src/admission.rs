@@ -0,0 +1,154 @@
+//! File descriptor limit, and admission of new flows within it
+//!
+//! A TCP session holds 2 descriptors, the client and the outbound connections, and a UDP
+//! association about 2, its outbound socket and the socket sending the replies back. New flows
+//! beyond the descriptors available are rejected, instead of failing `accept()` with `EMFILE`.
+
+use libc::{RLIMIT_NOFILE, rlim_t, rlimit};
+use std::{
+    fs, io,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+/// The lowest `RLIMIT_NOFILE` to run reliably
+pub const MINIMUM_FD_LIMIT: u64 = 4096;
+/// Descriptors kept for the listeners, the DNS server, the admin API, the logs and the reloads
+const RESERVED_FDS: u64 = 256;
+const FDS_PER_FLOW: u64 = 2;
+
+fn get_fd_limit() -> io::Result<rlimit> {
+    let mut limit = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+fn set_fd_limit(limit: &rlimit) -> io::Result<()> {
+    if unsafe { libc::setrlimit(RLIMIT_NOFILE, limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Raise the soft `RLIMIT_NOFILE` to `target`, or the hard limit by default, returns the soft
+/// limit in effect
+///
+/// The hard limit is only raised with `CAP_SYS_RESOURCE` or root. If `target` can't be set, the
+/// highest limit allowed is used, the limit is never lowered.
+pub fn raise_fd_limit(target: Option<u64>) -> io::Result<u64> {
+    let limit = get_fd_limit()?;
+    let target = target.map_or(limit.rlim_max, |target| target as rlim_t);
+    let mut candidates = vec![target];
+    if target > limit.rlim_max {
+        candidates.push(limit.rlim_max);
+    }
+    // the unlimited hard limit of macOS is still capped by `kern.maxfilesperproc`
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    candidates.push(libc::OPEN_MAX as rlim_t);
+
+    for cur in candidates {
+        if cur <= limit.rlim_cur {
+            break;
+        }
+        let new = rlimit {
+            rlim_cur: cur,
+            rlim_max: limit.rlim_max.max(cur),
+        };
+        match set_fd_limit(&new) {
+            Ok(()) => {
+                log::info!(
+                    "file descriptor limit is raised from {} to {}",
+                    limit.rlim_cur,
+                    cur
+                );
+                return Ok(new.rlim_cur as u64);
+            }
+            Err(e) => log::debug!("raise file descriptor limit to {} error: {}", cur, e),
+        }
+    }
+    Ok(limit.rlim_cur as u64)
+}
+
+/// Limit of the concurrent TCP sessions and UDP associations
+#[derive(Debug)]
+pub struct Admission {
+    limit: usize,
+    active: AtomicUsize,
+}
+
+impl Admission {
+    pub fn new(limit: usize) -> Admission {
+        Admission {
+            limit,
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    /// Limit of the descriptors available now: the soft `RLIMIT_NOFILE`, without the open and
+    /// reserved ones
+    pub fn from_fd_limit() -> io::Result<Admission> {
+        let limit = get_fd_limit()?.rlim_cur as u64;
+        let open = fs::read_dir("/dev/fd").map_or(0, |dir| dir.count() as u64);
+        let available = limit.saturating_sub(open + RESERVED_FDS);
+        let flows = usize::try_from(available / FDS_PER_FLOW).unwrap_or(usize::MAX);
+        log::info!(
+            "admitting up to {} TCP sessions and UDP associations ({} file descriptors available)",
+            flows,
+            available
+        );
+        Ok(Admission::new(flows))
+    }
+
+    /// Admit a new flow, `None` if the limit is reached
+    pub fn try_acquire(self: &Arc<Self>) -> Option<AdmissionPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.limit).then_some(n + 1)
+            })
+            .ok()?;
+        Some(AdmissionPermit(self.clone()))
+    }
+}
+
+impl Default for Admission {
+    /// Unlimited
+    fn default() -> Self {
+        Admission::new(usize::MAX)
+    }
+}
+
+/// An admitted flow, released when dropped
+#[derive(Debug)]
+pub struct AdmissionPermit(Arc<Admission>);
+
+impl Drop for AdmissionPermit {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[test]
+fn test_admission() {
+    let admission = Arc::new(Admission::new(2));
+    let a = admission.try_acquire().unwrap();
+    let _b = admission.try_acquire().unwrap();
+    assert!(admission.try_acquire().is_none());
+
+    drop(a);
+    let _c = admission.try_acquire().unwrap();
+    assert!(admission.try_acquire().is_none());
+}
+
+#[test]
+fn test_raise_fd_limit() {
+    let limit = get_fd_limit().unwrap();
+    // never lowered
+    assert_eq!(raise_fd_limit(Some(1)).unwrap(), limit.rlim_cur as u64);
+}
@@ -0,0 +1,172 @@
+//! Cache of DNS responses, kept for the TTLs of their records
+
+use hickory_proto::{
+    op::{Message, ResponseCode},
+    rr::{RData, Record},
+};
+use lru_time_cache::LruCache;
+use std::{sync::Mutex, time::Instant};
+
+/// The maximum number of cached responses
+const DNS_CACHE_CAPACITY: usize = 4096;
+/// The maximum TTL of negative responses, RFC 2308 recommends 1 to 3 hours, but they are more
+/// likely to change
+const MAXIMUM_NEGATIVE_TTL: u32 = 300;
+
+/// Lowercase name, record type and class of the query
+type CacheKey = (String, u16, u16);
+
+struct CacheEntry {
+    cached_at: Instant,
+    ttl: u32,
+    response: Message,
+}
+
+/// Responses of the single question queries, the least recently used ones are dropped when it is full
+pub struct DnsCache {
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        DnsCache {
+            entries: Mutex::new(LruCache::with_capacity(DNS_CACHE_CAPACITY)),
+        }
+    }
+}
+
+impl DnsCache {
+    pub fn new() -> DnsCache {
+        DnsCache::default()
+    }
+
+    /// Cached response to `request`, with the TTLs decreased by the time it was cached
+    pub fn get(&self, request: &Message) -> Option<Message> {
+        let key = cache_key(request)?;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        let elapsed = entry.cached_at.elapsed().as_secs().min(u32::MAX as u64) as u32;
+        if elapsed >= entry.ttl {
+            entries.remove(&key);
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        drop(entries);
+        response.set_id(request.id());
+        // keep the letter case of the question
+        *response.queries_mut() = request.queries().to_vec();
+        let decrease_ttl = |records: &mut Vec<Record>| {
+            for record in records {
+                let ttl = record.ttl().saturating_sub(elapsed);
+                record.set_ttl(ttl);
+            }
+        };
+        decrease_ttl(response.answers_mut());
+        decrease_ttl(response.name_servers_mut());
+        decrease_ttl(response.additionals_mut());
+        Some(response)
+    }
+
+    /// Cache `response` for the minimum TTL of its answers, or the SOA record if it is negative
+    pub fn insert(&self, response: &Message) {
+        let (Some(key), Some(ttl)) = (cache_key(response), cache_ttl(response)) else {
+            return;
+        };
+        let entry = CacheEntry {
+            cached_at: Instant::now(),
+            ttl,
+            response: response.clone(),
+        };
+        self.entries.lock().unwrap().insert(key, entry);
+    }
+}
+
+fn cache_key(message: &Message) -> Option<CacheKey> {
+    let [query] = message.queries() else {
+        return None;
+    };
+    Some((
+        query.name().to_ascii().to_ascii_lowercase(),
+        query.query_type().into(),
+        query.query_class().into(),
+    ))
+}
+
+/// TTL of `response`, `None` if it should not be cached
+fn cache_ttl(response: &Message) -> Option<u32> {
+    if response.truncated() {
+        return None;
+    }
+    let ttl = match response.response_code() {
+        ResponseCode::NoError if !response.answers().is_empty() => {
+            response.answers().iter().map(|r| r.ttl()).min()?
+        }
+        // NXDOMAIN, or no record of the type (RFC 2308)
+        ResponseCode::NoError | ResponseCode::NXDomain => response
+            .name_servers()
+            .iter()
+            .find_map(|r| match r.data() {
+                RData::SOA(soa) => Some(r.ttl().min(soa.minimum())),
+                _ => None,
+            })?
+            .min(MAXIMUM_NEGATIVE_TTL),
+        _ => return None,
+    };
+    (ttl > 0).then_some(ttl)
+}
+
+#[test]
+fn test_dns_cache() {
+    use hickory_proto::{
+        op::{MessageType, Query},
+        rr::{Name, RecordType, rdata::A},
+    };
+    use std::{net::Ipv4Addr, time::Duration};
+
+    let name = Name::from_ascii("www.example.com.").unwrap();
+    let mut request = Message::new();
+    request
+        .set_id(1)
+        .add_query(Query::query(name.clone(), RecordType::A));
+    let mut response = request.clone();
+    response
+        .set_message_type(MessageType::Response)
+        .add_answer(Record::from_rdata(
+            name.clone(),
+            300,
+            RData::A(A(Ipv4Addr::new(1, 2, 3, 4))),
+        ))
+        .add_answer(Record::from_rdata(
+            name,
+            60,
+            RData::A(A(Ipv4Addr::new(1, 2, 3, 5))),
+        ));
+
+    let cache = DnsCache::new();
+    assert!(cache.get(&request).is_none());
+    cache.insert(&response);
+
+    let mut request = Message::new();
+    request.set_id(2).add_query(Query::query(
+        Name::from_ascii("WWW.example.com.").unwrap(),
+        RecordType::A,
+    ));
+    let cached = cache.get(&request).unwrap();
+    assert_eq!(cached.id(), 2);
+    assert_eq!(cached.queries(), request.queries());
+    assert_eq!(cached.answers().len(), 2);
+    assert_eq!(cache_ttl(&cached), Some(60));
+
+    // expired
+    let key = cache_key(&request).unwrap();
+    if let Some(entry) = cache.entries.lock().unwrap().get_mut(&key) {
+        entry.cached_at -= Duration::from_secs(60);
+    }
+    assert!(cache.get(&request).is_none());
+
+    // not cached
+    let mut servfail = response.clone();
+    servfail.set_response_code(ResponseCode::ServFail);
+    assert_eq!(cache_ttl(&servfail), None);
+}
@@ -0,0 +1,239 @@
+//! Fake IPs standing for domain names, so the flows to them can be proxied by domain
+
+use ipnet::IpNet;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a fake IP is kept for its domain after it was last queried or connected
+///
+/// Clients may cache the answers much longer than their TTL, so this is kept generous.
+pub const FAKE_IP_EXPIRY_DURATION: Duration = Duration::from_secs(60 * 60);
+
+struct Allocation {
+    domain: String,
+    used_at: Instant,
+    /// Key in `Allocations::lru`
+    order: u64,
+}
+
+/// Fake IPs allocated from a range, in the order they were last used
+#[derive(Default)]
+struct Allocations {
+    /// fake IP -> allocation
+    domains: HashMap<IpAddr, Allocation>,
+    /// domain -> fake IP
+    addrs: HashMap<String, IpAddr>,
+    /// order -> fake IP, the first one is the least recently used
+    lru: BTreeMap<u64, IpAddr>,
+    /// Order of the next use
+    clock: u64,
+    /// Offset of the first address never allocated
+    next: u128,
+    /// Expired addresses, allocated before the ones never allocated
+    free: Vec<IpAddr>,
+}
+
+impl Allocations {
+    /// Domain of `ip`, which is moved to the most recently used
+    fn touch(&mut self, ip: IpAddr) -> Option<&str> {
+        let allocation = self.domains.get_mut(&ip)?;
+        self.lru.remove(&allocation.order);
+        allocation.order = self.clock;
+        allocation.used_at = Instant::now();
+        self.lru.insert(self.clock, ip);
+        self.clock += 1;
+        Some(&allocation.domain)
+    }
+
+    fn insert(&mut self, ip: IpAddr, domain: String) {
+        let allocation = Allocation {
+            domain: domain.clone(),
+            used_at: Instant::now(),
+            order: self.clock,
+        };
+        self.domains.insert(ip, allocation);
+        self.addrs.insert(domain, ip);
+        self.lru.insert(self.clock, ip);
+        self.clock += 1;
+    }
+
+    /// Remove the least recently used allocation if `expired` returns true for it
+    fn pop_lru(&mut self, expired: impl Fn(&Allocation) -> bool) -> Option<(IpAddr, String)> {
+        let (_, ip) = self.lru.first_key_value()?;
+        if !expired(&self.domains[ip]) {
+            return None;
+        }
+        let (_, ip) = self.lru.pop_first()?;
+        let allocation = self.domains.remove(&ip)?;
+        self.addrs.remove(&allocation.domain);
+        Some((ip, allocation.domain))
+    }
+}
+
+/// A reserved network the fake IPs are allocated from
+struct FakeIpRange {
+    net: IpNet,
+    allocations: Mutex<Allocations>,
+}
+
+impl FakeIpRange {
+    fn new(net: IpNet) -> FakeIpRange {
+        FakeIpRange {
+            net,
+            allocations: Mutex::new(Allocations::default()),
+        }
+    }
+
+    /// Number of the usable addresses, without the network and the broadcast addresses
+    fn size(&self) -> u128 {
+        let host_bits = self.net.max_prefix_len() - self.net.prefix_len();
+        1u128
+            .checked_shl(host_bits as u32)
+            .unwrap_or(u128::MAX)
+            .saturating_sub(2)
+    }
+
+    /// The `offset`th usable address
+    fn addr(&self, offset: u128) -> IpAddr {
+        match self.net.network() {
+            IpAddr::V4(net) => IpAddr::V4(Ipv4Addr::from(
+                u32::from(net).wrapping_add(offset as u32 + 1),
+            )),
+            IpAddr::V6(net) => IpAddr::V6(Ipv6Addr::from(u128::from(net).wrapping_add(offset + 1))),
+        }
+    }
+
+    /// Fake IP of `domain`, allocating a free address, or taking the least recently used one if
+    /// the range is full
+    fn fake_ip(&self, domain: &str) -> Option<IpAddr> {
+        let mut allocations = self.allocations.lock().unwrap();
+        if let Some(&ip) = allocations.addrs.get(domain) {
+            allocations.touch(ip);
+            return Some(ip);
+        }
+
+        let ip = match allocations.free.pop() {
+            Some(ip) => ip,
+            None if allocations.next < self.size() => {
+                allocations.next += 1;
+                self.addr(allocations.next - 1)
+            }
+            None => {
+                let (ip, old) = allocations.pop_lru(|_| true)?;
+                log::debug!("fake IP pool is full, {} is taken from {}", ip, old);
+                ip
+            }
+        };
+        allocations.insert(ip, domain.to_owned());
+        Some(ip)
+    }
+
+    fn cleanup_expired(&self) {
+        let mut allocations = self.allocations.lock().unwrap();
+        let now = Instant::now();
+        let expired = |a: &Allocation| now.duration_since(a.used_at) > FAKE_IP_EXPIRY_DURATION;
+        while let Some((ip, _)) = allocations.pop_lru(expired) {
+            allocations.free.push(ip);
+        }
+    }
+}
+
+/// Fake IPs allocated to the domain names, from an IPv4 and an optional IPv6 range
+///
+/// A domain keeps its fake IP while it is queried or connected, the idle ones expire after
+/// `FAKE_IP_EXPIRY_DURATION` and their addresses can be allocated to other domains. When a range
+/// is full, the least recently used fake IP is taken.
+pub struct FakeIpPool {
+    v4: FakeIpRange,
+    v6: Option<FakeIpRange>,
+}
+
+impl FakeIpPool {
+    /// Pool of the addresses in `v4`, and `v6` for AAAA queries if given
+    pub fn new(v4: IpNet, v6: Option<IpNet>) -> FakeIpPool {
+        FakeIpPool {
+            v4: FakeIpRange::new(v4),
+            v6: v6.map(FakeIpRange::new),
+        }
+    }
+
+    /// Fake IP of lowercase `domain`, allocating one if it has none
+    ///
+    /// Returns `None` if `ipv6` is requested without an IPv6 range.
+    pub fn fake_ip(&self, domain: &str, ipv6: bool) -> Option<IpAddr> {
+        match ipv6 {
+            true => self.v6.as_ref()?.fake_ip(domain),
+            false => self.v4.fake_ip(domain),
+        }
+    }
+
+    /// Domain of fake IP `ip`, `None` if it is not allocated or expired
+    pub fn domain(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        let range = self.ranges().find(|r| r.net.contains(&ip))?;
+        let mut allocations = range.allocations.lock().unwrap();
+        allocations.touch(ip).map(str::to_owned)
+    }
+
+    /// Check if `ip` is in the fake IP ranges
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.ranges().any(|r| r.net.contains(&ip))
+    }
+
+    /// Remove the expired fake IPs
+    pub fn cleanup_expired(&self) {
+        for range in self.ranges() {
+            range.cleanup_expired();
+        }
+    }
+
+    fn ranges(&self) -> impl Iterator<Item = &FakeIpRange> {
+        std::iter::once(&self.v4).chain(self.v6.as_ref())
+    }
+}
+
+#[test]
+fn test_fake_ip_pool() {
+    let pool = FakeIpPool::new("198.18.0.0/30".parse().unwrap(), None);
+
+    let a = pool.fake_ip("a.example.com", false).unwrap();
+    let b = pool.fake_ip("b.example.com", false).unwrap();
+    assert_eq!(a, "198.18.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(b, "198.18.0.2".parse::<IpAddr>().unwrap());
+    assert_eq!(pool.fake_ip("a.example.com", false), Some(a));
+    assert_eq!(pool.fake_ip("a.example.com", true), None);
+    assert_eq!(pool.domain(a).as_deref(), Some("a.example.com"));
+    assert_eq!(
+        pool.domain("::ffff:198.18.0.2".parse().unwrap()).as_deref(),
+        Some("b.example.com")
+    );
+    assert!(pool.contains("198.18.0.3".parse().unwrap()));
+    assert!(!pool.contains("198.19.0.1".parse().unwrap()));
+
+    // only 2 usable addresses, the next domain takes the first one
+    let c = pool.fake_ip("c.example.com", false).unwrap();
+    assert_eq!(c, a);
+    assert_eq!(pool.domain(a).as_deref(), Some("c.example.com"));
+    assert_eq!(pool.fake_ip("a.example.com", false), Some(b));
+
+    // the least recently used one is taken, not the oldest allocation
+    assert_eq!(pool.domain(c).as_deref(), Some("c.example.com"));
+    assert_eq!(pool.fake_ip("d.example.com", false), Some(b));
+    assert_eq!(pool.fake_ip("c.example.com", false), Some(c));
+
+    // the expired addresses are freed
+    for allocation in pool.v4.allocations.lock().unwrap().domains.values_mut() {
+        allocation.used_at -= FAKE_IP_EXPIRY_DURATION + Duration::from_secs(1);
+    }
+    pool.cleanup_expired();
+    assert_eq!(pool.domain(c), None);
+    assert_eq!(pool.domain(b), None);
+    let e = pool.fake_ip("e.example.com", false).unwrap();
+    assert!(e == b || e == c);
+    assert_eq!(pool.domain(e).as_deref(), Some("e.example.com"));
+}
@@ -0,0 +1,366 @@
+//! Forwarding the DNS queries redirected to a UDP listener over TCP, through the outbound of the
+//! listener, so DNS works with the upstreams which can not relay UDP
+
+use crate::{
+    dns::{cache::DnsCache, read_tcp_message, snoop::DnsSnoop, write_tcp_message},
+    router::{Flow, Outbound, Protocol},
+    udp_relay::send::{SendBackOpts, ServerToClient},
+    utils::{
+        config::{Config, ListenerMode, UpstreamProtocol},
+        http::tcp_client::HttpTcpClient,
+        net,
+        socks::tcp_client::Socks5TcpClient,
+    },
+};
+use arc_swap::ArcSwap;
+use hickory_proto::op::Message;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::OnceCell,
+    time,
+};
+
+/// The maximum time of connecting to the DNS server and getting the response
+const DNS_FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves the DNS queries redirected to the UDP listener on `listen_addr`
+pub struct DnsForwarder {
+    config: Arc<ArcSwap<Config>>,
+    listen_addr: SocketAddr,
+    send_back: SendBackOpts,
+    cache: DnsCache,
+    /// Learns the domain names from the responses
+    snoop: Option<Arc<DnsSnoop>>,
+    /// Senders of the responses to IPv4 and IPv6 clients, created on demand
+    senders: [OnceCell<ServerToClient>; 2],
+}
+
+impl DnsForwarder {
+    pub fn new(
+        config: Arc<ArcSwap<Config>>,
+        listen_addr: SocketAddr,
+        send_back: SendBackOpts,
+        snoop: Option<Arc<DnsSnoop>>,
+    ) -> DnsForwarder {
+        DnsForwarder {
+            config,
+            listen_addr,
+            send_back,
+            cache: DnsCache::new(),
+            snoop,
+            senders: Default::default(),
+        }
+    }
+
+    /// Resolve `query` sent from `peer_addr` to DNS server `dst`, and send the response back
+    /// from `dst`
+    pub async fn forward(&self, peer_addr: SocketAddr, dst: SocketAddr, query: &[u8]) {
+        let response = match self.resolve(peer_addr, dst, query).await {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                log::trace!("DNS query {} -> {} is rejected", peer_addr, dst);
+                return;
+            }
+            Err(e) => {
+                log::debug!("forward DNS query {} -> {} error: {}", peer_addr, dst, e);
+                return;
+            }
+        };
+
+        if let Some(ref snoop) = self.snoop {
+            snoop.record(&response);
+        }
+
+        let sender = self.senders[peer_addr.is_ipv6() as usize]
+            .get_or_try_init(|| async { ServerToClient::new(&peer_addr, &self.send_back) })
+            .await;
+        let result = match sender {
+            Ok(sender) => sender.send_to(dst, peer_addr, &response).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::error!("DNS response {} <- {} error: {}", peer_addr, dst, e);
+        }
+    }
+
+    /// Response to `query`, from the cache or the DNS server. `None` if it is rejected
+    async fn resolve(
+        &self,
+        peer_addr: SocketAddr,
+        dst: SocketAddr,
+        query: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let request =
+            Message::from_vec(query).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let max_payload = request.max_payload() as usize;
+
+        let response = match self.cache.get(&request) {
+            Some(response) => {
+                log::trace!("DNS cache hit {:?}", request.query());
+                response.to_vec().map_err(io::Error::other)?
+            }
+            None => {
+                let exchange = self.exchange(peer_addr, dst, query);
+                let Some(response) = time::timeout(DNS_FORWARD_TIMEOUT, exchange)
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??
+                else {
+                    return Ok(None);
+                };
+                if let Ok(message) = Message::from_vec(&response)
+                    && message.id() == request.id()
+                {
+                    self.cache.insert(&message);
+                }
+                response
+            }
+        };
+
+        // the client retries over TCP, which is not redirected to the relay usually
+        if response.len() > max_payload {
+            let truncated = Message::from_vec(&response)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                .truncate();
+            return truncated.to_vec().map(Some).map_err(io::Error::other);
+        }
+        Ok(Some(response))
+    }
+
+    /// Send `query` to the DNS server over TCP, through the outbound chosen by the current config
+    async fn exchange(
+        &self,
+        peer_addr: SocketAddr,
+        dst: SocketAddr,
+        query: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let (server, outbound, upstream, connect_opts) = {
+            let config = self.config.load();
+            let Some(listener) = config.listeners.iter().find(|l| l.addr == self.listen_addr)
+            else {
+                return Ok(None);
+            };
+            let server = listener.dns_server.unwrap_or(dst);
+            // the query is sent over TCP, so proxy mode uses the TCP upstream, while the rules
+            // match it as the UDP flow redirected to the listener
+            let protocol = match listener.mode {
+                ListenerMode::Proxy => Protocol::Tcp,
+                ListenerMode::Direct | ListenerMode::Rule => Protocol::Udp,
+            };
+            let flow = Flow {
+                protocol,
+                src: peer_addr,
+                dst: server,
+                domain: None,
+            };
+            let outbound = config.route(listener, &flow);
+            let upstream = match outbound {
+                Outbound::Proxy(ref name) => config.upstreams.get(name).cloned(),
+                _ => None,
+            };
+            (server, outbound, upstream, config.connect_opts())
+        };
+        log::trace!(
+            "forward DNS query {} -> {} via {}",
+            peer_addr,
+            server,
+            outbound
+        );
+
+        let response = match (outbound, upstream) {
+            (Outbound::Reject, _) => return Ok(None),
+            (Outbound::Direct, _) => {
+                exchange_tcp(net::connect_tcp(server, &connect_opts).await?, query).await?
+            }
+            (Outbound::Proxy(name), None) => {
+                return Err(io::Error::other(format!("upstream {name} is not defined")));
+            }
+            (Outbound::Proxy(_), Some(upstream)) => match upstream.protocol {
+                UpstreamProtocol::Http => {
+                    let proxy_stream = net::connect_tcp(upstream.addr, &connect_opts).await?;
+                    let reconnect = || net::connect_tcp(upstream.addr, &connect_opts);
+                    let auth = upstream.auth.as_ref();
+                    let stream =
+                        HttpTcpClient::connect_on(proxy_stream, server, auth, reconnect).await?;
+                    exchange_tcp(stream, query).await?
+                }
+                UpstreamProtocol::Socks5 => {
+                    let proxy_stream = net::connect_tcp(upstream.addr, &connect_opts).await?;
+                    let auth = upstream.auth.as_ref();
+                    let stream = Socks5TcpClient::connect_on(proxy_stream, server, auth).await?;
+                    exchange_tcp(stream, query).await?
+                }
+            },
+        };
+        Ok(Some(response))
+    }
+}
+
+/// Send `query` and read the response on a new DNS over TCP connection
+async fn exchange_tcp<S>(mut stream: S, query: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_tcp_message(&mut stream, query).await?;
+    let mut response = Vec::new();
+    read_tcp_message(&mut stream, &mut response).await?;
+    Ok(response)
+}
+
+#[tokio::test]
+async fn test_resolve() {
+    use crate::utils::config::{ConfigType, RedirType};
+    use hickory_proto::{
+        op::{Edns, MessageType, Query},
+        rr::{Name, RData, Record, RecordType, rdata::A},
+    };
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    // DNS over TCP server answering 40 A records, too large without EDNS
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let exchanges = Arc::new(AtomicUsize::new(0));
+    let counter = exchanges.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            read_tcp_message(&mut stream, &mut buf).await.unwrap();
+            counter.fetch_add(1, Ordering::Relaxed);
+            let mut response = Message::from_vec(&buf).unwrap();
+            response.set_message_type(MessageType::Response);
+            let name = response.queries()[0].name().clone();
+            for i in 0..40 {
+                let ip = Ipv4Addr::new(1, 2, 3, i);
+                response.add_answer(Record::from_rdata(name.clone(), 300, RData::A(A(ip))));
+            }
+            write_tcp_message(&mut stream, &response.to_vec().unwrap())
+                .await
+                .unwrap();
+        }
+    });
+
+    // HTTP proxy tunneling every CONNECT to the DNS server
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = proxy.accept().await.unwrap();
+            let mut buf = vec![0u8; 1024];
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(buf[..n].starts_with(b"CONNECT "));
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap();
+            let mut server = TcpStream::connect(server_addr).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut server).await;
+            });
+        }
+    });
+
+    let config = Config::load_from_str(
+        &format!(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23461"
+            mode = "rule"
+            dns_forward = true
+            dns_server = "{server_addr}"
+
+            [[listeners]]
+            address = "127.0.0.1:23462"
+            mode = "rule"
+            dns_forward = true
+
+            [[listeners]]
+            address = "127.0.0.1:23463"
+            mode = "proxy"
+            upstream = "http"
+            dns_forward = true
+            dns_server = "{server_addr}"
+
+            [upstreams.http]
+            protocol = "http"
+            address = "{proxy_addr}"
+
+            [routing]
+            default = "direct"
+
+            [[routing.rules]]
+            dst = ["127.0.0.2"]
+            protocol = "udp"
+            outbound = "reject"
+            "#
+        ),
+        ConfigType::Toml,
+    )
+    .unwrap();
+    let config = Arc::new(ArcSwap::from_pointee(config));
+    let forwarder = |listen_addr: &str| {
+        let send_back = SendBackOpts::new(RedirType::TProxy);
+        DnsForwarder::new(
+            config.clone(),
+            listen_addr.parse().unwrap(),
+            send_back,
+            None,
+        )
+    };
+    let query = |id: u16, edns: bool| {
+        let mut message = Message::new();
+        message.set_id(id).add_query(Query::query(
+            Name::from_ascii("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        if edns {
+            let mut edns = Edns::new();
+            edns.set_max_payload(4096);
+            message.set_edns(edns);
+        }
+        message.to_vec().unwrap()
+    };
+    let peer_addr: SocketAddr = "192.168.1.2:40000".parse().unwrap();
+    let dst: SocketAddr = "127.0.0.2:53".parse().unwrap();
+
+    let dns = forwarder("127.0.0.1:23461");
+    let resolve = async |id, edns| {
+        let response = dns.resolve(peer_addr, dst, &query(id, edns)).await;
+        Message::from_vec(&response.unwrap().unwrap()).unwrap()
+    };
+    let response = resolve(1, true).await;
+    assert_eq!(response.id(), 1);
+    assert!(!response.truncated());
+    assert_eq!(response.answers().len(), 40);
+
+    // from the cache, truncated to 512 bytes
+    let response = resolve(2, false).await;
+    assert_eq!(response.id(), 2);
+    assert!(response.truncated());
+    assert!(response.answers().is_empty());
+
+    let response = resolve(3, true).await;
+    assert_eq!(response.id(), 3);
+    assert_eq!(response.answers().len(), 40);
+    assert_eq!(exchanges.load(Ordering::Relaxed), 1);
+
+    // the queries to 127.0.0.2 are rejected as UDP flows
+    let dns = forwarder("127.0.0.1:23462");
+    let response = dns.resolve(peer_addr, dst, &query(4, true)).await;
+    assert!(response.unwrap().is_none());
+
+    // proxy mode without udp_upstream forwards through the TCP upstream
+    let dns = forwarder("127.0.0.1:23463");
+    let response = dns.resolve(peer_addr, dst, &query(5, true)).await;
+    let response = Message::from_vec(&response.unwrap().unwrap()).unwrap();
+    assert_eq!(response.id(), 5);
+    assert_eq!(response.answers().len(), 40);
+    assert_eq!(exchanges.load(Ordering::Relaxed), 2);
+}
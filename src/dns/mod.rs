@@ -0,0 +1,236 @@
+//! Built-in DNS server, answering A/AAAA queries with fake IPs (see `fake_ip`)
+//!
+//! The redirected flows to a fake IP are relayed by its domain name, so the domain can be
+//! resolved by the upstream proxy, and the real DNS queries don't leak from the local network.
+//!
+//! The DNS queries redirected to the UDP listeners can also be forwarded over TCP, see `forward`,
+//! or their responses snooped for the domain names of the real IPs, see `snoop`.
+
+use crate::dns::fake_ip::FakeIpPool;
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{
+        DNSClass, RData, Record, RecordType,
+        rdata::{A, AAAA},
+    },
+};
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::watch,
+    time,
+};
+
+pub mod cache;
+pub mod fake_ip;
+pub mod forward;
+pub mod snoop;
+
+/// The maximum DNS message size, limited by the 2 bytes length prefix of DNS over TCP
+const MAXIMUM_DNS_MESSAGE_SIZE: usize = u16::MAX as usize;
+/// Idle DNS over TCP connections are closed after it
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval of removing the expired fake IPs
+const FAKE_IP_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Answers DNS queries with the fake IPs in `pool`
+pub struct FakeDns {
+    pub pool: Arc<FakeIpPool>,
+    /// TTL of the answers
+    pub ttl: u32,
+}
+
+impl FakeDns {
+    /// Response to DNS message `query`, `None` if it is not a valid query
+    ///
+    /// Only A and AAAA queries are answered, the others get an empty response.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let request = Message::from_vec(query).ok()?;
+        if request.message_type() != MessageType::Query {
+            return None;
+        }
+
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(request.queries().iter().cloned());
+        match request.queries() {
+            _ if request.op_code() != OpCode::Query => {
+                response.set_response_code(ResponseCode::NotImp);
+            }
+            [query] => {
+                if let Some(record) = self.fake_record(query) {
+                    response.add_answer(record);
+                }
+            }
+            _ => {
+                response.set_response_code(ResponseCode::FormErr);
+            }
+        }
+        response.to_vec().ok()
+    }
+
+    /// A or AAAA record of the fake IP for `query`
+    fn fake_record(&self, query: &Query) -> Option<Record> {
+        let ipv6 = match query.query_type() {
+            RecordType::A => false,
+            RecordType::AAAA => true,
+            _ => return None,
+        };
+        if query.query_class() != DNSClass::IN {
+            return None;
+        }
+        let domain = query.name().to_ascii();
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() {
+            return None;
+        }
+
+        let rdata = match self.pool.fake_ip(&domain, ipv6)? {
+            IpAddr::V4(ip) => RData::A(A(ip)),
+            IpAddr::V6(ip) => RData::AAAA(AAAA(ip)),
+        };
+        log::trace!("fake IP of {}: {}", domain, rdata);
+        Some(Record::from_rdata(query.name().clone(), self.ttl, rdata))
+    }
+}
+
+/// Serve the DNS queries on `udp` and `tcp`
+///
+/// Once `shutdown` is set (or its sender is dropped), the sockets and `shutdown` are dropped, so
+/// `watch::Sender::closed()` resolves when the listening address can be bound again.
+pub async fn run(
+    udp: UdpSocket,
+    tcp: TcpListener,
+    dns: Arc<FakeDns>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut buf = vec![0u8; MAXIMUM_DNS_MESSAGE_SIZE].into_boxed_slice();
+    let mut cleanup_timer = time::interval(FAKE_IP_CLEANUP_INTERVAL);
+    loop {
+        tokio::select! {
+            recv_result = udp.recv_from(&mut buf) => {
+                let (n, peer_addr) = match recv_result {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("DNS receive error: {}", e);
+                        continue;
+                    }
+                };
+                let Some(response) = dns.answer(&buf[..n]) else {
+                    log::debug!("invalid DNS query from {}", peer_addr);
+                    continue;
+                };
+                if let Err(e) = udp.send_to(&response, peer_addr).await {
+                    log::debug!("DNS send to {} error: {}", peer_addr, e);
+                }
+            }
+            accept_result = tcp.accept() => {
+                let (stream, peer_addr) = match accept_result {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("DNS accept error: {}", e);
+                        continue;
+                    }
+                };
+                let dns = dns.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tcp(stream, &dns).await {
+                        log::debug!("DNS over TCP from {} error: {}", peer_addr, e);
+                    }
+                });
+            }
+            _ = cleanup_timer.tick() => dns.pool.cleanup_expired(),
+            _ = shutdown.changed() => break,
+        }
+    }
+}
+
+/// Serve the queries on a DNS over TCP connection
+async fn serve_tcp(mut stream: TcpStream, dns: &FakeDns) -> io::Result<()> {
+    let mut query = Vec::new();
+    loop {
+        match time::timeout(TCP_IDLE_TIMEOUT, read_tcp_message(&mut stream, &mut query)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            // idle
+            Err(..) => return Ok(()),
+        }
+        let Some(response) = dns.answer(&query) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid DNS query",
+            ));
+        };
+        write_tcp_message(&mut stream, &response).await?;
+    }
+}
+
+/// Read a DNS over TCP message into `buf`, which is prefixed by its length
+async fn read_tcp_message<S>(stream: &mut S, buf: &mut Vec<u8>) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await? as usize;
+    buf.resize(len, 0);
+    stream.read_exact(buf).await?;
+    Ok(())
+}
+
+/// Write a DNS over TCP message, prefixed by its length
+async fn write_tcp_message<S>(stream: &mut S, message: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::with_capacity(2 + message.len());
+    buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buf.extend_from_slice(message);
+    stream.write_all(&buf).await
+}
+
+#[test]
+fn test_fake_dns_answer() {
+    use hickory_proto::rr::Name;
+
+    let dns = FakeDns {
+        pool: Arc::new(FakeIpPool::new("198.18.0.0/15".parse().unwrap(), None)),
+        ttl: 1,
+    };
+    let query = |name: &str, ty| {
+        let mut message = Message::new();
+        message
+            .set_id(1234)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), ty));
+        Message::from_vec(&dns.answer(&message.to_vec().unwrap()).unwrap()).unwrap()
+    };
+
+    let response = query("WWW.Example.com.", RecordType::A);
+    assert_eq!(response.id(), 1234);
+    assert_eq!(response.message_type(), MessageType::Response);
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].ttl(), 1);
+    let RData::A(A(ip)) = response.answers()[0].data() else {
+        panic!("unexpected answer {:?}", response.answers());
+    };
+    assert_eq!(
+        dns.pool.domain(IpAddr::V4(*ip)).as_deref(),
+        Some("www.example.com")
+    );
+
+    // no IPv6 range, and other types
+    assert!(
+        query("www.example.com", RecordType::AAAA)
+            .answers()
+            .is_empty()
+    );
+    assert!(query("example.com", RecordType::MX).answers().is_empty());
+    assert!(dns.answer(b"invalid").is_none());
+}
@@ -0,0 +1,137 @@
+//! Domain names of the IPs learned from the DNS responses relayed by rustsocks
+//!
+//! Unlike fake IPs, the clients get the real answers, so it only works for the DNS queries
+//! passing through the UDP relay (or `forward`), and the IPs shared by several domains are
+//! attributed to the latest one.
+
+use hickory_proto::{op::Message, rr::RData};
+use lru_time_cache::LruCache;
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The maximum number of IPs remembered
+const SNOOP_CACHE_CAPACITY: usize = 16384;
+/// The minimum time an answer is kept, the clients may connect a while after the TTL of short
+/// lived answers expires
+const MINIMUM_SNOOP_TTL: Duration = Duration::from_secs(60);
+
+/// IP -> domain name of the A/AAAA answers, kept for their TTLs
+pub struct DnsSnoop {
+    domains: Mutex<LruCache<IpAddr, (String, Instant)>>,
+}
+
+impl Default for DnsSnoop {
+    fn default() -> Self {
+        DnsSnoop {
+            domains: Mutex::new(LruCache::with_capacity(SNOOP_CACHE_CAPACITY)),
+        }
+    }
+}
+
+impl DnsSnoop {
+    pub fn new() -> DnsSnoop {
+        DnsSnoop::default()
+    }
+
+    /// Learn the addresses in DNS message `response`, other messages are ignored
+    ///
+    /// The addresses are mapped to the name of the question, not the CNAME targets, as the
+    /// client asked for it.
+    pub fn record(&self, response: &[u8]) {
+        let Ok(message) = Message::from_vec(response) else {
+            return;
+        };
+        let [query] = message.queries() else {
+            return;
+        };
+        if message.answers().is_empty() {
+            return;
+        }
+        let domain = query.name().to_ascii();
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if domain.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut domains = self.domains.lock().unwrap();
+        for record in message.answers() {
+            let ip = match record.data() {
+                RData::A(a) => IpAddr::V4(a.0),
+                RData::AAAA(aaaa) => IpAddr::V6(aaaa.0),
+                _ => continue,
+            };
+            let ttl = Duration::from_secs(record.ttl().into()).max(MINIMUM_SNOOP_TTL);
+            log::trace!("snooped DNS answer {} -> {}", domain, ip);
+            domains.insert(ip, (domain.clone(), now + ttl));
+        }
+    }
+
+    /// Domain name of `ip`, `None` if it is unknown or expired
+    pub fn domain(&self, ip: IpAddr) -> Option<String> {
+        let ip = ip.to_canonical();
+        let mut domains = self.domains.lock().unwrap();
+        let (domain, expires_at) = domains.get(&ip)?;
+        if *expires_at <= Instant::now() {
+            domains.remove(&ip);
+            return None;
+        }
+        Some(domain.clone())
+    }
+}
+
+#[test]
+fn test_dns_snoop() {
+    use hickory_proto::{
+        op::{MessageType, Query},
+        rr::{
+            Name, Record, RecordType,
+            rdata::{A, CNAME},
+        },
+    };
+    use std::net::Ipv4Addr;
+
+    let name = Name::from_ascii("WWW.Example.com.").unwrap();
+    let cdn = Name::from_ascii("cdn.example.net.").unwrap();
+    let mut response = Message::new();
+    response
+        .set_message_type(MessageType::Response)
+        .add_query(Query::query(name.clone(), RecordType::A))
+        .add_answer(Record::from_rdata(
+            name,
+            300,
+            RData::CNAME(CNAME(cdn.clone())),
+        ))
+        .add_answer(Record::from_rdata(
+            cdn.clone(),
+            0,
+            RData::A(A(Ipv4Addr::new(1, 2, 3, 4))),
+        ));
+
+    let snoop = DnsSnoop::new();
+    snoop.record(&response.to_vec().unwrap());
+    snoop.record(b"invalid");
+    assert_eq!(
+        snoop.domain("1.2.3.4".parse().unwrap()).as_deref(),
+        Some("www.example.com")
+    );
+    assert_eq!(
+        snoop.domain("::ffff:1.2.3.4".parse().unwrap()).as_deref(),
+        Some("www.example.com")
+    );
+    assert_eq!(snoop.domain("1.2.3.5".parse().unwrap()), None);
+
+    // expired
+    if let Some((_, expires_at)) = snoop
+        .domains
+        .lock()
+        .unwrap()
+        .get_mut(&"1.2.3.4".parse::<IpAddr>().unwrap())
+    {
+        *expires_at -= MINIMUM_SNOOP_TTL;
+    }
+    assert_eq!(snoop.domain("1.2.3.4".parse().unwrap()), None);
+}
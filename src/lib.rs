@@ -1,4 +1,14 @@
+pub mod access_log;
+pub mod admin;
+pub mod admission;
+pub mod dns;
+pub mod metrics;
+pub mod privilege;
 pub mod redir;
+pub mod router;
+pub mod service;
+pub mod session;
+pub mod systemd;
 pub mod tcp_relay;
 pub mod udp_relay;
 pub mod utils;
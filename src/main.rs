@@ -1,60 +1,251 @@
-use libc::{RLIMIT_NOFILE, getrlimit, rlimit};
-use rustsocks::redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt};
-use rustsocks::udp_relay::macos::UdpRedirSocket;
-use rustsocks::udp_relay::run;
-use rustsocks::udp_relay::send::{Direct, Proxy};
-use rustsocks::utils::config::RedirType;
+use rustsocks::admission::{self, MINIMUM_FD_LIMIT};
+use rustsocks::metrics::METRICS;
+use rustsocks::privilege::{self, RunAs};
+use rustsocks::redir::redir_ext::RedirSocketOpts;
+use rustsocks::router::Router;
+use rustsocks::service::Service;
+use rustsocks::systemd::{self, ActivatedSockets, NotifyState};
+use rustsocks::utils::config::{
+    Config, Credentials, ListenerConfig, ListenerMode, RedirType, TcpTimeouts, UdpSendBackType,
+    UpstreamConfig, UpstreamProtocol,
+};
 use rustsocks::utils::net::AcceptOpts;
-use std::io;
+use std::collections::HashMap;
+use std::fs;
+use std::time::{Duration, SystemTime};
 use std::{io::Result, net::SocketAddr};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::join;
-use tokio::{
-    io::copy_bidirectional,
-    net::{TcpListener, TcpStream},
-};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time;
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // check fd limit
-    let mut limit = rlimit {
-        rlim_cur: 0,
-        rlim_max: 0,
+/// Interval of checking if the config file and GeoIP databases are modified
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+/// Exit status if some sessions are closed unfinished on shutdown
+const EXIT_SESSIONS_CLOSED: i32 = 2;
+
+fn main() -> Result<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let user = take_option(&mut args, "--user");
+    let group = take_option(&mut args, "--group");
+    let (config_path, config) = load_config(args);
+    let run_as = match (user, group) {
+        (Some(user), group) => match RunAs::resolve(&user, group.as_deref()) {
+            Ok(run_as) => Some(run_as),
+            Err(e) => {
+                eprintln!("invalid --user or --group: {e}");
+                std::process::exit(1);
+            }
+        },
+        (None, Some(_)) => {
+            eprintln!("--group requires --user");
+            std::process::exit(1);
+        }
+        (None, None) => None,
     };
-    let res = unsafe { getrlimit(RLIMIT_NOFILE, &mut limit) };
-    if res != 0 {
-        return Err(std::io::Error::last_os_error());
-    }
-    if limit.rlim_cur < 4096 {
+
+    env_logger::init();
+    // raised before dropping the privileges, which may allow a higher hard limit
+    let fd_limit = admission::raise_fd_limit(config.max_open_files)?;
+    if fd_limit < MINIMUM_FD_LIMIT {
         eprintln!(
-            "Error: current file descriptor limit is {}, which is too low for the program to run reliably.",
-            limit.rlim_cur
+            "Error: file descriptor limit is {fd_limit}, which is too low for the program to run reliably."
         );
         eprintln!(
-            "Hint: increase the limit to at least 4096.\n\
-             On macOS, you can temporarily raise it with:\n\
-             \tlaunchctl limit maxfiles 4096\n\
-             Note: changes may require restarting the current terminal session or logging out and back in to take effect.\n\
-             To make it permanent across restarts, you may need to create a launchd service."
+            "Hint: raise the hard limit to at least {MINIMUM_FD_LIMIT}, e.g. `ulimit -Hn` as root, \
+             `LimitNOFILE=` in the systemd unit, or `launchctl limit maxfiles` on macOS."
         );
         std::process::exit(1);
     }
-
-    // check if running as root
-    if unsafe { libc::geteuid() } != 0 {
-        eprintln!("You should run rustsocks with root privilege!");
+    let mut activated = ActivatedSockets::from_env()
+        .inspect_err(|e| eprintln!("get sockets passed by systemd error: {e}"))?;
+    // check the capabilities instead of root on Linux
+    if let Err(e) = privilege::check(&config, &activated, run_as.as_ref()) {
+        eprintln!("{e}");
         std::process::exit(1);
     }
-    let arg_error = || {
-        eprintln!(
-            "invalid arguments \nusage: rustsocks <listen address(forward to proxy)> <listen address(direct)> <proxy address> <socks5 proxy address(optional)>"
-        );
-        std::process::exit(1);
+    // runtime threads are only started after switching the user
+    if let Some(ref run_as) = run_as {
+        privilege::open_sockets(&config, &mut activated)?;
+        privilege::switch_user(run_as, &config)
+            .inspect_err(|e| eprintln!("switch to user {} error: {e}", run_as.user))?;
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(config_path, config, activated))
+}
+
+/// Run the service until SIGTERM or SIGINT
+async fn run(
+    config_path: Option<String>,
+    config: Config,
+    activated: ActivatedSockets,
+) -> Result<()> {
+    log::info!(
+        "UDP packets are sent back with {} sockets",
+        config.udp_send_back
+    );
+    let mut service = Service::start(config, activated).await?;
+    notify_ready(&service);
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(async move {
+            let mut timer = time::interval(interval);
+            loop {
+                timer.tick().await;
+                systemd::notify(&[NotifyState::Watchdog]);
+            }
+        });
+    }
+
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut watch_timer = time::interval(CONFIG_WATCH_INTERVAL);
+    let mut last_modified = config_path.as_deref().and_then(modified_time);
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                log::info!("received SIGHUP, reloading config");
+            }
+            _ = sigterm.recv() => {
+                log::info!("received SIGTERM, shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                log::info!("received SIGINT, shutting down");
+                break;
+            }
+            _ = watch_timer.tick(), if config_path.is_some() => {
+                service.reload_geoip();
+                let modified = config_path.as_deref().and_then(modified_time);
+                if modified == last_modified {
+                    continue;
+                }
+                log::info!("config file changed, reloading config");
+            }
+        }
+
+        let Some(ref path) = config_path else {
+            log::warn!("no config file is given, nothing to reload");
+            continue;
+        };
+        last_modified = modified_time(path);
+        match Config::load_from_file(path) {
+            Ok(config) => {
+                // systemd 253+ requires the time of reloading
+                let mut states = vec![NotifyState::Reloading];
+                if let Ok(now) = NotifyState::monotonic_usec_now() {
+                    states.push(now);
+                }
+                systemd::notify(&states);
+                if let Err(e) = service.reload(config).await {
+                    log::error!(
+                        "reload config file {} error: {}, keep the current config",
+                        path,
+                        e
+                    );
+                }
+                notify_ready(&service);
+            }
+            Err(e) => log::error!(
+                "reload config file {} error: {}, keep the current config",
+                path,
+                e
+            ),
+        }
+    }
+
+    // another signal closes the sessions at once
+    let timeout = service.config().shutdown_timeout;
+    let force = async {
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
     };
-    let mut args = std::env::args();
-    let listen_addr_proxy = args.nth(1).unwrap_or_else(arg_error);
-    let listen_addr_proxy = match listen_addr_proxy.parse::<SocketAddr>() {
+    systemd::notify(&[
+        NotifyState::Stopping,
+        NotifyState::Status("draining sessions"),
+    ]);
+    let closed = service.shutdown(timeout, force).await;
+
+    let totals = METRICS.totals();
+    log::info!(
+        "stopped, relayed {} TCP sessions and {} UDP associations ({} bytes up, {} bytes down), \
+         accepted {} TCP connections, {} connect failures",
+        totals.tcp_sessions,
+        totals.udp_associations,
+        totals.uploaded,
+        totals.downloaded,
+        totals.accepted,
+        totals.connect_failures
+    );
+    if closed > 0 {
+        log::warn!("{} sessions were closed unfinished", closed);
+        std::process::exit(EXIT_SESSIONS_CLOSED);
+    }
+    Ok(())
+}
+
+/// Tell systemd the listeners are ready
+fn notify_ready(service: &Service) {
+    let addrs: Vec<String> = service
+        .config()
+        .listeners
+        .iter()
+        .map(|l| l.addr.to_string())
+        .collect();
+    let status = format!("listening on {}", addrs.join(", "));
+    systemd::notify(&[NotifyState::Ready, NotifyState::Status(&status)]);
+}
+
+/// Last modification time of the config file
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// SOCKS5 credentials from `RUSTSOCKS_SOCKS5_USERNAME` and `RUSTSOCKS_SOCKS5_PASSWORD`
+fn socks5_auth_from_env() -> Option<Credentials> {
+    let username = std::env::var("RUSTSOCKS_SOCKS5_USERNAME").ok()?;
+    let password = std::env::var("RUSTSOCKS_SOCKS5_PASSWORD").ok()?;
+    Some(Credentials { username, password })
+}
+
+fn arg_error<T>() -> T {
+    eprintln!(
+        "invalid arguments \nusage: rustsocks [--user <user> [--group <group>]] <listen address(forward to proxy)> <listen address(direct)> <proxy address> <socks5 proxy address(optional)>\n       rustsocks [--user <user> [--group <group>]] --config <config file>"
+    );
+    std::process::exit(1);
+}
+
+/// Remove `name <value>` from `args`, returns the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    if i + 1 >= args.len() {
+        return arg_error();
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
+/// Load config from `--config <path>`, or build it from the positional arguments
+///
+/// Returns the config file path, if any
+fn load_config(args: Vec<String>) -> (Option<String>, Config) {
+    let mut args = args.into_iter();
+    let first_arg = args.next().unwrap_or_else(arg_error);
+    if first_arg == "--config" || first_arg == "-c" {
+        let path = args.next().unwrap_or_else(arg_error);
+        return match Config::load_from_file(&path) {
+            Ok(config) => (Some(path), config),
+            Err(e) => {
+                eprintln!("load config file {path} error: {e}");
+                std::process::exit(1);
+            }
+        };
+    }
+
+    let listen_addr_proxy = match first_arg.parse::<SocketAddr>() {
         Ok(addr) => addr,
         Err(_) => {
             eprintln!(
@@ -92,138 +283,73 @@ async fn main() -> Result<()> {
         }
     });
 
-    let mut accept_options = AcceptOpts::default();
-    accept_options.tcp.fastopen = true;
-    accept_options.tcp.nodelay = true;
-    accept_options.tcp.mptcp = true;
-
-    let listener_proxy = TcpListener::bind_redir(
-        RedirType::PacketFilter,
-        listen_addr_proxy,
-        AcceptOpts::default(),
-    )
-    .await
-    .inspect_err(|e| {
-        eprintln!("bind listen address(forward to proxy) error: {e}");
-    })?;
-    let listener_direct = TcpListener::bind_redir(
-        RedirType::PacketFilter,
-        listen_addr_direct,
-        AcceptOpts::default(),
-    )
-    .await
-    .inspect_err(|e| {
-        eprintln!("bind listen address(direct) error: {e}");
-    })?;
-    env_logger::init();
-    log::info!(
-        "rustsocks is listening on \nProxy: {}\nDirect: {}",
-        listen_addr_proxy,
-        listen_addr_direct
+    let mut upstreams = HashMap::new();
+    upstreams.insert(
+        "http".to_owned(),
+        UpstreamConfig {
+            protocol: UpstreamProtocol::Http,
+            addr: proxy_addr,
+            auth: None,
+        },
     );
-    let mut run_service = Vec::new();
     if let Some(socks_proxy_addr) = socks_proxy {
-        log::info!("Using SOCKS5 proxy at {}", socks_proxy_addr);
-        let udp_socket_proxy = UdpRedirSocket::listen(RedirType::PacketFilter, listen_addr_proxy)?;
-        run_service.push(run(udp_socket_proxy, Proxy(socks_proxy_addr)));
-    }
-    let udp_socket_direct = UdpRedirSocket::listen(RedirType::PacketFilter, listen_addr_direct)?;
-    join!(
-        accept_stream_proxy(&listener_proxy, proxy_addr),
-        accept_stream_direct(&listener_direct),
-        run(udp_socket_direct, Direct),
-        futures::future::join_all(run_service),
-    );
-    Ok(())
-}
-
-async fn accept_stream_direct(listener: &TcpListener) {
-    loop {
-        let (stream, _client_addr) = match listener.accept().await {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("accept stream direct error: {}", e);
-                continue;
-            }
-        };
-        log::debug!("Direct: New client from: {}", _client_addr);
-        tokio::spawn(async move {
-            if let Err(e) = handle_client_direct(stream).await {
-                log::error!("handle stream direct error: {}", e);
-            }
-        });
-    }
-}
-
-async fn accept_stream_proxy(listener: &TcpListener, proxy_addr: SocketAddr) {
-    loop {
-        let (stream, _client_addr) = match listener.accept().await {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("accept stream proxy error: {}", e);
-                continue;
-            }
-        };
-        log::debug!("Proxy: New client from: {}", _client_addr);
-        tokio::spawn(async move {
-            if let Err(e) = handle_client_with_proxy(stream, proxy_addr).await {
-                log::error!("handle stream proxy error: {}", e);
-            }
-        });
+        upstreams.insert(
+            "socks5".to_owned(),
+            UpstreamConfig {
+                protocol: UpstreamProtocol::Socks5,
+                addr: socks_proxy_addr,
+                auth: socks5_auth_from_env(),
+            },
+        );
     }
-}
-
-async fn handle_client_with_proxy(mut client_stream: TcpStream, proxy: SocketAddr) -> Result<()> {
-    let orig_dst = client_stream
-        .destination_addr(RedirType::PacketFilter)
-        .map_err(|e| io::Error::other(format!("get original addr error: {e}")))?;
-    log::trace!("Original destination: {}", orig_dst);
-
-    let mut proxy_stream = connect_http(proxy, orig_dst).await?;
-    let _ = copy_bidirectional(&mut client_stream, &mut proxy_stream).await;
-    // .inspect_err(|e| log::error!("proxy stream: copy error: {e}"))?;
-    Ok(())
-}
-
-async fn handle_client_direct(mut client_stream: TcpStream) -> Result<()> {
-    let orig_dst = client_stream
-        .destination_addr(RedirType::PacketFilter)
-        .map_err(|e| io::Error::other(format!("get original addr error: {e}")))?;
 
-    log::trace!("Original destination: {}", orig_dst);
-
-    let mut another_stream = TcpStream::connect(orig_dst)
-        .await
-        .inspect_err(|e| log::error!("connect direct error: {e}"))?;
-    let _ = copy_bidirectional(&mut client_stream, &mut another_stream).await;
-    // .inspect_err(|e| log::error!("direct stream: copy error: {e}"))?;
-    Ok(())
-}
-
-async fn connect_http(proxy: SocketAddr, target: SocketAddr) -> Result<TcpStream> {
-    // connect to http proxy
-    let mut stream = TcpStream::connect(proxy)
-        .await
-        .inspect_err(|e| log::error!("connect proxy error: {e}"))?;
-
-    let connect_req = format!(
-        "CONNECT {}:{} HTTP/1.1\r\nHost: {}:{}\r\n\r\n",
-        target.ip(),
-        target.port(),
-        target.ip(),
-        target.port()
-    );
-
-    stream.write_all(connect_req.as_bytes()).await?;
-
-    // parse response
-    let mut buf = [0u8; 1024];
-
-    let n = stream.read(&mut buf).await?;
-    let resp = String::from_utf8_lossy(&buf[..n]);
-    if !resp.starts_with("HTTP/1.1 200") {
-        return Err(std::io::Error::other(format!("HTTP proxy error: {}", resp)));
+    let config = Config {
+        listeners: vec![
+            ListenerConfig {
+                addr: listen_addr_proxy,
+                tcp_redir: RedirType::tcp_default(),
+                udp_redir: RedirType::udp_default(),
+                mode: ListenerMode::Proxy,
+                upstream: Some("http".to_owned()),
+                udp_upstream: socks_proxy.map(|_| "socks5".to_owned()),
+                sniff: false,
+                dns_forward: false,
+                dns_server: None,
+                dns_snoop: false,
+            },
+            ListenerConfig {
+                addr: listen_addr_direct,
+                tcp_redir: RedirType::tcp_default(),
+                udp_redir: RedirType::udp_default(),
+                mode: ListenerMode::Direct,
+                upstream: None,
+                udp_upstream: None,
+                sniff: false,
+                dns_forward: false,
+                dns_server: None,
+                dns_snoop: false,
+            },
+        ],
+        upstreams,
+        // the shorthand binds the pf listeners with the default options, as it always did
+        accept_opts: if cfg!(any(target_os = "linux", target_os = "android")) {
+            Config::default_accept_opts()
+        } else {
+            AcceptOpts::default()
+        },
+        udp_send_back: UdpSendBackType::default(),
+        redir_opts: RedirSocketOpts::default(),
+        router: Router::default(),
+        dns: None,
+        admin: None,
+        access_log: None,
+        shutdown_timeout: Config::DEFAULT_SHUTDOWN_TIMEOUT,
+        max_open_files: None,
+        tcp_timeouts: TcpTimeouts::default(),
+    };
+    if let Err(e) = config.check_udp_send_back() {
+        eprintln!("{e}");
+        std::process::exit(1);
     }
-
-    Ok(stream)
+    (None, config)
 }
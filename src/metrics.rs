@@ -0,0 +1,423 @@
+//! Prometheus metrics, served on `/metrics` of the admin API (see `crate::admin`)
+//!
+//! The counters are process-wide like the default registry of the Prometheus clients, so they
+//! are kept across config reloads. The gauges of the sessions and the bytes relayed by the active
+//! ones are read from `Sessions` when scraped.
+
+use crate::{
+    router::Protocol,
+    session::{Session, Sessions},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Metrics of this process
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Upper bounds of the connect latency buckets, seconds
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Non-cumulative counts of `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| value <= *b) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Sessions and their bytes of an outbound
+#[derive(Clone, Copy, Debug, Default)]
+struct Relayed {
+    sessions: u64,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+/// (protocol, outbound) -> relayed
+type RelayedBytes = BTreeMap<(&'static str, String), Relayed>;
+
+/// Totals since the start, see `Metrics::totals`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Totals {
+    pub accepted: u64,
+    pub tcp_sessions: u64,
+    pub udp_associations: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub connect_failures: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// listener -> accepted TCP connections
+    accepted: Mutex<BTreeMap<SocketAddr, u64>>,
+    /// (upstream, protocol) -> latency of connecting through it
+    connect_latency: Mutex<BTreeMap<(String, &'static str), Histogram>>,
+    /// (outbound, reason) -> failures
+    connect_failures: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// The finished sessions and their bytes
+    finished: Mutex<RelayedBytes>,
+    /// (protocol, close reason) -> finished sessions
+    close_reasons: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    udp_queue_full: AtomicU64,
+    raw_socket_send_errors: AtomicU64,
+    access_log_drops: AtomicU64,
+    /// New TCP connections and UDP associations rejected by the admission limit
+    rejected_tcp: AtomicU64,
+    rejected_udp: AtomicU64,
+}
+
+impl Metrics {
+    /// A TCP connection is accepted by `listener`
+    pub fn inc_accepted(&self, listener: SocketAddr) {
+        *self.accepted.lock().unwrap().entry(listener).or_default() += 1;
+    }
+
+    /// Connected through `upstream` of `protocol` (`http` or `socks5`) in `latency`
+    pub fn observe_connect(&self, upstream: &str, protocol: &'static str, latency: Duration) {
+        self.connect_latency
+            .lock()
+            .unwrap()
+            .entry((upstream.to_owned(), protocol))
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Connecting through `outbound` failed for `reason`, see `io_failure_reason`
+    pub fn inc_connect_failure(&self, outbound: &str, reason: &'static str) {
+        *self
+            .connect_failures
+            .lock()
+            .unwrap()
+            .entry((outbound.to_owned(), reason))
+            .or_default() += 1;
+    }
+
+    /// `session` is finished, keep its bytes and close reason
+    pub fn add_finished_session(&self, session: &Session) {
+        let info = session.info();
+        let reason = session.close_reason().0.as_str();
+        *self
+            .close_reasons
+            .lock()
+            .unwrap()
+            .entry((info.protocol, reason))
+            .or_default() += 1;
+        let mut finished = self.finished.lock().unwrap();
+        let relayed = finished.entry((info.protocol, info.outbound)).or_default();
+        relayed.sessions += 1;
+        relayed.uploaded += info.uploaded;
+        relayed.downloaded += info.downloaded;
+    }
+
+    /// A UDP packet is dropped as the send queue of its association is full
+    pub fn inc_udp_queue_full(&self) {
+        self.udp_queue_full.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_raw_socket_send_error(&self) {
+        self.raw_socket_send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An access record is dropped as the writer falls behind, see `crate::access_log`
+    pub fn inc_access_log_drop(&self) {
+        self.access_log_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A new flow of `protocol` is rejected, see `crate::admission`
+    pub fn inc_rejected(&self, protocol: Protocol) {
+        let rejected = match protocol {
+            Protocol::Tcp => &self.rejected_tcp,
+            Protocol::Udp => &self.rejected_udp,
+        };
+        rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Totals of the finished sessions, and the accepted connections and connect failures
+    pub fn totals(&self) -> Totals {
+        let mut totals = Totals {
+            accepted: self.accepted.lock().unwrap().values().sum(),
+            connect_failures: self.connect_failures.lock().unwrap().values().sum(),
+            ..Totals::default()
+        };
+        for ((protocol, _), relayed) in self.finished.lock().unwrap().iter() {
+            match *protocol {
+                "tcp" => totals.tcp_sessions += relayed.sessions,
+                _ => totals.udp_associations += relayed.sessions,
+            }
+            totals.uploaded += relayed.uploaded;
+            totals.downloaded += relayed.downloaded;
+        }
+        totals
+    }
+
+    /// Metrics in the Prometheus text format, with the active `sessions`
+    pub fn render(&self, sessions: &Sessions) -> String {
+        let mut out = String::new();
+        let active = sessions.list();
+
+        header(
+            &mut out,
+            "rustsocks_accepted_connections_total",
+            "counter",
+            "TCP connections accepted by the listeners",
+        );
+        for (listener, n) in self.accepted.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rustsocks_accepted_connections_total{{listener=\"{listener}\"}} {n}"
+            );
+        }
+
+        header(
+            &mut out,
+            "rustsocks_tcp_sessions",
+            "gauge",
+            "Active TCP sessions",
+        );
+        let tcp = active.iter().filter(|s| s.protocol == "tcp").count();
+        let _ = writeln!(out, "rustsocks_tcp_sessions {tcp}");
+        header(
+            &mut out,
+            "rustsocks_udp_associations",
+            "gauge",
+            "Active UDP associations",
+        );
+        let _ = writeln!(out, "rustsocks_udp_associations {}", active.len() - tcp);
+
+        let name = "rustsocks_upstream_connect_duration_seconds";
+        header(
+            &mut out,
+            name,
+            "histogram",
+            "Latency of connecting to the destinations through the upstreams",
+        );
+        for ((upstream, protocol), histogram) in self.connect_latency.lock().unwrap().iter() {
+            let labels = format!("upstream=\"{}\",protocol=\"{protocol}\"", escape(upstream));
+            let mut cumulative = 0;
+            for (bound, n) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += n;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+            }
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "{name}_count{{{labels}}} {}", histogram.count);
+        }
+
+        header(
+            &mut out,
+            "rustsocks_connect_failures_total",
+            "counter",
+            "Failures of connecting to the destinations, by outbound and reason",
+        );
+        for ((outbound, reason), n) in self.connect_failures.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rustsocks_connect_failures_total{{outbound=\"{}\",reason=\"{reason}\"}} {n}",
+                escape(outbound)
+            );
+        }
+
+        let mut relayed = self.finished.lock().unwrap().clone();
+        header(
+            &mut out,
+            "rustsocks_finished_sessions_total",
+            "counter",
+            "Finished TCP sessions and UDP associations, by protocol and outbound",
+        );
+        for ((protocol, outbound), relayed) in relayed.iter() {
+            let _ = writeln!(
+                out,
+                "rustsocks_finished_sessions_total{{protocol=\"{protocol}\",outbound=\"{}\"}} {}",
+                escape(outbound),
+                relayed.sessions
+            );
+        }
+
+        header(
+            &mut out,
+            "rustsocks_closed_sessions_total",
+            "counter",
+            "Finished TCP sessions and UDP associations, by protocol and close reason",
+        );
+        for ((protocol, reason), n) in self.close_reasons.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rustsocks_closed_sessions_total{{protocol=\"{protocol}\",reason=\"{reason}\"}} {n}"
+            );
+        }
+
+        for session in active {
+            let entry = relayed
+                .entry((session.protocol, session.outbound))
+                .or_default();
+            entry.uploaded += session.uploaded;
+            entry.downloaded += session.downloaded;
+        }
+        header(
+            &mut out,
+            "rustsocks_relayed_bytes_total",
+            "counter",
+            "Bytes relayed, by protocol, outbound and direction",
+        );
+        for ((protocol, outbound), relayed) in relayed {
+            let labels = format!("protocol=\"{protocol}\",outbound=\"{}\"", escape(&outbound));
+            let _ = writeln!(
+                out,
+                "rustsocks_relayed_bytes_total{{{labels},direction=\"up\"}} {}",
+                relayed.uploaded
+            );
+            let _ = writeln!(
+                out,
+                "rustsocks_relayed_bytes_total{{{labels},direction=\"down\"}} {}",
+                relayed.downloaded
+            );
+        }
+
+        header(
+            &mut out,
+            "rustsocks_udp_queue_full_drops_total",
+            "counter",
+            "UDP packets dropped as the send queue of the association is full",
+        );
+        let n = self.udp_queue_full.load(Ordering::Relaxed);
+        let _ = writeln!(out, "rustsocks_udp_queue_full_drops_total {n}");
+        header(
+            &mut out,
+            "rustsocks_raw_socket_send_errors_total",
+            "counter",
+            "Errors of sending UDP packets back with the raw socket",
+        );
+        let n = self.raw_socket_send_errors.load(Ordering::Relaxed);
+        let _ = writeln!(out, "rustsocks_raw_socket_send_errors_total {n}");
+        header(
+            &mut out,
+            "rustsocks_access_log_drops_total",
+            "counter",
+            "Access records dropped as the queue of the writer is full",
+        );
+        let n = self.access_log_drops.load(Ordering::Relaxed);
+        let _ = writeln!(out, "rustsocks_access_log_drops_total {n}");
+        header(
+            &mut out,
+            "rustsocks_rejected_flows_total",
+            "counter",
+            "New TCP connections and UDP associations rejected as the file descriptors run out",
+        );
+        for (protocol, rejected) in [("tcp", &self.rejected_tcp), ("udp", &self.rejected_udp)] {
+            let n = rejected.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "rustsocks_rejected_flows_total{{protocol=\"{protocol}\"}} {n}"
+            );
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {ty}");
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reason label of a connect failure
+pub fn io_failure_reason(err: &io::Error) -> &'static str {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => "refused",
+        io::ErrorKind::TimedOut => "timeout",
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted => "reset",
+        io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => "unreachable",
+        _ => "io",
+    }
+}
+
+#[test]
+fn test_render_metrics() {
+    use crate::session::{CloseReason, SessionMeta};
+    use std::sync::Arc;
+
+    let metrics = Metrics::default();
+    let listener = "127.0.0.1:12345".parse().unwrap();
+    metrics.inc_accepted(listener);
+    metrics.observe_connect("http", "http", Duration::from_millis(30));
+    metrics.observe_connect("http", "http", Duration::from_secs(20));
+    metrics.inc_connect_failure("direct", "refused");
+    metrics.inc_rejected(Protocol::Udp);
+
+    let sessions = Arc::new(Sessions::new());
+    let meta = SessionMeta {
+        protocol: Protocol::Tcp,
+        listener,
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "1.2.3.4:443".parse().unwrap(),
+        domain: None,
+        outbound: "direct".to_owned(),
+        upstream: None,
+    };
+    let finished = sessions.register(meta.clone());
+    finished.add_uploaded(100);
+    finished.set_close_reason(CloseReason::IdleTimeout, None);
+    metrics.add_finished_session(&finished);
+    drop(finished);
+    let active = sessions.register(meta);
+    active.add_uploaded(10);
+    active.add_downloaded(20);
+
+    let totals = metrics.totals();
+    assert_eq!((totals.tcp_sessions, totals.uploaded), (1, 100));
+
+    let text = metrics.render(&sessions);
+    for line in [
+        "rustsocks_accepted_connections_total{listener=\"127.0.0.1:12345\"} 1",
+        "rustsocks_tcp_sessions 1",
+        "rustsocks_udp_associations 0",
+        "rustsocks_upstream_connect_duration_seconds_bucket{upstream=\"http\",protocol=\"http\",le=\"0.025\"} 0",
+        "rustsocks_upstream_connect_duration_seconds_bucket{upstream=\"http\",protocol=\"http\",le=\"0.05\"} 1",
+        "rustsocks_upstream_connect_duration_seconds_bucket{upstream=\"http\",protocol=\"http\",le=\"10\"} 1",
+        "rustsocks_upstream_connect_duration_seconds_bucket{upstream=\"http\",protocol=\"http\",le=\"+Inf\"} 2",
+        "rustsocks_upstream_connect_duration_seconds_count{upstream=\"http\",protocol=\"http\"} 2",
+        "rustsocks_connect_failures_total{outbound=\"direct\",reason=\"refused\"} 1",
+        "rustsocks_finished_sessions_total{protocol=\"tcp\",outbound=\"direct\"} 1",
+        "rustsocks_closed_sessions_total{protocol=\"tcp\",reason=\"idle_timeout\"} 1",
+        "rustsocks_relayed_bytes_total{protocol=\"tcp\",outbound=\"direct\",direction=\"up\"} 110",
+        "rustsocks_relayed_bytes_total{protocol=\"tcp\",outbound=\"direct\",direction=\"down\"} 20",
+        "rustsocks_udp_queue_full_drops_total 0",
+        "rustsocks_rejected_flows_total{protocol=\"tcp\"} 0",
+        "rustsocks_rejected_flows_total{protocol=\"udp\"} 1",
+    ] {
+        assert!(text.lines().any(|l| l == line), "{line}\n{text}");
+    }
+}
@@ -0,0 +1,376 @@
+//! Running without root: checking the privileges the config needs, and switching to another user
+//! once the sockets are opened
+//!
+//! On Linux, `IP_TRANSPARENT` needs `CAP_NET_RAW` or `CAP_NET_ADMIN`, raw sockets need
+//! `CAP_NET_RAW`, `SO_MARK` needs `CAP_NET_ADMIN`, and ports below 1024 need
+//! `CAP_NET_BIND_SERVICE`. The capabilities are per thread, so the user is switched before the
+//! runtime starts, and its threads inherit the reduced sets.
+
+use crate::{
+    service::serves_udp,
+    systemd::ActivatedSockets,
+    utils::config::{AdminAddr, Config, RedirType, UdpSendBackType},
+};
+use cfg_if::cfg_if;
+use nix::unistd::{Gid, Group, Uid, User};
+use std::{io, net::SocketAddr};
+
+/// User and group to switch to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunAs {
+    pub user: String,
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+impl RunAs {
+    /// Look up `user` and `group` by names or IDs, the primary group of `user` is used by default
+    pub fn resolve(user: &str, group: Option<&str>) -> io::Result<RunAs> {
+        let not_found = |what: &str| io::Error::new(io::ErrorKind::NotFound, what.to_owned());
+        let (uid, primary_gid) = match user.parse() {
+            Ok(uid) => {
+                let uid = Uid::from_raw(uid);
+                (uid, User::from_uid(uid)?.map(|u| u.gid))
+            }
+            Err(_) => {
+                let entry = User::from_name(user)?
+                    .ok_or_else(|| not_found(&format!("user {user} is not found")))?;
+                (entry.uid, Some(entry.gid))
+            }
+        };
+        let gid = match group {
+            Some(group) => match group.parse() {
+                Ok(gid) => Gid::from_raw(gid),
+                Err(_) => {
+                    Group::from_name(group)?
+                        .ok_or_else(|| not_found(&format!("group {group} is not found")))?
+                        .gid
+                }
+            },
+            None => primary_gid
+                .ok_or_else(|| not_found(&format!("user {user} has no primary group")))?,
+        };
+        Ok(RunAs {
+            user: user.to_owned(),
+            uid,
+            gid,
+        })
+    }
+}
+
+/// What the relays of a config do which needs privileges
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(any(target_os = "linux", target_os = "android")), allow(dead_code))]
+struct Needs {
+    /// `IP_TRANSPARENT` of the TPROXY listeners and the UDP send back sockets
+    transparent: bool,
+    /// Raw sockets sending back the UDP packets
+    raw_socket: bool,
+    /// `SO_MARK` of the outbound and send-back sockets
+    fwmark: bool,
+    /// Binding ports below 1024, only on startup
+    privileged_ports: bool,
+}
+
+impl Needs {
+    /// Needs of `config`, the addresses in `activated` are bound already
+    fn of(config: &Config, activated: &ActivatedSockets) -> Needs {
+        let privileged = |addr: &SocketAddr| addr.port() != 0 && addr.port() < 1024;
+        let mut needs = Needs {
+            fwmark: config.redir_opts.fwmark.is_some(),
+            ..Needs::default()
+        };
+        for listener in &config.listeners {
+            let udp = serves_udp(config, listener);
+            cfg_if! {
+                if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                    needs.transparent |= udp || listener.tcp_redir == RedirType::TProxy;
+                }
+            }
+            needs.raw_socket |= udp && config.udp_send_back == UdpSendBackType::RawSocket;
+            needs.privileged_ports |= privileged(&listener.addr)
+                && (!activated.has_tcp(listener.addr) || udp && !activated.has_udp(listener.addr));
+        }
+        if let Some(ref dns) = config.dns {
+            needs.privileged_ports |= privileged(&dns.addr)
+                && !(activated.has_tcp(dns.addr) && activated.has_udp(dns.addr));
+        }
+        if let Some(ref admin) = config.admin
+            && let AdminAddr::Tcp(ref addr) = admin.addr
+        {
+            needs.privileged_ports |= privileged(addr) && !activated.has_tcp(*addr);
+        }
+        needs
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        use crate::{redir::redir_ext::TcpListenerRedirExt, udp_relay::UdpRedirSocket};
+        use caps::{CapSet, Capability, CapsHashSet};
+        use std::os::fd::OwnedFd;
+
+        /// Check if the effective capabilities are enough for `config`, and for switching to
+        /// `run_as` if any
+        pub fn check(
+            config: &Config,
+            activated: &ActivatedSockets,
+            run_as: Option<&RunAs>,
+        ) -> io::Result<()> {
+            let has = |cap| caps::has_cap(None, CapSet::Effective, cap).unwrap_or(false);
+            let needs = Needs::of(config, activated);
+            let mut missing = Vec::new();
+            if needs.transparent
+                && !has(Capability::CAP_NET_RAW)
+                && !has(Capability::CAP_NET_ADMIN)
+            {
+                missing.push("CAP_NET_RAW or CAP_NET_ADMIN (IP_TRANSPARENT)");
+            }
+            if needs.raw_socket && !has(Capability::CAP_NET_RAW) {
+                missing.push("CAP_NET_RAW (raw socket)");
+            }
+            if needs.fwmark && !has(Capability::CAP_NET_ADMIN) {
+                missing.push("CAP_NET_ADMIN (SO_MARK)");
+            }
+            if needs.privileged_ports && !has(Capability::CAP_NET_BIND_SERVICE) {
+                missing.push("CAP_NET_BIND_SERVICE (ports below 1024)");
+            }
+            if run_as.is_some() {
+                if !has(Capability::CAP_SETUID) {
+                    missing.push("CAP_SETUID (--user)");
+                }
+                if !has(Capability::CAP_SETGID) {
+                    missing.push("CAP_SETGID (--group)");
+                }
+            }
+            match missing.is_empty() {
+                true => Ok(()),
+                false => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("missing capabilities: {}", missing.join(", ")),
+                )),
+            }
+        }
+
+        /// Bind the listeners, the DNS server and the TCP admin API of `config` into `activated`,
+        /// so they are not bound again after switching the user
+        pub fn open_sockets(config: &Config, activated: &mut ActivatedSockets) -> io::Result<()> {
+            const SOURCE: &str = "bound before switching the user";
+
+            // no thread is started, see `switch_user`
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()?;
+            for listener in &config.listeners {
+                if !activated.has_tcp(listener.addr) {
+                    let tcp = runtime
+                        .block_on(tokio::net::TcpListener::bind_redir(
+                            listener.tcp_redir,
+                            listener.addr,
+                            config.accept_opts.clone(),
+                        ))
+                        .and_then(|l| l.into_std())
+                        .inspect_err(|e| eprintln!("bind listen address {} error: {e}", listener.addr))?;
+                    activated.insert(OwnedFd::from(tcp), SOURCE.to_owned())?;
+                }
+                if serves_udp(config, listener) && !activated.has_udp(listener.addr) {
+                    let _guard = runtime.enter();
+                    let udp = UdpRedirSocket::listen(
+                        listener.udp_redir,
+                        listener.addr,
+                        &config.accept_opts,
+                        &config.redir_opts,
+                    )
+                    .and_then(|s| s.into_std())
+                    .inspect_err(|e| eprintln!("bind UDP {} error: {e}", listener.addr))?;
+                    activated.insert(OwnedFd::from(udp), SOURCE.to_owned())?;
+                }
+            }
+            if let Some(ref dns) = config.dns {
+                let error = |e: &io::Error| eprintln!("bind DNS address {} error: {e}", dns.addr);
+                if !activated.has_udp(dns.addr) {
+                    let udp = std::net::UdpSocket::bind(dns.addr).inspect_err(error)?;
+                    activated.insert(OwnedFd::from(udp), SOURCE.to_owned())?;
+                }
+                if !activated.has_tcp(dns.addr) {
+                    let tcp = runtime
+                        .block_on(tokio::net::TcpListener::bind(dns.addr))
+                        .and_then(|l| l.into_std())
+                        .inspect_err(error)?;
+                    activated.insert(OwnedFd::from(tcp), SOURCE.to_owned())?;
+                }
+            }
+            if let Some(ref admin) = config.admin
+                && let AdminAddr::Tcp(addr) = admin.addr
+                && !activated.has_tcp(addr)
+            {
+                let tcp = runtime
+                    .block_on(tokio::net::TcpListener::bind(addr))
+                    .and_then(|l| l.into_std())
+                    .inspect_err(|e| eprintln!("bind admin address {addr} error: {e}"))?;
+                activated.insert(OwnedFd::from(tcp), SOURCE.to_owned())?;
+            }
+            Ok(())
+        }
+
+        /// Switch to `run_as`, keeping only the capabilities `config` needs at runtime:
+        /// `CAP_NET_RAW` for raw sockets and `IP_TRANSPARENT`, and `CAP_NET_ADMIN` for `SO_MARK`
+        ///
+        /// It must be called before starting any thread, as the capabilities of the other threads
+        /// are not changed.
+        pub fn switch_user(run_as: &RunAs, config: &Config) -> io::Result<()> {
+            let needs = Needs::of(config, &ActivatedSockets::default());
+            let has_raw = caps::has_cap(None, CapSet::Permitted, Capability::CAP_NET_RAW)
+                .map_err(io::Error::other)?;
+            let mut keep = CapsHashSet::new();
+            if needs.raw_socket || needs.transparent && has_raw {
+                keep.insert(Capability::CAP_NET_RAW);
+            }
+            if needs.fwmark || needs.transparent && !has_raw {
+                keep.insert(Capability::CAP_NET_ADMIN);
+            }
+
+            // no other capability can be gained again, even by `execve()`
+            for cap in caps::read(None, CapSet::Bounding).map_err(io::Error::other)? {
+                if !keep.contains(&cap) {
+                    caps::drop(None, CapSet::Bounding, cap).map_err(io::Error::other)?;
+                }
+            }
+            // the permitted capabilities are cleared by `setuid()` without keepcaps
+            caps::securebits::set_keepcaps(true).map_err(io::Error::other)?;
+            nix::unistd::setgroups(&[run_as.gid])?;
+            nix::unistd::setgid(run_as.gid)?;
+            nix::unistd::setuid(run_as.uid)?;
+            caps::securebits::set_keepcaps(false).map_err(io::Error::other)?;
+            caps::set(None, CapSet::Permitted, &keep).map_err(io::Error::other)?;
+            caps::set(None, CapSet::Effective, &keep).map_err(io::Error::other)?;
+            caps::clear(None, CapSet::Inheritable).map_err(io::Error::other)?;
+
+            let mut keep: Vec<String> = keep.iter().map(|cap| cap.to_string()).collect();
+            keep.sort();
+            log::info!(
+                "running as user {} (uid {}, gid {}) with capabilities [{}]",
+                run_as.user,
+                run_as.uid,
+                run_as.gid,
+                keep.join(", ")
+            );
+            Ok(())
+        }
+    } else {
+        /// Check if running as root, which is needed for `/dev/pf`
+        pub fn check(
+            config: &Config,
+            activated: &ActivatedSockets,
+            run_as: Option<&RunAs>,
+        ) -> io::Result<()> {
+            if !nix::unistd::geteuid().is_root() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "You should run rustsocks with root privilege!",
+                ));
+            }
+            if run_as.is_some() && Needs::of(config, activated).raw_socket {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "--user can't be used with UDP relays, raw sockets need root on this platform",
+                ));
+            }
+            Ok(())
+        }
+
+        /// Open `/dev/pf` if any listener uses it, the listeners are bound after switching the
+        /// user
+        pub fn open_sockets(config: &Config, _activated: &mut ActivatedSockets) -> io::Result<()> {
+            cfg_if! {
+                if #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))] {
+                    let pf = config.listeners.iter().any(|l| {
+                        l.tcp_redir == RedirType::PacketFilter
+                            || l.udp_redir == RedirType::PacketFilter
+                    });
+                    if pf {
+                        std::sync::LazyLock::force(&crate::redir::bsd_pf::PF);
+                    }
+                } else {
+                    let _ = config;
+                }
+            }
+            Ok(())
+        }
+
+        /// Switch to `run_as`
+        pub fn switch_user(run_as: &RunAs, _config: &Config) -> io::Result<()> {
+            let gid = run_as.gid.as_raw();
+            // SAFETY: `gid` is a valid array of 1 group
+            if unsafe { libc::setgroups(1, &gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            nix::unistd::setgid(run_as.gid)?;
+            nix::unistd::setuid(run_as.uid)?;
+            log::info!(
+                "running as user {} (uid {}, gid {})",
+                run_as.user,
+                run_as.uid,
+                run_as.gid
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn test_needs() {
+    use crate::utils::config::ConfigType;
+
+    let config = Config::load_from_str(
+        r#"
+        fwmark = 1
+
+        [[listeners]]
+        address = "127.0.0.1:80"
+        mode = "direct"
+
+        [udp]
+        send_back = "raw"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+    let needs = Needs::of(&config, &ActivatedSockets::default());
+    assert_eq!(
+        needs,
+        Needs {
+            transparent: true,
+            raw_socket: true,
+            fwmark: true,
+            privileged_ports: true,
+        }
+    );
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[ignore = "this test needs root privilege"]
+#[test]
+fn test_open_sockets() {
+    use crate::utils::config::ConfigType;
+
+    let config = Config::load_from_str(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:23455"
+        mode = "direct"
+
+        [admin]
+        address = "127.0.0.1:1023"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+    assert!(Needs::of(&config, &ActivatedSockets::default()).privileged_ports);
+
+    let mut activated = ActivatedSockets::default();
+    open_sockets(&config, &mut activated).unwrap();
+    assert!(activated.has_tcp("127.0.0.1:1023".parse().unwrap()));
+    assert!(!Needs::of(&config, &activated).privileged_ports);
+}
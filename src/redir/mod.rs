@@ -1,5 +1,6 @@
 use cfg_if::cfg_if;
 
+#[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))]
 pub mod bsd_pf;
 pub mod redir_ext;
 pub mod sys;
@@ -77,8 +77,8 @@ where
     }
 }
 
-// sockopts for send-back sockets
-#[derive(Debug, Clone, Default)]
+// sockopts for the outbound and send-back sockets
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct RedirSocketOpts {
     /// Linux mark based routing, going to set by `setsockopt` with `SO_MARK` option
     #[cfg(any(target_os = "linux", target_os = "android"))]
@@ -1,7 +1,11 @@
 //! modified from shadowsocks-service/src/local/redir/sys/mod.rs
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::utils::net::is_dual_stack_addr;
 use socket2::Socket;
 use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::net::SocketAddr;
 
 #[cfg(unix)]
 #[allow(dead_code)]
@@ -17,3 +21,62 @@ where
     let _ = sock.into_raw_fd();
     result
 }
+
+/// Set `IP_TRANSPARENT` (or `IPV6_TRANSPARENT`) to allow binding to and receiving from
+/// non-local addresses, which is required by netfilter's `TPROXY` target
+///
+/// Dual-stack sockets need both options, because IPv4 traffic arrives with `SOL_IP` semantics.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_ip_transparent<S>(socket: &S, addr: &SocketAddr) -> io::Result<()>
+where
+    S: std::os::unix::io::AsRawFd,
+{
+    let fd = socket.as_raw_fd();
+
+    match *addr {
+        SocketAddr::V4(..) => set_int_opt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1),
+        SocketAddr::V6(..) => {
+            set_int_opt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT, 1)?;
+            if is_dual_stack_addr(addr) {
+                set_int_opt(fd, libc::SOL_IP, libc::IP_TRANSPARENT, 1)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Set `SO_MARK` for Linux mark based routing
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_fwmark<S>(socket: &S, mark: u32) -> io::Result<()>
+where
+    S: std::os::unix::io::AsRawFd,
+{
+    set_int_opt(
+        socket.as_raw_fd(),
+        libc::SOL_SOCKET,
+        libc::SO_MARK,
+        mark as libc::c_int,
+    )
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn set_int_opt(
+    fd: libc::c_int,
+    level: libc::c_int,
+    opt: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            opt,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,344 @@
+//! Domain name matching with a suffix trie, keywords and regular expressions
+//!
+//! Rules can be loaded from gfwlist (base64 encoded AdBlock syntax) and plain domain-list files.
+
+use aho_corasick::AhoCorasick;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use regex::RegexSet;
+use std::{collections::HashMap, fmt, io};
+
+/// Error while building `DomainMatcher`
+#[derive(Debug, thiserror::Error)]
+pub enum DomainMatcherError {
+    #[error("invalid regex, {0}")]
+    Regex(#[from] regex::Error),
+    #[error("build keyword matcher error, {0}")]
+    Keyword(#[from] aho_corasick::BuildError),
+}
+
+/// A node of `SuffixTrie`, for one label of the domain names
+#[derive(Debug, Default)]
+struct TrieNode {
+    children: HashMap<Box<str>, TrieNode>,
+    /// Matches the domain name ending here
+    full: bool,
+    /// Matches the domain name ending here, and its subdomains
+    suffix: bool,
+}
+
+/// Domain names keyed by their labels in reversed order, `www.example.com` is stored as
+/// `com` -> `example` -> `www`
+#[derive(Debug, Default)]
+struct SuffixTrie {
+    root: TrieNode,
+}
+
+impl SuffixTrie {
+    fn insert(&mut self, domain: &str, suffix: bool) {
+        let mut node = &mut self.root;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.into()).or_default();
+        }
+        if suffix {
+            node.suffix = true;
+        } else {
+            node.full = true;
+        }
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        let mut node = &self.root;
+        let mut labels = domain.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+            if node.suffix || (node.full && labels.peek().is_none()) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Collects the domain rules for `DomainMatcher`
+#[derive(Default)]
+pub struct DomainMatcherBuilder {
+    trie: SuffixTrie,
+    /// Exceptions of gfwlist, `@@` rules
+    exclude: SuffixTrie,
+    keywords: Vec<String>,
+    regexes: Vec<String>,
+    len: usize,
+}
+
+impl DomainMatcherBuilder {
+    pub fn new() -> DomainMatcherBuilder {
+        DomainMatcherBuilder::default()
+    }
+
+    /// Match `domain` and its subdomains
+    pub fn add_suffix(&mut self, domain: &str) {
+        if let Some(domain) = normalize(domain) {
+            self.trie.insert(&domain, true);
+            self.len += 1;
+        }
+    }
+
+    /// Match `domain` only
+    pub fn add_full(&mut self, domain: &str) {
+        if let Some(domain) = normalize(domain) {
+            self.trie.insert(&domain, false);
+            self.len += 1;
+        }
+    }
+
+    /// Match the domain names containing `keyword`
+    pub fn add_keyword(&mut self, keyword: &str) {
+        self.keywords.push(keyword.to_ascii_lowercase());
+        self.len += 1;
+    }
+
+    /// Match the domain names matching the regular expression `regex`
+    pub fn add_regex(&mut self, regex: &str) {
+        self.regexes.push(regex.to_owned());
+        self.len += 1;
+    }
+
+    /// Load a domain list, one rule per line, `#` starts a comment
+    ///
+    /// - `example.com`, `.example.com`, `+.example.com` or `domain:example.com`: domain and subdomains
+    /// - `full:example.com`: the domain only
+    /// - `keyword:example`: domain names containing the keyword
+    /// - `regexp:^ad[0-9]*\.`: domain names matching the regular expression
+    pub fn load_domain_list(&mut self, content: &str) -> io::Result<()> {
+        for (i, line) in content.lines().enumerate() {
+            let line = match line.split_once('#') {
+                Some((line, _)) => line.trim(),
+                None => line.trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            // attributes of v2fly domain-list-community, e.g. `example.com @ads`
+            let line = line.split_whitespace().next().unwrap_or(line);
+
+            match line.split_once(':') {
+                Some(("domain", domain)) => self.add_suffix(domain),
+                Some(("full", domain)) => self.add_full(domain),
+                Some(("keyword", keyword)) => self.add_keyword(keyword),
+                Some(("regexp", regex)) => self.add_regex(regex),
+                Some(..) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: unknown rule \"{line}\"", i + 1),
+                    ));
+                }
+                None => {
+                    let domain = line.trim_start_matches("+.").trim_start_matches('.');
+                    self.add_suffix(domain)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Load a gfwlist, AdBlock syntax encoded with base64
+    ///
+    /// Only the host parts of the rules are used, as the path of a request is unknown.
+    pub fn load_gfwlist(&mut self, content: &str) -> io::Result<()> {
+        let encoded = content
+            .split_ascii_whitespace()
+            .collect::<Vec<_>>()
+            .concat();
+        let decoded = STANDARD
+            .decode(encoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        for line in decoded.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+
+            let (line, exception) = match line.strip_prefix("@@") {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if let Some(regex) = line.strip_prefix('/').and_then(|l| l.strip_suffix('/')) {
+                if !exception {
+                    self.add_regex(regex);
+                }
+                continue;
+            }
+
+            // `||example.com`, `|http://example.com/path`, `.example.com` or `example.com/path`
+            let host = line.trim_start_matches('|');
+            let host = host
+                .strip_prefix("http://")
+                .or_else(|| host.strip_prefix("https://"))
+                .unwrap_or(host);
+            let host = host.split(['/', '^', ':']).next().unwrap_or_default();
+            let host = host.trim_start_matches('.');
+            // wildcards in the host can not be matched by domain names reliably
+            if host.is_empty() || host.contains('*') || !host.contains('.') {
+                continue;
+            }
+
+            if exception {
+                if let Some(host) = normalize(host) {
+                    self.exclude.insert(&host, true);
+                }
+            } else {
+                self.add_suffix(host);
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of the rules added
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn build(self) -> Result<DomainMatcher, DomainMatcherError> {
+        let keywords = match self.keywords.is_empty() {
+            true => None,
+            false => Some(AhoCorasick::new(&self.keywords)?),
+        };
+        let regexes = match self.regexes.is_empty() {
+            true => None,
+            false => Some(RegexSet::new(&self.regexes)?),
+        };
+        Ok(DomainMatcher {
+            trie: self.trie,
+            exclude: self.exclude,
+            keywords,
+            regexes,
+            len: self.len,
+        })
+    }
+}
+
+/// Matches lowercase domain names against suffixes, full names, keywords and regular expressions
+pub struct DomainMatcher {
+    trie: SuffixTrie,
+    exclude: SuffixTrie,
+    keywords: Option<AhoCorasick>,
+    regexes: Option<RegexSet>,
+    len: usize,
+}
+
+impl DomainMatcher {
+    /// Check if lowercase `domain` matches any rule
+    pub fn matches(&self, domain: &str) -> bool {
+        if self.exclude.matches(domain) {
+            return false;
+        }
+        self.trie.matches(domain)
+            || self.keywords.as_ref().is_some_and(|k| k.is_match(domain))
+            || self.regexes.as_ref().is_some_and(|r| r.is_match(domain))
+    }
+
+    /// Number of the rules
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Debug for DomainMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DomainMatcher")
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+/// Lowercase `domain` without the trailing dot
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim().trim_end_matches('.');
+    (!domain.is_empty()).then(|| domain.to_ascii_lowercase())
+}
+
+#[test]
+fn test_domain_matcher() {
+    let mut builder = DomainMatcherBuilder::new();
+    builder.add_suffix("Google.com.");
+    builder.add_full("example.com");
+    builder
+        .load_domain_list(
+            "# comment\n\
+             +.github.io\n\
+             full:www.example.org @cn\n\
+             keyword:ads\n\
+             regexp:^track[0-9]+\\.\n",
+        )
+        .unwrap();
+    assert_eq!(builder.len(), 6);
+    assert!(builder.load_domain_list("unknown:example.com").is_err());
+    let matcher = builder.build().unwrap();
+
+    for domain in [
+        "google.com",
+        "www.google.com",
+        "example.com",
+        "user.github.io",
+        "www.example.org",
+        "cdn.ads.net",
+        "track1.example.net",
+    ] {
+        assert!(matcher.matches(domain), "{domain}");
+    }
+    for domain in [
+        "notgoogle.com",
+        "com",
+        "www.example.com",
+        "example.org",
+        "track.example.net",
+    ] {
+        assert!(!matcher.matches(domain), "{domain}");
+    }
+}
+
+#[test]
+fn test_gfwlist() {
+    let list = "[AutoProxy 0.2.9]\n\
+                ! comment\n\
+                ||blocked.com\n\
+                |https://www.blocked.net/path\n\
+                .blocked.org\n\
+                blocked.io/path\n\
+                *.wildcard.com\n\
+                @@||allowed.blocked.com\n\
+                /^ad[0-9]+\\.example\\.com/\n";
+    let mut builder = DomainMatcherBuilder::new();
+    builder.load_gfwlist(&STANDARD.encode(list)).unwrap();
+    let matcher = builder.build().unwrap();
+
+    for domain in [
+        "blocked.com",
+        "a.blocked.com",
+        "www.blocked.net",
+        "blocked.org",
+        "blocked.io",
+        "ad1.example.com",
+    ] {
+        assert!(matcher.matches(domain), "{domain}");
+    }
+    for domain in ["allowed.blocked.com", "a.wildcard.com", "example.com"] {
+        assert!(!matcher.matches(domain), "{domain}");
+    }
+
+    assert!(DomainMatcherBuilder::new().load_gfwlist("!!!").is_err());
+}
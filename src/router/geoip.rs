@@ -0,0 +1,215 @@
+//! GeoIP lookups in MaxMind DB (`.mmdb`) files
+
+use arc_swap::ArcSwap;
+use maxminddb::{MaxMindDBError, Mmap, Reader, geoip2};
+use std::{
+    fmt::{self, Debug, Formatter},
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A memory-mapped MaxMind DB, e.g. GeoLite2-Country or GeoLite2-ASN
+///
+/// NOTE: Replace the file by renaming a new one over it. Writing the mapped file in place may
+/// crash the lookups.
+pub struct GeoIpDatabase {
+    path: PathBuf,
+    reader: ArcSwap<Reader<Mmap>>,
+    /// Modification time of the mapped file
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl GeoIpDatabase {
+    /// Map the database at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GeoIpDatabase, MaxMindDBError> {
+        let path = path.as_ref().to_owned();
+        let modified = modified_time(&path);
+        let reader = Reader::open_mmap(&path)?;
+        Ok(GeoIpDatabase {
+            path,
+            reader: ArcSwap::from_pointee(reader),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Map the file again if it is modified after it was mapped, returns `true` if reloaded
+    ///
+    /// The lookups keep using the old mapping until the new one is loaded successfully.
+    pub fn reload_if_modified(&self) -> Result<bool, MaxMindDBError> {
+        let Some(modified) = modified_time(&self.path) else {
+            // being replaced, or removed. Keep the old one
+            return Ok(false);
+        };
+        let mut mapped = self.modified.lock().unwrap();
+        if *mapped == Some(modified) {
+            return Ok(false);
+        }
+
+        let reader = Reader::open_mmap(&self.path)?;
+        self.reader.store(Arc::new(reader));
+        *mapped = Some(modified);
+        Ok(true)
+    }
+
+    /// ISO 3166-1 country code of `ip`, e.g. `CN`
+    ///
+    /// Falls back to the country where the network is registered.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.reader.load();
+        let country: geoip2::Country = reader.lookup(ip.to_canonical()).ok()?;
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .or_else(|| country.registered_country.and_then(|c| c.iso_code))
+            .map(str::to_owned)
+    }
+
+    /// Autonomous system number of `ip`
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        let reader = self.reader.load();
+        let asn: geoip2::Asn = reader.lookup(ip.to_canonical()).ok()?;
+        asn.autonomous_system_number
+    }
+}
+
+impl Debug for GeoIpDatabase {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("GeoIpDatabase")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Build an IPv4 MaxMind DB with `(network, prefix length, data)`, the data is encoded already
+#[cfg(test)]
+fn build_test_database(networks: &[(std::net::Ipv4Addr, u8, Vec<u8>)]) -> Vec<u8> {
+    const EMPTY: u64 = u64::MAX;
+    const DATA: u64 = 1 << 32;
+
+    let mut nodes = vec![[EMPTY; 2]];
+    let mut data = Vec::new();
+    for (network, prefix_len, value) in networks {
+        let bits = u32::from(*network);
+        let mut node = 0;
+        for i in 0..*prefix_len {
+            let bit = ((bits >> (31 - i)) & 1) as usize;
+            if i + 1 == *prefix_len {
+                nodes[node][bit] = DATA | data.len() as u64;
+            } else {
+                if nodes[node][bit] == EMPTY {
+                    nodes.push([EMPTY; 2]);
+                    nodes[node][bit] = (nodes.len() - 1) as u64;
+                }
+                node = nodes[node][bit] as usize;
+            }
+        }
+        data.extend_from_slice(value);
+    }
+
+    // 24 bits records
+    let node_count = nodes.len() as u64;
+    let mut db = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            EMPTY => node_count,
+            r if r & DATA != 0 => node_count + 16 + (r & !DATA),
+            r => r,
+        };
+        db.extend_from_slice(&value.to_be_bytes()[5..]);
+    }
+    db.extend_from_slice(&[0u8; 16]);
+    db.extend_from_slice(&data);
+
+    db.extend_from_slice(b"\xab\xcd\xefMaxMind.com");
+    db.extend_from_slice(&test_map(&[
+        ("binary_format_major_version", test_uint(5, 2)),
+        ("binary_format_minor_version", test_uint(5, 0)),
+        ("build_epoch", vec![0x00, 0x02]),
+        ("database_type", test_string("Test")),
+        ("description", vec![0xe0]),
+        ("ip_version", test_uint(5, 4)),
+        ("languages", vec![0x00, 0x04]),
+        ("node_count", test_uint(6, node_count)),
+        ("record_size", test_uint(5, 24)),
+    ]));
+    db
+}
+
+/// MaxMind DB encoded string
+#[cfg(test)]
+fn test_string(s: &str) -> Vec<u8> {
+    [vec![0x40 | s.len() as u8], s.as_bytes().to_vec()].concat()
+}
+
+/// MaxMind DB encoded unsigned integer of type `ty`, 5 for uint16 and 6 for uint32
+#[cfg(test)]
+fn test_uint(ty: u8, v: u64) -> Vec<u8> {
+    let bytes = v.to_be_bytes();
+    let bytes = &bytes[v.leading_zeros() as usize / 8..];
+    [vec![(ty << 5) | bytes.len() as u8], bytes.to_vec()].concat()
+}
+
+/// MaxMind DB encoded map
+#[cfg(test)]
+fn test_map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut map = vec![0xe0 | entries.len() as u8];
+    for (key, value) in entries {
+        map.extend(test_string(key));
+        map.extend_from_slice(value);
+    }
+    map
+}
+
+#[test]
+fn test_lookup_and_reload() {
+    let country = |code| test_map(&[("country", test_map(&[("iso_code", test_string(code))]))]);
+    let path = std::env::temp_dir().join(format!("rustsocks-test-{}.mmdb", std::process::id()));
+    let write = |networks: Vec<(std::net::Ipv4Addr, u8, Vec<u8>)>, modified| {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, build_test_database(&networks)).unwrap();
+        let file = fs::File::options().write(true).open(&tmp).unwrap();
+        file.set_modified(modified).unwrap();
+        fs::rename(&tmp, &path).unwrap();
+    };
+
+    write(
+        vec![
+            ("1.0.0.0".parse().unwrap(), 8, country("CN")),
+            ("8.8.8.0".parse().unwrap(), 24, country("US")),
+        ],
+        SystemTime::UNIX_EPOCH,
+    );
+    let db = GeoIpDatabase::open(&path).unwrap();
+    assert_eq!(
+        db.country("1.2.3.4".parse().unwrap()).as_deref(),
+        Some("CN")
+    );
+    assert_eq!(
+        db.country("8.8.8.8".parse().unwrap()).as_deref(),
+        Some("US")
+    );
+    assert_eq!(db.country("9.9.9.9".parse().unwrap()), None);
+    assert!(!db.reload_if_modified().unwrap());
+
+    let asn = test_map(&[("autonomous_system_number", test_uint(6, 4134))]);
+    write(
+        vec![("1.0.0.0".parse().unwrap(), 8, asn)],
+        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1),
+    );
+    assert!(db.reload_if_modified().unwrap());
+    assert_eq!(db.asn("1.2.3.4".parse().unwrap()), Some(4134));
+    assert_eq!(db.country("8.8.8.8".parse().unwrap()), None);
+
+    let _ = fs::remove_file(&path);
+}
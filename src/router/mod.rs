@@ -0,0 +1,259 @@
+//! Rule based routing, choosing the outbound of a flow by its addresses and protocol
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{
+    cell::OnceCell,
+    fmt::{self, Display, Formatter},
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    sync::Arc,
+};
+
+use self::{domain::DomainMatcher, geoip::GeoIpDatabase};
+
+pub mod domain;
+pub mod geoip;
+
+/// Transport protocol of a flow
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Where a flow goes
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum Outbound {
+    /// Connect to the original destination directly
+    #[default]
+    Direct,
+    /// Drop the flow
+    Reject,
+    /// Forward through the upstream proxy of this name
+    ///
+    /// UDP flows can only be forwarded through SOCKS5 upstreams, they are dropped if it is an
+    /// HTTP upstream.
+    Proxy(String),
+}
+
+impl Outbound {
+    /// `direct`, `reject`, or the name of an upstream
+    pub fn from_name(name: &str) -> Outbound {
+        match name {
+            "direct" => Outbound::Direct,
+            "reject" => Outbound::Reject,
+            name => Outbound::Proxy(name.to_owned()),
+        }
+    }
+
+    /// Check if `name` can not be used as an upstream name
+    pub fn is_reserved_name(name: &str) -> bool {
+        matches!(name, "direct" | "reject")
+    }
+}
+
+impl Display for Outbound {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Outbound::Direct => f.write_str("direct"),
+            Outbound::Reject => f.write_str("reject"),
+            Outbound::Proxy(name) => f.write_str(name),
+        }
+    }
+}
+
+/// A redirected TCP connection or UDP association to be routed
+#[derive(Clone, Copy, Debug)]
+pub struct Flow<'a> {
+    pub protocol: Protocol,
+    /// Address of the client
+    pub src: SocketAddr,
+    /// Original destination
+    pub dst: SocketAddr,
+    /// Lowercase domain name of the destination, sniffed from the first bytes of the flow
+    pub domain: Option<&'a str>,
+}
+
+/// A routing rule, matches the flows meeting all of its conditions
+///
+/// An empty condition matches every flow.
+#[derive(Clone, Debug, Default)]
+pub struct Rule {
+    /// Destination networks
+    pub dst: Vec<IpNet>,
+    /// Destination port ranges
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// Client networks
+    pub src: Vec<IpNet>,
+    pub protocol: Option<Protocol>,
+    /// ISO 3166-1 country codes of the destination, in `Router::country_db`
+    pub country: Vec<String>,
+    /// Autonomous system numbers of the destination, in `Router::asn_db`
+    pub asn: Vec<u32>,
+    /// Matcher of the sniffed domain name
+    pub domain: Option<Arc<DomainMatcher>>,
+    pub outbound: Outbound,
+}
+
+impl Rule {
+    /// Check if `flow` meets all the conditions
+    fn matches(&self, flow: &Flow, geo: &GeoLookup) -> bool {
+        // IPv4 clients of a dual-stack listener come with IPv4-mapped IPv6 addresses
+        let contains = |nets: &[IpNet], addr: &SocketAddr| {
+            nets.is_empty() || nets.iter().any(|n| n.contains(&addr.ip().to_canonical()))
+        };
+
+        self.protocol.is_none_or(|p| p == flow.protocol)
+            && (self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&flow.dst.port())))
+            && contains(&self.dst, &flow.dst)
+            && contains(&self.src, &flow.src)
+            && (self.country.is_empty()
+                || geo
+                    .country()
+                    .is_some_and(|c| self.country.iter().any(|x| *x == c)))
+            && (self.asn.is_empty() || geo.asn().is_some_and(|a| self.asn.contains(&a)))
+            && self
+                .domain
+                .as_ref()
+                .is_none_or(|m| flow.domain.is_some_and(|d| m.matches(d)))
+    }
+}
+
+/// GeoIP information of a destination, looked up once on demand
+struct GeoLookup<'a> {
+    router: &'a Router,
+    ip: IpAddr,
+    country: OnceCell<Option<String>>,
+    asn: OnceCell<Option<u32>>,
+}
+
+impl GeoLookup<'_> {
+    fn country(&self) -> Option<&str> {
+        self.country
+            .get_or_init(|| self.router.country_db.as_ref()?.country(self.ip))
+            .as_deref()
+    }
+
+    fn asn(&self) -> Option<u32> {
+        *self
+            .asn
+            .get_or_init(|| self.router.asn_db.as_ref()?.asn(self.ip))
+    }
+}
+
+/// Ordered routing rules, the first matching rule decides the outbound
+#[derive(Clone, Debug, Default)]
+pub struct Router {
+    pub rules: Vec<Rule>,
+    /// Outbound of the flows matching no rule
+    pub default: Outbound,
+    /// Database for `Rule::country`
+    pub country_db: Option<Arc<GeoIpDatabase>>,
+    /// Database for `Rule::asn`
+    pub asn_db: Option<Arc<GeoIpDatabase>>,
+}
+
+impl Router {
+    /// Outbound of `flow`
+    pub fn route(&self, flow: &Flow) -> &Outbound {
+        let geo = GeoLookup {
+            router: self,
+            ip: flow.dst.ip(),
+            country: OnceCell::new(),
+            asn: OnceCell::new(),
+        };
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(flow, &geo))
+            .map_or(&self.default, |rule| &rule.outbound)
+    }
+
+    /// GeoIP databases in use
+    pub fn geoip_databases(&self) -> impl Iterator<Item = &Arc<GeoIpDatabase>> {
+        self.country_db.iter().chain(self.asn_db.iter())
+    }
+}
+
+#[test]
+fn test_route() {
+    let router = Router {
+        rules: vec![
+            Rule {
+                dst: vec!["10.0.0.0/8".parse().unwrap()],
+                outbound: Outbound::Direct,
+                ..Default::default()
+            },
+            Rule {
+                domain: Some(Arc::new({
+                    let mut builder = domain::DomainMatcherBuilder::new();
+                    builder.add_suffix("example.com");
+                    builder.build().unwrap()
+                })),
+                outbound: Outbound::Reject,
+                ..Default::default()
+            },
+            Rule {
+                src: vec!["192.168.1.0/24".parse().unwrap()],
+                ports: vec![53..=53, 8000..=9000],
+                protocol: Some(Protocol::Udp),
+                outbound: Outbound::Reject,
+                ..Default::default()
+            },
+        ],
+        default: Outbound::Proxy("socks5".to_owned()),
+        ..Default::default()
+    };
+    let flow = |protocol, src: &str, dst: &str| Flow {
+        protocol,
+        src: src.parse().unwrap(),
+        dst: dst.parse().unwrap(),
+        domain: None,
+    };
+
+    let route = |f: Flow| router.route(&f).clone();
+    assert_eq!(
+        route(flow(Protocol::Tcp, "192.168.1.2:1234", "10.1.2.3:80")),
+        Outbound::Direct
+    );
+    assert_eq!(
+        route(flow(Protocol::Udp, "192.168.1.2:1234", "1.1.1.1:8080")),
+        Outbound::Reject
+    );
+    // IPv4-mapped client address
+    assert_eq!(
+        route(flow(
+            Protocol::Udp,
+            "[::ffff:192.168.1.2]:1234",
+            "1.1.1.1:53"
+        )),
+        Outbound::Reject
+    );
+    // sniffed domain
+    let f = flow(Protocol::Tcp, "192.168.2.2:1234", "1.1.1.1:443");
+    for (domain, outbound) in [
+        ("example.com", Outbound::Reject),
+        ("www.example.com", Outbound::Reject),
+        ("badexample.com", Outbound::Proxy("socks5".to_owned())),
+    ] {
+        let f = Flow {
+            domain: Some(domain),
+            ..f
+        };
+        assert_eq!(route(f), outbound);
+    }
+    // protocol, port and source don't match
+    assert_eq!(
+        route(flow(Protocol::Tcp, "192.168.1.2:1234", "1.1.1.1:53")),
+        Outbound::Proxy("socks5".to_owned())
+    );
+    assert_eq!(
+        route(flow(Protocol::Udp, "192.168.1.2:1234", "1.1.1.1:443")),
+        Outbound::Proxy("socks5".to_owned())
+    );
+    assert_eq!(
+        route(flow(Protocol::Udp, "192.168.2.2:1234", "1.1.1.1:53")),
+        Outbound::Proxy("socks5".to_owned())
+    );
+}
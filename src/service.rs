@@ -0,0 +1,831 @@
+//! Running listeners of a `Config`, and reloading them with a new `Config`
+
+use crate::{
+    access_log::AccessLog,
+    admin::{self, AdminListener},
+    admission::Admission,
+    dns::{self, FakeDns, fake_ip::FakeIpPool, forward::DnsForwarder, snoop::DnsSnoop},
+    redir::redir_ext::TcpListenerRedirExt,
+    router::Protocol,
+    session::{CloseReason, Sessions},
+    systemd::ActivatedSockets,
+    tcp_relay,
+    udp_relay::{
+        self, UdpRedirSocket,
+        route::Routed,
+        send::{Direct, Proxy, SendBackOpts},
+    },
+    utils::{
+        config::{
+            AccessLogConfig, AdminAddr, AdminConfig, Config, DnsConfig, ListenerConfig,
+            ListenerMode, RedirType, UpstreamConfig,
+        },
+        net::{AcceptOpts, ConnectOpts},
+    },
+};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use cfg_if::cfg_if;
+use std::{collections::HashMap, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+    time::{self, Instant},
+};
+
+/// Interval of checking if the sessions are finished on shutdown
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the closed sessions may take to stop on shutdown
+const CLOSE_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Settings of a UDP relay that can not be changed without restarting it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct UdpServiceKey {
+    mode: ListenerMode,
+    /// SOCKS5 server in `ListenerMode::Proxy`
+    upstream: Option<UpstreamConfig>,
+    send_back: SendBackOpts,
+    connect_opts: ConnectOpts,
+    /// `IPV6_V6ONLY` of the listening socket
+    ipv6_only: bool,
+    dns_forward: bool,
+    dns_snoop: bool,
+}
+
+/// State shared by the relays, kept across reloads
+pub struct Shared {
+    /// Fake IPs of the DNS server, kept unless the ranges are changed
+    pub fake_ip: ArcSwapOption<FakeIpPool>,
+    /// Domain names learned from the DNS responses, for the listeners enabling `dns_snoop`
+    pub dns_snoop: Arc<DnsSnoop>,
+    /// Active TCP sessions and UDP associations
+    pub sessions: Arc<Sessions>,
+    /// Limit of the concurrent TCP sessions and UDP associations
+    pub admission: Arc<Admission>,
+}
+
+impl Default for Shared {
+    fn default() -> Self {
+        Shared {
+            fake_ip: ArcSwapOption::empty(),
+            dns_snoop: Arc::new(DnsSnoop::new()),
+            sessions: Arc::new(Sessions::new()),
+            admission: Arc::new(Admission::default()),
+        }
+    }
+}
+
+/// A running relay task, see `tcp_relay::run`, `udp_relay::run`, `dns::run` and `admin::run`
+struct RelayHandle {
+    shutdown: watch::Sender<bool>,
+}
+
+impl RelayHandle {
+    /// Stop accepting, and wait until the listening address is released
+    async fn stop(self) {
+        let _ = self.shutdown.send(true);
+        self.shutdown.closed().await;
+    }
+}
+
+struct RunningListener {
+    tcp_redir: RedirType,
+    /// Options the TCP listener is bound with
+    accept_opts: AcceptOpts,
+    tcp: RelayHandle,
+    udp: Option<(UdpServiceKey, RelayHandle)>,
+}
+
+/// All the listeners of a `Config`
+pub struct Service {
+    config: Arc<ArcSwap<Config>>,
+    listeners: HashMap<SocketAddr, RunningListener>,
+    shared: Arc<Shared>,
+    dns: Option<(DnsConfig, RelayHandle)>,
+    admin: Option<(AdminConfig, RelayHandle)>,
+    /// Config of the access log in use
+    access_log: Option<AccessLogConfig>,
+    /// Sockets passed by systemd or bound before dropping root, used instead of binding the
+    /// listeners and the DNS server
+    activated: ActivatedSockets,
+}
+
+impl Service {
+    /// Bind and run all the listeners in `config`, using the sockets in `activated` if any
+    pub async fn start(config: Config, activated: ActivatedSockets) -> io::Result<Service> {
+        let mut service = Service {
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            listeners: HashMap::new(),
+            shared: Arc::new(Shared {
+                admission: Arc::new(Admission::from_fd_limit()?),
+                ..Shared::default()
+            }),
+            dns: None,
+            admin: None,
+            access_log: None,
+            activated,
+        };
+
+        let config = service.config.load_full();
+        if let Some(ref access_log) = config.access_log {
+            let log = AccessLog::open(access_log)
+                .inspect_err(|e| eprintln!("open access log {} error: {e}", access_log.target))?;
+            service.shared.sessions.set_access_log(Some(Arc::new(log)));
+            service.access_log = Some(access_log.clone());
+        }
+        if let Some(ref dns) = config.dns {
+            let handle = service
+                .start_dns(dns)
+                .await
+                .inspect_err(|e| eprintln!("bind DNS address {} error: {e}", dns.addr))?;
+            service.dns = Some((dns.clone(), handle));
+        }
+        if let Some(ref admin) = config.admin {
+            let handle = service
+                .start_admin(admin)
+                .await
+                .inspect_err(|e| eprintln!("bind admin address {} error: {e}", admin.addr))?;
+            service.admin = Some((admin.clone(), handle));
+        }
+        for listener in &config.listeners {
+            let tcp = service
+                .start_tcp(&config, listener)
+                .await
+                .inspect_err(|e| eprintln!("bind listen address {} error: {e}", listener.addr))?;
+            let udp = service.start_udp(&config, listener)?;
+            service.listeners.insert(
+                listener.addr,
+                RunningListener {
+                    tcp_redir: listener.tcp_redir,
+                    accept_opts: config.accept_opts.clone(),
+                    tcp,
+                    udp,
+                },
+            );
+        }
+        let dns_addr = config.dns.as_ref().map(|dns| dns.addr);
+        let admin_addr = match config.admin.as_ref().map(|admin| &admin.addr) {
+            Some(AdminAddr::Tcp(addr)) => Some(*addr),
+            _ => None,
+        };
+        for addr in service.activated.addrs() {
+            if !service.listeners.contains_key(&addr)
+                && dns_addr != Some(addr)
+                && admin_addr != Some(addr)
+            {
+                log::warn!("socket {} passed by systemd is not a listener", addr);
+            }
+        }
+
+        Ok(service)
+    }
+
+    /// Current config
+    pub fn config(&self) -> Arc<Config> {
+        self.config.load_full()
+    }
+
+    /// Reload the GeoIP databases of the current config which are modified
+    pub fn reload_geoip(&self) {
+        let config = self.config.load();
+        for db in config.router.geoip_databases() {
+            match db.reload_if_modified() {
+                Ok(true) => log::info!("GeoIP database {} is reloaded", db.path().display()),
+                Ok(false) => {}
+                Err(e) => log::error!(
+                    "reload GeoIP database {} error: {}, keep the current one",
+                    db.path().display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Apply a new config
+    ///
+    /// Only the relays whose socket settings changed are rebound. Upstreams are swapped at once
+    /// for the new connections, existing TCP connections and UDP associations keep their old settings.
+    /// If a listener, the DNS server or the admin API can't be bound, the previous config is restored
+    /// and the error is returned.
+    pub async fn reload(&mut self, config: Config) -> io::Result<()> {
+        let previous = self.config.load_full();
+        let Err(e) = self.apply(Arc::new(config)).await else {
+            return Ok(());
+        };
+        log::warn!("restoring the previous config");
+        if let Err(e) = self.apply(previous).await {
+            log::error!("restore the previous config error: {}", e);
+        }
+        Err(e)
+    }
+
+    /// Rebind the relays changed by `config`, stops at the first socket which can't be bound
+    async fn apply(&mut self, config: Arc<Config>) -> io::Result<()> {
+        // 1. stop the changed relays, so their addresses can be bound again
+        let mut stopped = Vec::new();
+        for (addr, running) in self.listeners.iter_mut() {
+            let listener = config.listeners.iter().find(|l| l.addr == *addr);
+            let tcp_changed = listener.is_none_or(|l| l.tcp_redir != running.tcp_redir)
+                || !same_listen_opts(&config.accept_opts, &running.accept_opts);
+            if tcp_changed {
+                stopped.push(*addr);
+                continue;
+            }
+
+            let udp_key = listener.and_then(|l| udp_service_key(&config, l));
+            if running.udp.as_ref().map(|(key, _)| key) != udp_key.as_ref()
+                && let Some((_, udp)) = running.udp.take()
+            {
+                log::info!("stopping UDP relay on {}", addr);
+                udp.stop().await;
+            }
+        }
+        for addr in stopped {
+            if let Some(running) = self.listeners.remove(&addr) {
+                log::info!("stopping listener {}", addr);
+                running.tcp.stop().await;
+                if let Some((_, udp)) = running.udp {
+                    udp.stop().await;
+                }
+            }
+        }
+
+        let dns_changed = self.dns.as_ref().map(|(dns, _)| dns) != config.dns.as_ref();
+        let old_dns = match self.dns.take() {
+            Some((dns, handle)) if dns_changed => {
+                log::info!("stopping DNS server on {}", dns.addr);
+                handle.stop().await;
+                Some(dns)
+            }
+            running => {
+                self.dns = running;
+                None
+            }
+        };
+
+        let admin_changed = self.admin.as_ref().map(|(admin, _)| admin) != config.admin.as_ref();
+        if admin_changed && let Some((admin, handle)) = self.admin.take() {
+            log::info!("stopping admin API on {}", admin.addr);
+            handle.stop().await;
+        }
+
+        // 2. new connections are relayed with the new upstreams from now on
+        self.config.store(config.clone());
+
+        if self.access_log != config.access_log {
+            self.reload_access_log(config.access_log.as_ref());
+        }
+
+        if dns_changed {
+            let same_ranges = |a: &DnsConfig, b: &DnsConfig| {
+                a.fake_ip_range == b.fake_ip_range && a.fake_ip6_range == b.fake_ip6_range
+            };
+            match config.dns {
+                Some(ref dns) => {
+                    if !old_dns.is_some_and(|old| same_ranges(&old, dns)) {
+                        self.shared.fake_ip.store(None);
+                    }
+                    let handle = self.start_dns(dns).await.inspect_err(|e| {
+                        log::error!("bind DNS address {} error: {}", dns.addr, e)
+                    })?;
+                    self.dns = Some((dns.clone(), handle));
+                }
+                None => self.shared.fake_ip.store(None),
+            }
+        }
+
+        if admin_changed && let Some(ref admin) = config.admin {
+            let handle = self
+                .start_admin(admin)
+                .await
+                .inspect_err(|e| log::error!("bind admin address {} error: {}", admin.addr, e))?;
+            self.admin = Some((admin.clone(), handle));
+        }
+
+        // 3. start the new relays
+        for listener in &config.listeners {
+            match self.listeners.get(&listener.addr) {
+                Some(running) => {
+                    if running.udp.is_some() {
+                        continue;
+                    }
+                    let udp = self
+                        .start_udp(&config, listener)
+                        .inspect_err(|e| log::error!("bind UDP {} error: {}", listener.addr, e))?;
+                    if let Some(running) = self.listeners.get_mut(&listener.addr) {
+                        running.udp = udp;
+                    }
+                }
+                None => {
+                    let tcp = self.start_tcp(&config, listener).await.inspect_err(|e| {
+                        log::error!("bind listen address {} error: {}", listener.addr, e)
+                    })?;
+                    let (udp, result) = match self.start_udp(&config, listener) {
+                        Ok(udp) => (udp, Ok(())),
+                        Err(e) => {
+                            log::error!("bind UDP {} error: {}", listener.addr, e);
+                            (None, Err(e))
+                        }
+                    };
+                    // the TCP relay is tracked even if UDP fails, so it can be stopped on restoring
+                    self.listeners.insert(
+                        listener.addr,
+                        RunningListener {
+                            tcp_redir: listener.tcp_redir,
+                            accept_opts: config.accept_opts.clone(),
+                            tcp,
+                            udp,
+                        },
+                    );
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop all the relays, and wait for the active sessions to finish
+    ///
+    /// The UDP associations are closed at once, as they only finish by expiring. The TCP sessions
+    /// still running after `timeout`, or once `force` resolves, are closed. Returns the number of
+    /// the TCP sessions closed this way.
+    pub async fn shutdown<F>(mut self, timeout: Duration, force: F) -> usize
+    where
+        F: Future<Output = ()>,
+    {
+        for (addr, running) in self.listeners.drain() {
+            log::info!("stopping listener {}", addr);
+            running.tcp.stop().await;
+            if let Some((_, udp)) = running.udp {
+                udp.stop().await;
+            }
+        }
+        if let Some((dns, handle)) = self.dns.take() {
+            log::info!("stopping DNS server on {}", dns.addr);
+            handle.stop().await;
+        }
+
+        let sessions = self.shared.sessions.clone();
+        sessions.close_all(Some(Protocol::Udp), CloseReason::Shutdown);
+        if !sessions.is_empty() {
+            log::info!(
+                "waiting for {} sessions to finish, at most {:?}",
+                sessions.len(),
+                timeout
+            );
+        }
+        tokio::select! {
+            _ = wait_sessions(&sessions, Instant::now() + timeout) => {}
+            _ = force => log::info!("closing the sessions at once"),
+        }
+        let closed = match sessions.is_empty() {
+            true => 0,
+            false => {
+                let closed = sessions.close_all(None, CloseReason::Shutdown);
+                log::warn!("closing {} unfinished sessions", closed);
+                wait_sessions(&sessions, Instant::now() + CLOSE_WAIT_TIMEOUT).await;
+                closed
+            }
+        };
+
+        // the admin API is kept for watching the sessions draining
+        if let Some((admin, handle)) = self.admin.take() {
+            log::info!("stopping admin API on {}", admin.addr);
+            handle.stop().await;
+        }
+        // write the pending access records
+        sessions.set_access_log(None);
+        closed
+    }
+
+    async fn start_tcp(
+        &self,
+        config: &Config,
+        listener: &ListenerConfig,
+    ) -> io::Result<RelayHandle> {
+        let tcp_listener = match self.activated.tcp(listener.addr) {
+            Some(socket) => {
+                cfg_if! {
+                    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                        tcp_relay::linux::listener_from_std(
+                            listener.tcp_redir,
+                            socket?,
+                            &config.accept_opts,
+                        )?
+                    } else {
+                        drop(socket);
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "socket activation is only supported on Linux",
+                        ));
+                    }
+                }
+            }
+            None => {
+                TcpListener::bind_redir(
+                    listener.tcp_redir,
+                    listener.addr,
+                    config.accept_opts.clone(),
+                )
+                .await?
+            }
+        };
+        log::info!(
+            "rustsocks is listening on {} ({:?} mode)",
+            listener.addr,
+            listener.mode
+        );
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        tokio::spawn(tcp_relay::run(
+            tcp_listener,
+            listener.addr,
+            listener.tcp_redir,
+            self.config.clone(),
+            self.shared.clone(),
+            shutdown_rx,
+        ));
+        Ok(RelayHandle { shutdown })
+    }
+
+    /// Start the DNS server, with the current fake IPs if any
+    async fn start_dns(&self, dns: &DnsConfig) -> io::Result<RelayHandle> {
+        let udp_socket = match self.activated.udp(dns.addr) {
+            Some(socket) => {
+                let socket = socket?;
+                socket.set_nonblocking(true)?;
+                UdpSocket::from_std(socket)?
+            }
+            None => UdpSocket::bind(dns.addr).await?,
+        };
+        let tcp_listener = match self.activated.tcp(dns.addr) {
+            Some(listener) => {
+                let listener = listener?;
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(dns.addr).await?,
+        };
+        let pool = match self.shared.fake_ip.load_full() {
+            Some(pool) => pool,
+            None => Arc::new(FakeIpPool::new(dns.fake_ip_range, dns.fake_ip6_range)),
+        };
+        self.shared.fake_ip.store(Some(pool.clone()));
+        log::info!(
+            "DNS server is listening on {}, fake IPs in {}",
+            dns.addr,
+            dns.fake_ip_range
+        );
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let fake_dns = FakeDns { pool, ttl: dns.ttl };
+        tokio::spawn(dns::run(
+            udp_socket,
+            tcp_listener,
+            Arc::new(fake_dns),
+            shutdown_rx,
+        ));
+        Ok(RelayHandle { shutdown })
+    }
+
+    /// Switch to the access log of `access_log`, the current one is kept if it can't be opened
+    fn reload_access_log(&mut self, access_log: Option<&AccessLogConfig>) {
+        let log = match access_log {
+            Some(access_log) => match AccessLog::open(access_log) {
+                Ok(log) => {
+                    log::info!("access log is written to {}", access_log.target);
+                    Some(Arc::new(log))
+                }
+                Err(e) => {
+                    log::error!("open access log {} error: {}", access_log.target, e);
+                    return;
+                }
+            },
+            None => {
+                log::info!("access log is disabled");
+                None
+            }
+        };
+        self.shared.sessions.set_access_log(log);
+        self.access_log = access_log.cloned();
+    }
+
+    /// Start the admin API
+    async fn start_admin(&self, admin: &AdminConfig) -> io::Result<RelayHandle> {
+        let listener = match admin.addr {
+            AdminAddr::Tcp(addr) => match self.activated.tcp(addr) {
+                Some(listener) => {
+                    let listener = listener?;
+                    listener.set_nonblocking(true)?;
+                    AdminListener::Tcp(TcpListener::from_std(listener)?)
+                }
+                None => AdminListener::bind(&admin.addr).await?,
+            },
+            AdminAddr::Unix(_) => AdminListener::bind(&admin.addr).await?,
+        };
+        log::info!("admin API is listening on {}", admin.addr);
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        tokio::spawn(admin::run(
+            listener,
+            self.shared.sessions.clone(),
+            shutdown_rx,
+        ));
+        Ok(RelayHandle { shutdown })
+    }
+
+    fn start_udp(
+        &self,
+        config: &Config,
+        listener: &ListenerConfig,
+    ) -> io::Result<Option<(UdpServiceKey, RelayHandle)>> {
+        let Some(key) = udp_service_key(config, listener) else {
+            return Ok(None);
+        };
+        let udp_socket = match self.activated.udp(listener.addr) {
+            Some(socket) => {
+                cfg_if! {
+                    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                        UdpRedirSocket::from_std(listener.udp_redir, socket?)?
+                    } else {
+                        drop(socket);
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            "socket activation is only supported on Linux",
+                        ));
+                    }
+                }
+            }
+            None => UdpRedirSocket::listen(
+                listener.udp_redir,
+                listener.addr,
+                &config.accept_opts,
+                &key.send_back.redir_opts,
+            )?,
+        };
+
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let send_back = key.send_back.clone();
+        let dns_snoop = key.dns_snoop.then(|| self.shared.dns_snoop.clone());
+        let dns_forwarder = key.dns_forward.then(|| {
+            let forwarder = DnsForwarder::new(
+                self.config.clone(),
+                listener.addr,
+                send_back.clone(),
+                dns_snoop,
+            );
+            Arc::new(forwarder)
+        });
+        match (key.mode, &key.upstream) {
+            (ListenerMode::Proxy, Some(upstream)) => {
+                log::info!(
+                    "UDP on {} is using SOCKS5 proxy at {}",
+                    listener.addr,
+                    upstream.addr
+                );
+                let auth = upstream.auth.clone().map(Arc::new);
+                tokio::spawn(udp_relay::run(
+                    udp_socket,
+                    Proxy(upstream.addr, auth),
+                    self.shared.clone(),
+                    dns_forwarder,
+                    key.dns_snoop,
+                    send_back,
+                    key.connect_opts.clone(),
+                    shutdown_rx,
+                ));
+            }
+            // in proxy mode without a SOCKS5 upstream, only the DNS queries are forwarded
+            (ListenerMode::Rule | ListenerMode::Proxy, _) => {
+                let route = Routed {
+                    config: self.config.clone(),
+                    listen_addr: listener.addr,
+                };
+                tokio::spawn(udp_relay::run(
+                    udp_socket,
+                    route,
+                    self.shared.clone(),
+                    dns_forwarder,
+                    key.dns_snoop,
+                    send_back,
+                    key.connect_opts.clone(),
+                    shutdown_rx,
+                ));
+            }
+            _ => {
+                tokio::spawn(udp_relay::run(
+                    udp_socket,
+                    Direct,
+                    self.shared.clone(),
+                    dns_forwarder,
+                    key.dns_snoop,
+                    send_back,
+                    key.connect_opts.clone(),
+                    shutdown_rx,
+                ));
+            }
+        }
+        Ok(Some((key, RelayHandle { shutdown })))
+    }
+}
+
+/// If UDP is served on `listener`
+pub fn serves_udp(config: &Config, listener: &ListenerConfig) -> bool {
+    udp_service_key(config, listener).is_some()
+}
+
+/// Whether the options applied to the listening sockets are the same, the other options are
+/// read from the current config by the relays
+fn same_listen_opts(a: &AcceptOpts, b: &AcceptOpts) -> bool {
+    a.tcp.fastopen == b.tcp.fastopen && a.ipv6_only == b.ipv6_only
+}
+
+/// Settings of the UDP relay on `listener`, `None` if UDP is not served
+fn udp_service_key(config: &Config, listener: &ListenerConfig) -> Option<UdpServiceKey> {
+    let upstream = match listener.mode {
+        ListenerMode::Proxy => match listener.udp_upstream {
+            Some(ref name) => Some(config.upstreams.get(name)?.clone()),
+            None if listener.dns_forward => None,
+            None => return None,
+        },
+        ListenerMode::Direct | ListenerMode::Rule => None,
+    };
+    let send_back = SendBackOpts {
+        ty: config.udp_send_back,
+        redir_ty: listener.udp_redir,
+        redir_opts: config.redir_opts.clone(),
+        mtu: config.accept_opts.udp.mtu,
+    };
+    Some(UdpServiceKey {
+        mode: listener.mode,
+        upstream,
+        send_back,
+        connect_opts: config.connect_opts(),
+        ipv6_only: config.accept_opts.ipv6_only,
+        dns_forward: listener.dns_forward,
+        dns_snoop: listener.dns_snoop,
+    })
+}
+
+/// Wait until all `sessions` are finished, or `deadline`
+async fn wait_sessions(sessions: &Sessions, deadline: Instant) {
+    while !sessions.is_empty() && Instant::now() < deadline {
+        time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+}
+
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_reload() {
+    use crate::utils::config::ConfigType;
+
+    let load = |s: &str| Config::load_from_str(s, ConfigType::Toml).unwrap();
+    let mut service = Service::start(
+        load(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23451"
+            mode = "direct"
+            tcp_redir = "redirect"
+            "#,
+        ),
+        ActivatedSockets::default(),
+    )
+    .await
+    .unwrap();
+
+    // keeps 23451, adds 23452
+    service
+        .reload(load(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23451"
+            mode = "direct"
+            tcp_redir = "redirect"
+
+            [[listeners]]
+            address = "127.0.0.1:23452"
+            mode = "direct"
+            tcp_redir = "redirect"
+            "#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(service.listeners.len(), 2);
+    assert!(service.listeners.values().all(|l| l.udp.is_some()));
+    assert!(
+        tokio::net::TcpStream::connect("127.0.0.1:23452")
+            .await
+            .is_ok()
+    );
+
+    // removes 23451, and rebinds 23452 with another redir type
+    service
+        .reload(load(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23452"
+            mode = "direct"
+            tcp_redir = "tproxy"
+            "#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(service.listeners.len(), 1);
+    assert_eq!(
+        service.listeners[&"127.0.0.1:23452".parse().unwrap()].tcp_redir,
+        RedirType::TProxy
+    );
+    assert!(
+        tokio::net::TcpStream::connect("127.0.0.1:23451")
+            .await
+            .is_err()
+    );
+
+    // 23454 is taken, so 23452 is restored with the previous options
+    let _taken = TcpListener::bind("127.0.0.1:23454").await.unwrap();
+    let result = service
+        .reload(load(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23452"
+            mode = "direct"
+            tcp_redir = "tproxy"
+
+            [[listeners]]
+            address = "127.0.0.1:23454"
+            mode = "direct"
+
+            [tcp]
+            fastopen = false
+            "#,
+        ))
+        .await;
+    assert!(result.is_err());
+    assert_eq!(service.listeners.len(), 1);
+    let running = &service.listeners[&"127.0.0.1:23452".parse().unwrap()];
+    assert!(running.accept_opts.tcp.fastopen);
+    assert!(service.config().accept_opts.tcp.fastopen);
+    assert!(
+        tokio::net::TcpStream::connect("127.0.0.1:23452")
+            .await
+            .is_ok()
+    );
+
+    // the DNS server can't be bound either, so the reload is rolled back
+    let result = service
+        .reload(load(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:23452"
+            mode = "direct"
+            tcp_redir = "tproxy"
+
+            [dns]
+            address = "127.0.0.1:23454"
+            "#,
+        ))
+        .await;
+    assert!(result.is_err());
+    assert!(service.dns.is_none());
+    assert!(service.config().dns.is_none());
+}
+
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_shutdown() {
+    use crate::{session::SessionMeta, utils::config::ConfigType};
+
+    let config = Config::load_from_str(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:23453"
+        mode = "direct"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+    let service = Service::start(config, ActivatedSockets::default())
+        .await
+        .unwrap();
+    let sessions = service.shared.sessions.clone();
+
+    // relays of a TCP session and a UDP association, running until closed
+    for protocol in [Protocol::Tcp, Protocol::Udp] {
+        let session = sessions.register(SessionMeta {
+            protocol,
+            listener: "127.0.0.1:23453".parse().unwrap(),
+            client: "192.168.1.2:40000".parse().unwrap(),
+            destination: "1.2.3.4:443".parse().unwrap(),
+            domain: None,
+            outbound: "direct".to_owned(),
+            upstream: None,
+        });
+        tokio::spawn(async move { session.closed().await });
+    }
+
+    let closed = service
+        .shutdown(Duration::from_millis(300), std::future::pending())
+        .await;
+    assert_eq!(closed, 1);
+    assert!(sessions.is_empty());
+}
@@ -0,0 +1,393 @@
+//! Registry of the active TCP sessions and UDP associations, which can be listed and closed by
+//! the admin API (see `crate::admin`)
+
+use crate::{
+    access_log::AccessLog, admission::AdmissionPermit, metrics::METRICS, router::Protocol,
+};
+use arc_swap::ArcSwapOption;
+use pin_project::pin_project;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::SocketAddr,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+/// What a session relays, fixed when it is registered
+#[derive(Clone, Debug)]
+pub struct SessionMeta {
+    pub protocol: Protocol,
+    /// Address of the listener the flow is redirected to
+    pub listener: SocketAddr,
+    pub client: SocketAddr,
+    /// Original destination. A UDP association may relay to other destinations too, this is
+    /// the one it was created for
+    pub destination: SocketAddr,
+    /// Domain name of the destination, from fake IP, sniffing or DNS snooping
+    pub domain: Option<String>,
+    /// `direct`, the upstream name, or `socks5 <address>` for the UDP associations
+    pub outbound: String,
+    /// Protocol and address of the upstream proxy, e.g. `socks5 1.2.3.4:1080`
+    pub upstream: Option<String>,
+}
+
+/// Why a session is finished
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Both sides are closed normally
+    Finished,
+    /// Closed by the admin API
+    Admin,
+    /// Connecting to the destination or the upstream failed
+    ConnectFailed,
+    /// Connecting to the destination or the upstream timed out
+    ConnectTimeout,
+    /// The handshake with the upstream timed out
+    HandshakeTimeout,
+    /// No data in either direction for too long
+    IdleTimeout,
+    /// One side sent FIN, and the other side didn't finish in time
+    HalfCloseTimeout,
+    /// The relay failed after connected
+    Error,
+    /// The UDP association is idle for too long, or its listener is stopped
+    Expired,
+    /// Closed as rustsocks is stopping
+    Shutdown,
+}
+
+impl CloseReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Finished => "finished",
+            CloseReason::Admin => "admin",
+            CloseReason::ConnectFailed => "connect_failed",
+            CloseReason::ConnectTimeout => "connect_timeout",
+            CloseReason::HandshakeTimeout => "handshake_timeout",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::HalfCloseTimeout => "half_close_timeout",
+            CloseReason::Error => "error",
+            CloseReason::Expired => "expired",
+            CloseReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An active TCP session or UDP association
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub meta: SessionMeta,
+    started: Instant,
+    started_at: SystemTime,
+    /// Bytes from the client to the destination
+    uploaded: AtomicU64,
+    /// Bytes from the destination to the client
+    downloaded: AtomicU64,
+    close: Notify,
+    /// The first reason set, and the error message
+    close_reason: Mutex<Option<(CloseReason, Option<String>)>>,
+}
+
+impl Session {
+    pub fn add_uploaded(&self, n: usize) {
+        self.uploaded.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, n: usize) {
+        self.downloaded.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Ask the relay of the session to stop for `reason`, see `closed()`
+    pub fn close(&self, reason: CloseReason) {
+        self.set_close_reason(reason, None);
+        self.close.notify_one();
+    }
+
+    /// Resolves once `close()` is called, should be awaited by the relay of the session only
+    pub async fn closed(&self) {
+        self.close.notified().await
+    }
+
+    /// Record why the session is finished, only the first reason is kept
+    pub fn set_close_reason(&self, reason: CloseReason, error: Option<String>) {
+        self.close_reason
+            .lock()
+            .unwrap()
+            .get_or_insert((reason, error));
+    }
+
+    /// Why the session is finished, `CloseReason::Finished` if no reason is set
+    pub fn close_reason(&self) -> (CloseReason, Option<String>) {
+        let reason = self.close_reason.lock().unwrap();
+        reason.clone().unwrap_or((CloseReason::Finished, None))
+    }
+
+    /// Wall-clock time the session is registered
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+
+    /// Current state of the session
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id,
+            protocol: match self.meta.protocol {
+                Protocol::Tcp => "tcp",
+                Protocol::Udp => "udp",
+            },
+            listener: self.meta.listener,
+            client: self.meta.client,
+            destination: self.meta.destination,
+            domain: self.meta.domain.clone(),
+            outbound: self.meta.outbound.clone(),
+            upstream: self.meta.upstream.clone(),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            age_secs: self.started.elapsed().as_secs(),
+        }
+    }
+}
+
+/// Snapshot of a session, as returned by the admin API
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub protocol: &'static str,
+    pub listener: SocketAddr,
+    pub client: SocketAddr,
+    pub destination: SocketAddr,
+    pub domain: Option<String>,
+    pub outbound: String,
+    pub upstream: Option<String>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub age_secs: u64,
+}
+
+/// All the active sessions, kept across config reloads
+#[derive(Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: Mutex<BTreeMap<u64, Arc<Session>>>,
+    /// Where the finished sessions are recorded
+    access_log: ArcSwapOption<AccessLog>,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    /// Add a session, which is removed when the returned guard is dropped
+    pub fn register(self: &Arc<Self>, meta: SessionMeta) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(Session {
+            id,
+            meta,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            close: Notify::new(),
+            close_reason: Mutex::new(None),
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        SessionGuard {
+            sessions: self.clone(),
+            session,
+            permit: None,
+        }
+    }
+
+    /// Snapshots of the active sessions, ordered by their IDs
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().map(|s| s.info()).collect()
+    }
+
+    /// Close session `id`, returns `false` if there is no such session
+    pub fn close(&self, id: u64, reason: CloseReason) -> bool {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.close(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close all the sessions of `protocol`, or all the sessions if it is `None`, returns the
+    /// number of them
+    pub fn close_all(&self, protocol: Option<Protocol>, reason: CloseReason) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .values()
+            .filter(|s| protocol.is_none_or(|p| s.meta.protocol == p))
+            .inspect(|s| s.close(reason))
+            .count()
+    }
+
+    /// Number of the active sessions
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Record the sessions finished from now on in `access_log`, or stop recording
+    pub fn set_access_log(&self, access_log: Option<Arc<AccessLog>>) {
+        self.access_log.store(access_log);
+    }
+}
+
+/// A registered session, removed from `Sessions` and recorded in the access log on drop
+pub struct SessionGuard {
+    sessions: Arc<Sessions>,
+    session: Arc<Session>,
+    /// Released after the session is removed
+    permit: Option<AdmissionPermit>,
+}
+
+impl SessionGuard {
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+
+    /// Hold the admission `permit` until the session is finished
+    pub fn with_permit(mut self, permit: AdmissionPermit) -> SessionGuard {
+        self.permit = Some(permit);
+        self
+    }
+}
+
+impl Deref for SessionGuard {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        METRICS.add_finished_session(&self.session);
+        if let Some(ref access_log) = *self.sessions.access_log.load() {
+            access_log.record(&self.session);
+        }
+        self.sessions
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session.id);
+    }
+}
+
+/// Client stream of a TCP session, counting the bytes read as uploaded and the bytes written as
+/// downloaded
+#[pin_project]
+pub struct CountingStream<S> {
+    #[pin]
+    stream: S,
+    session: Arc<Session>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(stream: S, session: Arc<Session>) -> CountingStream<S> {
+        CountingStream { stream, session }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let result = this.stream.poll_read(cx, buf);
+        this.session.add_uploaded(buf.filled().len() - filled);
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let result = this.stream.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.session.add_downloaded(n);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_sessions() {
+    let sessions = Arc::new(Sessions::new());
+    let meta = SessionMeta {
+        protocol: Protocol::Tcp,
+        listener: "127.0.0.1:12345".parse().unwrap(),
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "1.2.3.4:443".parse().unwrap(),
+        domain: Some("www.example.com".to_owned()),
+        outbound: "direct".to_owned(),
+        upstream: None,
+    };
+    let a = sessions.register(meta.clone());
+    let b = sessions.register(meta);
+    a.add_uploaded(10);
+    a.add_downloaded(20);
+
+    let list = sessions.list();
+    assert_eq!(list.len(), 2);
+    assert_eq!((list[0].id, list[1].id), (a.id, b.id));
+    assert_eq!((list[0].uploaded, list[0].downloaded), (10, 20));
+
+    // close() before closed() is awaited is not lost
+    assert!(sessions.close(b.id, CloseReason::Admin));
+    b.closed().await;
+    assert!(!sessions.close(0, CloseReason::Admin));
+    drop(b);
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(
+        sessions.close_all(Some(Protocol::Udp), CloseReason::Shutdown),
+        0
+    );
+    assert_eq!(sessions.close_all(None, CloseReason::Shutdown), 1);
+    a.closed().await;
+}
@@ -0,0 +1,173 @@
+//! systemd integration: socket activation (`sd_listen_fds(3)`) and readiness notification
+//! (`sd_notify(3)`)
+//!
+//! Both do nothing if rustsocks is not started by systemd. The activated sockets, and the ones
+//! bound before dropping root, are kept open across config reloads, so a listener restarted on
+//! the same address keeps its pending packets and connections.
+
+use socket2::{SockRef, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, TcpListener, UdpSocket},
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::net::UnixDatagram,
+    },
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+pub use sd_notify::NotifyState;
+
+/// Set once the sockets passed by systemd are owned by an `ActivatedSockets`
+static LISTEN_FDS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sockets opened before the service starts, by their local addresses
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    /// Socket and where it comes from, e.g. its name in `LISTEN_FDNAMES`
+    tcp: HashMap<SocketAddr, (OwnedFd, String)>,
+    udp: HashMap<SocketAddr, (OwnedFd, String)>,
+}
+
+impl ActivatedSockets {
+    /// The sockets in `LISTEN_FDS` of this process, empty if there is none or they are taken
+    /// already
+    pub fn from_env() -> io::Result<ActivatedSockets> {
+        if LISTEN_FDS_TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(ActivatedSockets::default());
+        }
+        // the environment is not modified, as other threads may be reading it
+        let fds = sd_notify::listen_fds_with_names(false)?;
+        // SAFETY: the fds are passed to this process, and only taken here once
+        let fds = fds.map(|(fd, name)| (unsafe { OwnedFd::from_raw_fd(fd) }, name));
+        ActivatedSockets::from_fds(fds)
+    }
+
+    fn from_fds<I>(fds: I) -> io::Result<ActivatedSockets>
+    where
+        I: IntoIterator<Item = (OwnedFd, String)>,
+    {
+        let mut sockets = ActivatedSockets::default();
+        for (fd, name) in fds {
+            sockets.insert(fd, format!("{name} passed by systemd"))?;
+        }
+        Ok(sockets)
+    }
+
+    /// Add a bound TCP or UDP socket, `source` tells where it comes from in the logs
+    pub fn insert(&mut self, fd: OwnedFd, source: String) -> io::Result<()> {
+        let socket = SockRef::from(&fd);
+        let Some(addr) = socket.local_addr()?.as_socket() else {
+            return Err(invalid_socket(&source, "is not an IP socket"));
+        };
+        let sockets = match socket.r#type()? {
+            Type::STREAM => &mut self.tcp,
+            Type::DGRAM => &mut self.udp,
+            _ => return Err(invalid_socket(&source, "is neither TCP nor UDP")),
+        };
+        if let Some((_, source)) = sockets.insert(addr, (fd, source)) {
+            return Err(invalid_socket(&source, "has a duplicate address"));
+        }
+        Ok(())
+    }
+
+    /// A duplicate of the TCP socket listening on `addr`
+    pub fn tcp(&self, addr: SocketAddr) -> Option<io::Result<TcpListener>> {
+        let (fd, source) = self.tcp.get(&addr)?;
+        log::info!("TCP {} is using socket {}", addr, source);
+        Some(fd.try_clone().map(TcpListener::from))
+    }
+
+    /// A duplicate of the UDP socket bound to `addr`
+    pub fn udp(&self, addr: SocketAddr) -> Option<io::Result<UdpSocket>> {
+        let (fd, source) = self.udp.get(&addr)?;
+        log::info!("UDP {} is using socket {}", addr, source);
+        Some(fd.try_clone().map(UdpSocket::from))
+    }
+
+    /// If there is a TCP socket listening on `addr`
+    pub fn has_tcp(&self, addr: SocketAddr) -> bool {
+        self.tcp.contains_key(&addr)
+    }
+
+    /// If there is a UDP socket bound to `addr`
+    pub fn has_udp(&self, addr: SocketAddr) -> bool {
+        self.udp.contains_key(&addr)
+    }
+
+    /// Addresses of all the sockets
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.tcp.keys().chain(self.udp.keys()).copied()
+    }
+}
+
+fn invalid_socket(source: &str, reason: &str) -> io::Error {
+    let message = format!("socket {source} {reason}");
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Send `states` to the service manager, errors are only logged
+pub fn notify(states: &[NotifyState]) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = notify_socket(Path::new(&path), states) {
+        log::warn!("notify systemd error: {}", e);
+    }
+}
+
+/// Send `states` to the notification socket at `path`
+fn notify_socket(path: &Path, states: &[NotifyState]) -> io::Result<()> {
+    let message = states.iter().map(|s| format!("{s}\n")).collect::<String>();
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    socket.send(message.as_bytes())?;
+    Ok(())
+}
+
+/// Interval of sending `WATCHDOG=1`, half of `WatchdogSec=`. `None` if the watchdog is disabled
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec) / 2)
+}
+
+#[test]
+fn test_activated_sockets() {
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (tcp_addr, udp_addr) = (tcp.local_addr().unwrap(), udp.local_addr().unwrap());
+    let sockets = ActivatedSockets::from_fds([
+        (OwnedFd::from(tcp), "rustsocks-tcp".to_owned()),
+        (OwnedFd::from(udp), "rustsocks-udp".to_owned()),
+    ])
+    .unwrap();
+
+    let tcp = sockets.tcp(tcp_addr).unwrap().unwrap();
+    assert_eq!(tcp.local_addr().unwrap(), tcp_addr);
+    // a duplicate every time
+    assert!(sockets.tcp(tcp_addr).unwrap().is_ok());
+    assert!(sockets.tcp(udp_addr).is_none());
+    let udp = sockets.udp(udp_addr).unwrap().unwrap();
+    assert_eq!(udp.local_addr().unwrap(), udp_addr);
+}
+
+#[test]
+fn test_notify() {
+    let path = std::env::temp_dir().join(format!("rustsocks-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+
+    notify_socket(
+        &path,
+        &[NotifyState::Ready, NotifyState::Status("listening")],
+    )
+    .unwrap();
+    let mut buf = [0; 256];
+    let n = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"READY=1\nSTATUS=listening\n");
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -58,7 +58,10 @@ impl TcpListenerRedirExt for TcpListener {
         socket.set_reuseaddr(true)?;
 
         let set_dual_stack = is_dual_stack_addr(&addr);
-        if set_dual_stack {
+        if accept_opts.ipv6_only && addr.is_ipv6() {
+            set_ipv6_only(&socket, true)?;
+            socket.bind(addr)?;
+        } else if set_dual_stack {
             // Try to bind dual-stack address
             match set_ipv6_only(&socket, false) {
                 Ok(..) => {
@@ -0,0 +1,200 @@
+//! modified from shadowsocks-service/src/local/redir/tcprelay/sys/unix/linux.rs
+
+use crate::utils::net::{AcceptOpts, is_dual_stack_addr, set_tcp_fastopen};
+use log::warn;
+use socket2::SockAddr;
+use std::{
+    io::{self, Error, ErrorKind},
+    net::SocketAddr,
+    os::unix::io::AsRawFd,
+};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use crate::{
+    redir::redir_ext::{TcpListenerRedirExt, TcpStreamRedirExt},
+    redir::sys::{set_ip_transparent, set_ipv6_only},
+    utils::config::RedirType,
+};
+
+impl TcpListenerRedirExt for TcpListener {
+    async fn bind_redir(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: AcceptOpts,
+    ) -> io::Result<TcpListener> {
+        match ty {
+            RedirType::Redirect | RedirType::TProxy => {}
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "not supported tcp transparent proxy type",
+                ));
+            }
+        }
+
+        let socket = match addr {
+            SocketAddr::V4(..) => TcpSocket::new_v4()?,
+            SocketAddr::V6(..) => TcpSocket::new_v6()?,
+        };
+
+        // `REDIRECT` rewrites the destination to a local address, so the listener itself
+        // is an ordinary socket. The original destination is recovered by `SO_ORIGINAL_DST`.
+        //
+        // `TPROXY` keeps the original destination, the listener has to accept connections
+        // to non-local addresses with `IP_TRANSPARENT`.
+        if ty == RedirType::TProxy {
+            set_ip_transparent(&socket, &addr)?;
+        }
+
+        socket.set_reuseaddr(true)?;
+
+        let set_dual_stack = is_dual_stack_addr(&addr);
+        if accept_opts.ipv6_only && addr.is_ipv6() {
+            set_ipv6_only(&socket, true)?;
+            socket.bind(addr)?;
+        } else if set_dual_stack {
+            // Try to bind dual-stack address
+            match set_ipv6_only(&socket, false) {
+                Ok(..) => {
+                    // bind()
+                    if let Err(err) = socket.bind(addr) {
+                        warn!(
+                            "bind() dual-stack address {} failed, error: {}, fallback to IPV6_V6ONLY=true",
+                            addr, err
+                        );
+
+                        if let Err(err) = set_ipv6_only(&socket, true) {
+                            warn!(
+                                "set IPV6_V6ONLY=true failed, error: {}, bind() to {} directly",
+                                err, addr
+                            );
+                        }
+
+                        socket.bind(addr)?;
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "set IPV6_V6ONLY=false failed, error: {}, bind() to {} directly",
+                        err, addr
+                    );
+                    socket.bind(addr)?;
+                }
+            }
+        } else {
+            socket.bind(addr)?;
+        }
+
+        // mio's default backlog is 1024
+        let listener = socket.listen(1024)?;
+
+        if accept_opts.tcp.fastopen {
+            set_tcp_fastopen(&listener)?;
+        }
+
+        Ok(listener)
+    }
+}
+
+/// Listener of `ty` from a socket listening already, e.g. passed by systemd
+pub fn listener_from_std(
+    ty: RedirType,
+    listener: std::net::TcpListener,
+    accept_opts: &AcceptOpts,
+) -> io::Result<TcpListener> {
+    match ty {
+        RedirType::Redirect => {}
+        // `IP_TRANSPARENT` also takes effect after `bind()`
+        RedirType::TProxy => set_ip_transparent(&listener, &listener.local_addr()?)?,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not supported tcp transparent proxy type",
+            ));
+        }
+    }
+
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    if accept_opts.tcp.fastopen {
+        set_tcp_fastopen(&listener)?;
+    }
+    Ok(listener)
+}
+
+impl TcpStreamRedirExt for TcpStream {
+    fn destination_addr(&self, ty: RedirType) -> io::Result<SocketAddr> {
+        match ty {
+            RedirType::Redirect => get_original_destination_addr(self),
+            // `TPROXY` doesn't modify the destination, it is the local address of the accepted socket,
+            // IPv4-mapped on the dual-stack listeners
+            RedirType::TProxy => {
+                let addr = self.local_addr()?;
+                Ok(SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+            }
+            _ => unreachable!("not supported tcp transparent proxy type"),
+        }
+    }
+}
+
+/// Read the destination address before `REDIRECT` with `SO_ORIGINAL_DST` (or `IP6T_SO_ORIGINAL_DST`)
+fn get_original_destination_addr(s: &TcpStream) -> io::Result<SocketAddr> {
+    let fd = s.as_raw_fd();
+
+    // Connections accepted by a dual-stack listener from IPv4 clients have IPv4-mapped local
+    // addresses, and their conntrack entries are IPv4, so they have to be queried with `SOL_IP`.
+    let is_ipv4 = match s.local_addr()? {
+        SocketAddr::V4(..) => true,
+        SocketAddr::V6(ref v6) => v6.ip().to_ipv4_mapped().is_some(),
+    };
+
+    unsafe {
+        let (_, target_addr) = SockAddr::try_init(|target_addr, target_addr_len| {
+            let ret = if is_ipv4 {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IP,
+                    libc::SO_ORIGINAL_DST,
+                    target_addr as *mut _,
+                    target_addr_len,
+                )
+            } else {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IP6T_SO_ORIGINAL_DST,
+                    target_addr as *mut _,
+                    target_addr_len,
+                )
+            };
+
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        })?;
+
+        target_addr
+            .as_socket()
+            .ok_or_else(|| io::Error::other("SO_ORIGINAL_DST returned a non-IP address"))
+    }
+}
+
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_tproxy_destination_addr() {
+    let listener = TcpListener::bind_redir(
+        RedirType::TProxy,
+        "[::]:23456".parse().unwrap(),
+        AcceptOpts::default(),
+    )
+    .await
+    .unwrap();
+    let _client = TcpStream::connect("127.0.0.1:23456").await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    assert_eq!(
+        stream.destination_addr(RedirType::TProxy).unwrap(),
+        "127.0.0.1:23456".parse().unwrap()
+    );
+}
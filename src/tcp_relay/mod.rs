@@ -1 +1,592 @@
-pub mod bsd;
+use crate::{
+    admission::AdmissionPermit,
+    metrics::{self, METRICS},
+    redir::redir_ext::TcpStreamRedirExt,
+    router::{Flow, Outbound, Protocol},
+    service::Shared,
+    session::{CloseReason, CountingStream, Session, SessionGuard, SessionMeta},
+    utils::{
+        config::{Config, RedirType, TcpTimeouts, UpstreamConfig, UpstreamProtocol},
+        http::tcp_client::HttpTcpClient,
+        net::{self, ConnectOpts},
+        socks::{socks5::Address, tcp_client::Socks5TcpClient},
+    },
+};
+use arc_swap::ArcSwap;
+use cfg_if::cfg_if;
+use futures::TryFutureExt;
+use socket2::SockRef;
+use std::{
+    future,
+    io::{self, Result},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, split},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::watch,
+    time,
+};
+
+/// Delay of accepting again after running out of descriptors
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+/// Buffer of each direction of a session
+const RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+pub mod sniff;
+
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        pub mod linux;
+    } else if #[cfg(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "macos",
+        target_os = "ios"
+    ))] {
+        pub mod bsd;
+    }
+}
+
+/// Accept the TCP connections redirected to `listener`
+///
+/// Outbound of each connection is decided by the current `config` (see `Config::route`), so the
+/// new settings take effect on the next connection after `config` is swapped.
+///
+/// Connections to the fake IPs in `shared.fake_ip` are relayed to their domain names. The domain
+/// names in `shared.dns_snoop` are used if the listener enables it. Each relayed connection is a
+/// session in `shared.sessions`.
+///
+/// Once `shutdown` is set (or its sender is dropped), `listener` and `shutdown` are dropped, so
+/// `watch::Sender::closed()` resolves when the listening address can be bound again.
+/// The established connections are not affected.
+pub async fn run(
+    listener: TcpListener,
+    listen_addr: SocketAddr,
+    redir_ty: RedirType,
+    config: Arc<ArcSwap<Config>>,
+    shared: Arc<Shared>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let (stream, client_addr) = tokio::select! {
+            accept_result = listener.accept() => match accept_result {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("accept stream on {} error: {}", listen_addr, e);
+                    // the pending connection is still there, don't retry at once
+                    if is_resource_exhausted(&e) {
+                        time::sleep(ACCEPT_ERROR_DELAY).await;
+                    }
+                    continue;
+                }
+            },
+            _ = shutdown.changed() => break,
+        };
+        METRICS.inc_accepted(listen_addr);
+        let Some(permit) = shared.admission.try_acquire() else {
+            METRICS.inc_rejected(Protocol::Tcp);
+            log::debug!(
+                "too many flows, reject client {} on {}",
+                client_addr,
+                listen_addr
+            );
+            // reset, so the client fails at once instead of waiting for a reply
+            let _ = SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+            continue;
+        };
+
+        let orig_dst = match stream.destination_addr(redir_ty) {
+            Ok(addr) => addr,
+            Err(e) => {
+                log::error!(
+                    "get original destination of client {} error: {}",
+                    client_addr,
+                    e
+                );
+                continue;
+            }
+        };
+        log::trace!("Original destination: {}", orig_dst);
+
+        let config = config.load_full();
+        if let Err(e) = net::set_tcp_opts(&stream, &config.accept_opts.tcp) {
+            log::warn!("set options of client {} error: {}", client_addr, e);
+        }
+        tokio::spawn(handle_client(
+            stream,
+            client_addr,
+            orig_dst,
+            listen_addr,
+            config,
+            shared.clone(),
+            permit,
+        ));
+    }
+}
+
+/// Out of descriptors or memory
+fn is_resource_exhausted(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    )
+}
+
+/// Relay `stream` through the outbound chosen by `config`, `_permit` is held until it is done
+///
+/// The domain name of the destination is found from the fake IPs, or sniffed if enabled, or from
+/// the DNS snooping if enabled.
+async fn handle_client(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    orig_dst: SocketAddr,
+    listen_addr: SocketAddr,
+    config: Arc<Config>,
+    shared: Arc<Shared>,
+    _permit: AdmissionPermit,
+) {
+    let Some(listener_config) = config.listeners.iter().find(|l| l.addr == listen_addr) else {
+        log::debug!(
+            "listener {} is removed, drop client {}",
+            listen_addr,
+            client_addr
+        );
+        return;
+    };
+
+    let fake_ip = shared.fake_ip.load_full();
+    let fake_ip = fake_ip.filter(|pool| pool.contains(orig_dst.ip()));
+    let domain = match fake_ip {
+        Some(ref pool) => match pool.domain(orig_dst.ip()) {
+            Some(domain) => Some(domain),
+            None => {
+                log::warn!(
+                    "fake IP {} is not allocated or expired, drop client {}",
+                    orig_dst.ip(),
+                    client_addr
+                );
+                return;
+            }
+        },
+        None => {
+            let sniffed = match listener_config.sniff {
+                true => sniff::sniff_domain(&stream).await,
+                false => None,
+            };
+            sniffed.or_else(|| {
+                listener_config
+                    .dns_snoop
+                    .then(|| shared.dns_snoop.domain(orig_dst.ip()))
+                    .flatten()
+            })
+        }
+    };
+    if let Some(ref domain) = domain {
+        log::trace!("Domain of {}: {}", orig_dst, domain);
+    }
+
+    let flow = Flow {
+        protocol: Protocol::Tcp,
+        src: client_addr,
+        dst: orig_dst,
+        domain: domain.as_deref(),
+    };
+    let register = |outbound: &Outbound, upstream: Option<String>| {
+        shared.sessions.register(SessionMeta {
+            protocol: Protocol::Tcp,
+            listener: listen_addr,
+            client: client_addr,
+            destination: orig_dst,
+            domain: domain.clone(),
+            outbound: outbound.to_string(),
+            upstream,
+        })
+    };
+    let timeouts = config.tcp_timeouts;
+    let connect_opts = config.connect_opts();
+    match config.route(listener_config, &flow) {
+        Outbound::Proxy(name) => {
+            let Some(upstream) = config.upstreams.get(&name) else {
+                log::error!(
+                    "upstream {} is not defined, drop client {}",
+                    name,
+                    client_addr
+                );
+                return;
+            };
+            // don't hold the old config during the connection
+            let upstream = upstream.clone();
+            drop(config);
+            let session = register(
+                &Outbound::Proxy(name.clone()),
+                Some(format!("{} {}", upstream.protocol, upstream.addr)),
+            );
+
+            // the upstream resolves the sniffed domain by itself
+            let target = match domain {
+                Some(domain) => Address::DomainNameAddress(domain, orig_dst.port()),
+                None => Address::SocketAddress(orig_dst),
+            };
+            log::debug!(
+                "Proxy: New client from: {} to {} via {}",
+                client_addr,
+                target,
+                name
+            );
+            let stream = CountingStream::new(stream, session.session().clone());
+            let relay = handle_client_with_proxy(
+                stream,
+                target,
+                &name,
+                &upstream,
+                &timeouts,
+                &connect_opts,
+                &session,
+            );
+            if let Err(e) = run_session(&session, relay).await {
+                log::error!("handle stream proxy error: {}", e);
+            }
+        }
+        Outbound::Direct => {
+            drop(config);
+            let session = register(&Outbound::Direct, None);
+            // a fake IP can not be connected, resolve its domain locally
+            let target = match domain {
+                Some(ref domain) if fake_ip.is_some() => {
+                    Address::DomainNameAddress(domain.clone(), orig_dst.port())
+                }
+                _ => Address::SocketAddress(orig_dst),
+            };
+            match domain {
+                Some(domain) if fake_ip.is_none() => log::debug!(
+                    "Direct: New client from: {} to {} ({})",
+                    client_addr,
+                    target,
+                    domain
+                ),
+                _ => log::debug!("Direct: New client from: {} to {}", client_addr, target),
+            }
+            let stream = CountingStream::new(stream, session.session().clone());
+            let relay = handle_client_direct(stream, target, &timeouts, &connect_opts, &session);
+            if let Err(e) = run_session(&session, relay).await {
+                log::error!("handle stream direct error: {}", e);
+            }
+        }
+        Outbound::Reject => match domain {
+            Some(domain) => log::debug!(
+                "Reject: client from: {} to {} ({})",
+                client_addr,
+                orig_dst,
+                domain
+            ),
+            None => log::debug!("Reject: client from: {} to {}", client_addr, orig_dst),
+        },
+    }
+}
+
+/// Run `relay` of `session` until it finishes, or the session is closed by the admin API or the
+/// shutdown
+async fn run_session<F>(session: &SessionGuard, relay: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    tokio::select! {
+        result = relay => result,
+        _ = session.closed() => {
+            log::debug!("session {} is closed ({})", session.id, session.close_reason().0);
+            Ok(())
+        }
+    }
+}
+
+/// Relay `client_stream` to `target` through upstream `name`, the close reason is recorded in
+/// `session`
+async fn handle_client_with_proxy<S>(
+    client_stream: S,
+    target: Address,
+    name: &str,
+    upstream: &UpstreamConfig,
+    timeouts: &TcpTimeouts,
+    connect_opts: &ConnectOpts,
+    session: &Session,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connect_start = Instant::now();
+    let proxy_stream = connect_tcp(upstream.addr, timeouts.connect, connect_opts)
+        .await
+        .inspect_err(|e| {
+            log::error!(
+                "connect {} proxy {} error: {e}",
+                upstream.protocol,
+                upstream.addr
+            );
+            METRICS.inc_connect_failure(name, metrics::io_failure_reason(e));
+            session.set_close_reason(connect_close_reason(e), Some(e.to_string()));
+        })?;
+    // reconnecting to retry with the credentials is a part of the handshake
+    let reconnect = || connect_tcp(upstream.addr, timeouts.connect, connect_opts);
+    let auth = upstream.auth.as_ref();
+    match upstream.protocol {
+        UpstreamProtocol::Http => {
+            let handshake = HttpTcpClient::connect_on(proxy_stream, target, auth, reconnect)
+                .map_err(|e| (e.failure_reason(), e.into()));
+            let proxy_stream = upstream_handshake(handshake, name, timeouts, session).await?;
+            METRICS.observe_connect(name, "http", connect_start.elapsed());
+            relay(client_stream, proxy_stream, timeouts, session).await;
+        }
+        UpstreamProtocol::Socks5 => {
+            let handshake = Socks5TcpClient::connect_on(proxy_stream, target, auth)
+                .map_err(|e| (e.failure_reason(), e.into()));
+            let proxy_stream = upstream_handshake(handshake, name, timeouts, session).await?;
+            METRICS.observe_connect(name, "socks5", connect_start.elapsed());
+            relay(client_stream, proxy_stream, timeouts, session).await;
+        }
+    }
+    Ok(())
+}
+
+/// Run `handshake` with upstream `name` in `timeouts.handshake`, the failure is recorded in
+/// `session`
+async fn upstream_handshake<F, T>(
+    handshake: F,
+    name: &str,
+    timeouts: &TcpTimeouts,
+    session: &Session,
+) -> Result<T>
+where
+    F: Future<Output = std::result::Result<T, (&'static str, io::Error)>>,
+{
+    let (reason, close_reason, err) = match time::timeout(timeouts.handshake, handshake).await {
+        Ok(Ok(stream)) => return Ok(stream),
+        Ok(Err((reason, err))) => (reason, connect_close_reason(&err), err),
+        Err(..) => (
+            "timeout",
+            CloseReason::HandshakeTimeout,
+            io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"),
+        ),
+    };
+    log::error!("handshake with proxy {name} error: {err}");
+    METRICS.inc_connect_failure(name, reason);
+    session.set_close_reason(close_reason, Some(err.to_string()));
+    Err(err)
+}
+
+/// Relay `client_stream` to `target` directly, the close reason is recorded in `session`
+async fn handle_client_direct<S>(
+    client_stream: S,
+    target: Address,
+    timeouts: &TcpTimeouts,
+    connect_opts: &ConnectOpts,
+    session: &Session,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let connect_result = match target {
+        Address::SocketAddress(addr) => connect_tcp(addr, timeouts.connect, connect_opts).await,
+        Address::DomainNameAddress(ref domain, port) => {
+            connect_tcp((domain.as_str(), port), timeouts.connect, connect_opts).await
+        }
+    };
+    let another_stream = connect_result.inspect_err(|e| {
+        log::error!("connect direct {target} error: {e}");
+        METRICS.inc_connect_failure("direct", metrics::io_failure_reason(e));
+        session.set_close_reason(connect_close_reason(e), Some(e.to_string()));
+    })?;
+    relay(client_stream, another_stream, timeouts, session).await;
+    Ok(())
+}
+
+/// Connect to `addr` with `connect_opts` in `timeout`, including resolving the domain name
+async fn connect_tcp<A: ToSocketAddrs>(
+    addr: A,
+    timeout: Duration,
+    connect_opts: &ConnectOpts,
+) -> Result<TcpStream> {
+    time::timeout(timeout, net::connect_tcp(addr, connect_opts))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")))
+}
+
+/// Close reason of failing to connect with `err`
+fn connect_close_reason(err: &io::Error) -> CloseReason {
+    match err.kind() {
+        io::ErrorKind::TimedOut => CloseReason::ConnectTimeout,
+        _ => CloseReason::ConnectFailed,
+    }
+}
+
+/// Copy between the connected streams until both sides are closed, or no data is relayed in
+/// `timeouts.idle`, or the other side doesn't finish in `timeouts.half_close` after one side is
+/// closed
+///
+/// The errors are usual (e.g. reset by either side), so they are only recorded in `session`.
+async fn relay<A, B>(a: A, b: B, timeouts: &TcpTimeouts, session: &Session)
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = split(a);
+    let (mut b_read, mut b_write) = split(b);
+    let activity = Activity::new();
+    let a_to_b = copy_half(&mut a_read, &mut b_write, &activity);
+    let b_to_a = copy_half(&mut b_read, &mut a_write, &activity);
+    tokio::pin!(a_to_b, b_to_a);
+
+    let (mut a_closed, mut b_closed) = (false, false);
+    let mut half_closed_at = None;
+    let (reason, error) = loop {
+        let idle_deadline = timeouts.idle.map(|idle| activity.last() + idle);
+        let half_close_deadline = half_closed_at
+            .zip(timeouts.half_close)
+            .map(|(at, t)| at + t);
+        let deadline = match (idle_deadline, half_close_deadline) {
+            (Some(idle), Some(half_close)) => Some(idle.min(half_close)),
+            (deadline, None) | (None, deadline) => deadline,
+        };
+        let timer = async {
+            match deadline {
+                Some(deadline) => time::sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = &mut a_to_b, if !a_closed => match result {
+                Ok(()) => a_closed = true,
+                Err(e) => break (CloseReason::Error, e.to_string()),
+            },
+            result = &mut b_to_a, if !b_closed => match result {
+                Ok(()) => b_closed = true,
+                Err(e) => break (CloseReason::Error, e.to_string()),
+            },
+            _ = timer => {
+                let now = time::Instant::now();
+                if half_close_deadline.is_some_and(|deadline| deadline <= now) {
+                    break (CloseReason::HalfCloseTimeout, "half-closed for too long".to_owned());
+                }
+                // the deadline is moved if there is data in the meantime
+                if let Some(idle) = timeouts.idle
+                    && activity.last() + idle <= now
+                {
+                    break (CloseReason::IdleTimeout, "idle for too long".to_owned());
+                }
+            }
+        }
+        if a_closed && b_closed {
+            return;
+        }
+        if a_closed || b_closed {
+            half_closed_at.get_or_insert_with(time::Instant::now);
+        }
+    };
+    log::debug!("session {} relay error: {}", session.id, error);
+    session.set_close_reason(reason, Some(error));
+}
+
+/// Last time data is relayed in either direction
+struct Activity {
+    start: time::Instant,
+    /// Milliseconds since `start`
+    last: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            start: time::Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> time::Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// Copy `reader` to `writer` until EOF, then shut `writer` down
+async fn copy_half<R, W>(reader: &mut R, writer: &mut W, activity: &Activity) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        activity.touch();
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+    }
+}
+
+#[tokio::test]
+async fn test_relay_timeouts() {
+    use crate::session::Sessions;
+    use tokio::io::duplex;
+
+    let sessions = Arc::new(Sessions::new());
+    let meta = SessionMeta {
+        protocol: Protocol::Tcp,
+        listener: "127.0.0.1:12345".parse().unwrap(),
+        client: "192.168.1.2:40000".parse().unwrap(),
+        destination: "1.2.3.4:443".parse().unwrap(),
+        domain: None,
+        outbound: "direct".to_owned(),
+        upstream: None,
+    };
+    let timeouts = TcpTimeouts {
+        idle: Some(Duration::from_millis(200)),
+        half_close: Some(Duration::from_millis(500)),
+        ..TcpTimeouts::default()
+    };
+
+    // the data keeps an idle session alive
+    let session = sessions.register(meta.clone());
+    let (a, mut client) = duplex(64);
+    let (b, _server) = duplex(64);
+    let start = Instant::now();
+    let writer = async {
+        for _ in 0..3 {
+            time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+        }
+    };
+    tokio::join!(relay(a, b, &timeouts, &session), writer);
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    assert_eq!(session.close_reason().0, CloseReason::IdleTimeout);
+
+    // the client is closed, and the server keeps sending but doesn't close
+    let session = sessions.register(meta);
+    let (a, mut client) = duplex(64);
+    let (b, mut server) = duplex(64);
+    client.shutdown().await.unwrap();
+    let writer = async {
+        loop {
+            time::sleep(Duration::from_millis(100)).await;
+            if server.write_all(b"pong").await.is_err() {
+                break;
+            }
+        }
+    };
+    tokio::select! {
+        _ = relay(a, b, &timeouts, &session) => {}
+        _ = writer => {}
+    }
+    assert_eq!(session.close_reason().0, CloseReason::HalfCloseTimeout);
+}
@@ -0,0 +1,252 @@
+//! Recover the destination domain name from the first bytes sent by the client,
+//! the TLS ClientHello SNI or the HTTP/1 `Host` header
+
+use std::{net::IpAddr, time::Duration};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
+
+/// Maximum bytes to inspect
+const MAXIMUM_SNIFF_SIZE: usize = 8192;
+/// Maximum time to wait for the client, servers speaking first (e.g. SSH, SMTP) are delayed by it
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+/// `peek` returns the same bytes at once, wait a moment before peeking again
+const SNIFF_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+const TLS_CONTENT_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_HOST_NAME: u8 = 0x00;
+
+/// Result of inspecting the first bytes
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    Domain(String),
+    /// More bytes are required
+    Incomplete,
+    /// Not TLS or HTTP, or no domain is carried
+    NotFound,
+}
+
+/// Peek the first bytes of `stream` without consuming them, and extract the domain name
+pub async fn sniff_domain(stream: &TcpStream) -> Option<String> {
+    let mut buf = vec![0u8; MAXIMUM_SNIFF_SIZE];
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    loop {
+        let n = match time::timeout_at(deadline, stream.peek(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            // timed out, closed or failed
+            _ => return None,
+        };
+        match sniff(&buf[..n]) {
+            Sniffed::Domain(domain) => return Some(domain),
+            Sniffed::Incomplete if n < buf.len() => {
+                if Instant::now() + SNIFF_RETRY_INTERVAL > deadline {
+                    return None;
+                }
+                time::sleep(SNIFF_RETRY_INTERVAL).await;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Extract the domain name from the first bytes of a TLS or HTTP/1 stream
+pub fn sniff(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(&TLS_CONTENT_HANDSHAKE) => sniff_tls(buf),
+        Some(b) if b.is_ascii_uppercase() => sniff_http(buf),
+        Some(..) => Sniffed::NotFound,
+    }
+}
+
+fn sniff_tls(buf: &[u8]) -> Sniffed {
+    // record header: content type(1), version(2), length(2)
+    if buf.len() < 5 {
+        return Sniffed::Incomplete;
+    }
+    if buf[1] != 0x03 {
+        return Sniffed::NotFound;
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    let record = &buf[5..];
+
+    // only the first record is inspected, ClientHello rarely spans multiple records
+    match parse_client_hello(Cursor(&record[..record.len().min(record_len)])) {
+        Some(sniffed) => sniffed,
+        None if record.len() < record_len => Sniffed::Incomplete,
+        None => Sniffed::NotFound,
+    }
+}
+
+/// `None` if the ClientHello is truncated
+fn parse_client_hello(mut c: Cursor) -> Option<Sniffed> {
+    if c.u8()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return Some(Sniffed::NotFound);
+    }
+    // length(3), version(2), random(32)
+    c.bytes(3 + 2 + 32)?;
+    // session id, cipher suites, compression methods
+    c.vec8()?;
+    c.vec16()?;
+    c.vec8()?;
+
+    let mut extensions = Cursor(c.vec16()?);
+    while !extensions.0.is_empty() {
+        let ty = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if ty == TLS_EXTENSION_SERVER_NAME {
+            let domain = parse_server_name(Cursor(data)).and_then(normalize_domain);
+            return Some(domain.map_or(Sniffed::NotFound, Sniffed::Domain));
+        }
+    }
+    Some(Sniffed::NotFound)
+}
+
+fn parse_server_name(mut c: Cursor<'_>) -> Option<&str> {
+    let mut names = Cursor(c.vec16()?);
+    while !names.0.is_empty() {
+        let ty = names.u8()?;
+        let name = names.vec16()?;
+        if ty == TLS_SERVER_NAME_HOST_NAME {
+            return std::str::from_utf8(name).ok();
+        }
+    }
+    None
+}
+
+fn sniff_http(buf: &[u8]) -> Sniffed {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(..)) => {}
+        Ok(httparse::Status::Partial) => return Sniffed::Incomplete,
+        Err(..) => return Sniffed::NotFound,
+    }
+
+    req.headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("Host"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .and_then(normalize_domain)
+        .map_or(Sniffed::NotFound, Sniffed::Domain)
+}
+
+/// Lowercase `host` without port and the trailing dot, `None` if it is not a domain name
+fn normalize_domain(host: &str) -> Option<String> {
+    let host = host.trim();
+    // IPv6 literal
+    if host.starts_with('[') {
+        return None;
+    }
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        Some(..) => return None,
+        None => host,
+    };
+    let host = host.strip_suffix('.').unwrap_or(host);
+
+    let valid = (1..=253).contains(&host.len())
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+        && host.parse::<IpAddr>().is_err();
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+/// Reads big-endian integers and length-prefixed vectors, `None` if there are not enough bytes
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+#[test]
+fn test_sniff_tls() {
+    let name = b"Example.COM.";
+    let mut server_name = Vec::new();
+    server_name.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    server_name.push(TLS_SERVER_NAME_HOST_NAME);
+    server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    server_name.extend_from_slice(name);
+
+    let mut extensions = Vec::new();
+    // an extension before server_name, supported_versions
+    extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+    extensions.extend_from_slice(&TLS_EXTENSION_SERVER_NAME.to_be_bytes());
+    extensions.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(&server_name);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    body.extend_from_slice(&[0x00]); // session id
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+    body.extend_from_slice(&[0x01, 0x00]); // compression methods
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut hello = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+    hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    hello.extend_from_slice(&body);
+
+    let mut record = vec![TLS_CONTENT_HANDSHAKE, 0x03, 0x01];
+    record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+    record.extend_from_slice(&hello);
+
+    assert_eq!(sniff(&record), Sniffed::Domain("example.com".to_owned()));
+    assert_eq!(sniff(&record[..record.len() - 1]), Sniffed::Incomplete);
+    assert_eq!(sniff(&record[..3]), Sniffed::Incomplete);
+
+    // record length is shorter than the ClientHello
+    record[4] -= 1;
+    assert_eq!(sniff(&record), Sniffed::NotFound);
+}
+
+#[test]
+fn test_sniff_http() {
+    assert_eq!(
+        sniff(b"GET / HTTP/1.1\r\nHost: www.example.com:8080\r\nAccept: */*\r\n\r\n"),
+        Sniffed::Domain("www.example.com".to_owned())
+    );
+    assert_eq!(
+        sniff(b"GET / HTTP/1.1\r\nHost: www.exa"),
+        Sniffed::Incomplete
+    );
+    assert_eq!(
+        sniff(b"GET / HTTP/1.1\r\nHost: 1.2.3.4\r\n\r\n"),
+        Sniffed::NotFound
+    );
+    assert_eq!(
+        sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+        Sniffed::NotFound
+    );
+    assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.6\r\n"), Sniffed::NotFound);
+    assert_eq!(sniff(&[0x00, 0x01]), Sniffed::NotFound);
+}
@@ -0,0 +1,333 @@
+//! modified from shadowsocks-service/src/local/redir/udprelay/sys/unix/linux.rs
+
+use std::{
+    io::{self, Error, ErrorKind},
+    mem,
+    net::{SocketAddr, UdpSocket},
+    os::unix::io::AsRawFd,
+    ptr,
+    task::{Context, Poll},
+};
+
+use crate::utils::net::{AcceptOpts, is_dual_stack_addr};
+use futures::{future::poll_fn, ready};
+use log::{error, trace, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    redir::{
+        redir_ext::{RedirSocketOpts, UdpSocketRedir},
+        sys::{set_fwmark, set_int_opt, set_ip_transparent, set_ipv6_only},
+    },
+    utils::config::RedirType,
+};
+
+pub struct UdpRedirSocket {
+    io: AsyncFd<UdpSocket>,
+}
+
+impl UdpRedirSocket {
+    /// Create a new UDP socket binded to `addr`
+    ///
+    /// This will allow listening to `addr` that is not in local host
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: &AcceptOpts,
+        redir_opts: &RedirSocketOpts,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, redir_opts, false, accept_opts.ipv6_only)
+    }
+
+    /// Create from a bound UDP socket, e.g. passed by systemd
+    pub fn from_std(ty: RedirType, socket: UdpSocket) -> io::Result<UdpRedirSocket> {
+        if ty != RedirType::TProxy {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not supported udp transparent proxy type",
+            ));
+        }
+
+        let addr = socket.local_addr()?;
+        let socket = Socket::from(socket);
+        // the options also take effect after `bind()`
+        set_socket_before_bind(&addr, &socket)?;
+        socket.set_nonblocking(true)?;
+
+        let io = AsyncFd::new(socket.into())?;
+        Ok(UdpRedirSocket { io })
+    }
+
+    /// Deregister from the runtime, e.g. to be used by another one
+    pub fn into_std(self) -> io::Result<UdpSocket> {
+        Ok(self.io.into_inner())
+    }
+
+    /// Create a new UDP socket binded to `addr`
+    ///
+    /// This will allow binding to `addr` that is not in local host
+    pub fn bind_nonlocal(
+        ty: RedirType,
+        addr: SocketAddr,
+        redir_opts: &RedirSocketOpts,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, redir_opts, true, false)
+    }
+
+    fn bind(
+        ty: RedirType,
+        addr: SocketAddr,
+        redir_opts: &RedirSocketOpts,
+        reuse_port: bool,
+        ipv6_only: bool,
+    ) -> io::Result<UdpRedirSocket> {
+        if ty != RedirType::TProxy {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "not supported udp transparent proxy type",
+            ));
+        }
+
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        set_socket_before_bind(&addr, &socket)?;
+
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
+        if reuse_port && let Err(err) = socket.set_reuse_port(true) {
+            if let Some(libc::ENOPROTOOPT) = err.raw_os_error() {
+                trace!("failed to set SO_REUSEPORT, error: {}", err);
+            } else {
+                error!("failed to set SO_REUSEPORT, error: {}", err);
+                return Err(err);
+            }
+        }
+
+        if let Some(mark) = redir_opts.fwmark {
+            set_fwmark(&socket, mark)?;
+        }
+
+        let sock_addr = SockAddr::from(addr);
+
+        if ipv6_only && addr.is_ipv6() {
+            set_ipv6_only(&socket, true)?;
+            socket.bind(&sock_addr)?;
+        } else if is_dual_stack_addr(&addr) {
+            match set_ipv6_only(&socket, false) {
+                Ok(..) => {
+                    if let Err(err) = socket.bind(&sock_addr) {
+                        warn!(
+                            "bind() dual-stack address {} failed, error: {}, fallback to IPV6_V6ONLY=true",
+                            addr, err
+                        );
+
+                        if let Err(err) = set_ipv6_only(&socket, true) {
+                            warn!(
+                                "set IPV6_V6ONLY=true failed, error: {}, bind() to {} directly",
+                                err, addr
+                            );
+                        }
+
+                        socket.bind(&sock_addr)?;
+                    }
+                }
+                Err(err) => {
+                    warn!(
+                        "set IPV6_V6ONLY=false failed, error: {}, bind() to {} directly",
+                        err, addr
+                    );
+                    socket.bind(&sock_addr)?;
+                }
+            }
+        } else {
+            socket.bind(&sock_addr)?;
+        }
+
+        let io = AsyncFd::new(socket.into())?;
+        Ok(UdpRedirSocket { io })
+    }
+
+    /// Send data to the socket to the given target address
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        poll_fn(|cx| self.poll_send_to(cx, buf, target)).await
+    }
+
+    fn poll_send_to(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut write_guard = ready!(self.io.poll_write_ready(cx))?;
+
+            match self.io.get_ref().send_to(buf, target) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    write_guard.clear_ready();
+                }
+                x => return Poll::Ready(x),
+            }
+        }
+    }
+
+    /// Returns the local address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+}
+
+impl UdpSocketRedir for UdpRedirSocket {
+    fn poll_recv_dest_from(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, SocketAddr)>> {
+        loop {
+            let mut read_guard = ready!(self.io.poll_read_ready(cx))?;
+
+            match recv_dest_from(self.io.get_ref(), buf) {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    read_guard.clear_ready();
+                }
+                x => return Poll::Ready(x),
+            }
+        }
+    }
+}
+
+fn set_socket_before_bind(addr: &SocketAddr, socket: &Socket) -> io::Result<()> {
+    let fd = socket.as_raw_fd();
+
+    // 1. set IP_TRANSPARENT, IPV6_TRANSPARENT to allow binding to non-local addresses
+    set_ip_transparent(socket, addr)?;
+
+    // 2. set IP_RECVORIGDSTADDR, IPV6_RECVORIGDSTADDR to receive the original destination
+    //    address as an ancillary message
+    match *addr {
+        SocketAddr::V4(..) => set_int_opt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1)?,
+        SocketAddr::V6(..) => {
+            set_int_opt(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1)?;
+            if is_dual_stack_addr(addr) {
+                set_int_opt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn get_destination_addr(msg: &libc::msghdr) -> io::Result<SocketAddr> {
+    unsafe {
+        let (_, addr) = SockAddr::try_init(|dst_addr, dst_addr_len| {
+            let mut cmsg: *mut libc::cmsghdr = libc::CMSG_FIRSTHDR(msg);
+            while !cmsg.is_null() {
+                let rcmsg = &*cmsg;
+                match (rcmsg.cmsg_level, rcmsg.cmsg_type) {
+                    (libc::SOL_IP, libc::IP_ORIGDSTADDR) => {
+                        ptr::copy_nonoverlapping(
+                            libc::CMSG_DATA(cmsg),
+                            dst_addr as *mut _,
+                            mem::size_of::<libc::sockaddr_in>(),
+                        );
+                        *dst_addr_len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+                        return Ok(());
+                    }
+                    (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => {
+                        ptr::copy_nonoverlapping(
+                            libc::CMSG_DATA(cmsg),
+                            dst_addr as *mut _,
+                            mem::size_of::<libc::sockaddr_in6>(),
+                        );
+                        *dst_addr_len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+
+                        return Ok(());
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+            }
+
+            Err(Error::new(
+                ErrorKind::InvalidData,
+                "missing destination address in msghdr",
+            ))
+        })?;
+
+        addr.as_socket()
+            .ok_or_else(|| io::Error::other("destination address is not an IP address"))
+    }
+}
+
+fn recv_dest_from(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    unsafe {
+        // large enough for one sockaddr_in6 ancillary message
+        let mut control_buf = [0u8; 64];
+        let mut src_addr: libc::sockaddr_storage = mem::zeroed();
+
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_name = &mut src_addr as *mut _ as *mut _;
+        msg.msg_namelen = mem::size_of_val(&src_addr) as libc::socklen_t;
+
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len: buf.len() as libc::size_t,
+        };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        msg.msg_control = control_buf.as_mut_ptr() as *mut _;
+        msg.msg_controllen = control_buf.len() as _;
+
+        let fd = socket.as_raw_fd();
+        let ret = libc::recvmsg(fd, &mut msg, 0);
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (_, src_saddr) = SockAddr::try_init(|a, l| {
+            ptr::copy_nonoverlapping(
+                msg.msg_name as *const u8,
+                a as *mut u8,
+                msg.msg_namelen as usize,
+            );
+            *l = msg.msg_namelen;
+            Ok(())
+        })?;
+        let peer_addr = src_saddr
+            .as_socket()
+            .ok_or_else(|| io::Error::other("source address is not an IP address"))?;
+
+        Ok((ret as usize, peer_addr, get_destination_addr(&msg)?))
+    }
+}
+
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_recv_dest_from() {
+    use crate::redir::redir_ext::UdpSocketRedirExt;
+    use std::net::Ipv4Addr;
+
+    let listen_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+    let socket = UdpRedirSocket::listen(
+        RedirType::TProxy,
+        listen_addr,
+        &AcceptOpts::default(),
+        &RedirSocketOpts::default(),
+    )
+    .unwrap();
+    let local_addr = socket.local_addr().unwrap();
+
+    let client = tokio::net::UdpSocket::bind(listen_addr).await.unwrap();
+    client.send_to(b"hello tproxy", local_addr).await.unwrap();
+
+    let mut buf = [0u8; 64];
+    let (n, peer_addr, dst_addr) = socket.recv_dest_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"hello tproxy");
+    assert_eq!(peer_addr, client.local_addr().unwrap());
+    // not redirected, so the original destination is the listening address itself
+    assert_eq!(dst_addr, local_addr);
+}
@@ -6,7 +6,7 @@ use std::{
     task::{Context, Poll},
 };
 
-use crate::utils::net::is_dual_stack_addr;
+use crate::utils::net::{AcceptOpts, is_dual_stack_addr};
 use futures::{future::poll_fn, ready};
 use log::{error, trace, warn};
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
@@ -14,7 +14,7 @@ use tokio::io::unix::AsyncFd;
 
 use crate::{
     redir::{
-        redir_ext::UdpSocketRedir,
+        redir_ext::{RedirSocketOpts, UdpSocketRedir},
         {bsd_pf::PF, sys::set_ipv6_only},
     },
     utils::config::RedirType,
@@ -28,18 +28,32 @@ impl UdpRedirSocket {
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow listening to `addr` that is not in local host
-    pub fn listen(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
-        UdpRedirSocket::bind(ty, addr, false)
+    pub fn listen(
+        ty: RedirType,
+        addr: SocketAddr,
+        accept_opts: &AcceptOpts,
+        _redir_opts: &RedirSocketOpts,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, false, accept_opts.ipv6_only)
     }
 
     /// Create a new UDP socket binded to `addr`
     ///
     /// This will allow binding to `addr` that is not in local host
-    pub fn bind_nonlocal(ty: RedirType, addr: SocketAddr) -> io::Result<UdpRedirSocket> {
-        UdpRedirSocket::bind(ty, addr, true)
+    pub fn bind_nonlocal(
+        ty: RedirType,
+        addr: SocketAddr,
+        _redir_opts: &RedirSocketOpts,
+    ) -> io::Result<UdpRedirSocket> {
+        UdpRedirSocket::bind(ty, addr, true, false)
     }
 
-    fn bind(ty: RedirType, addr: SocketAddr, reuse_port: bool) -> io::Result<UdpRedirSocket> {
+    fn bind(
+        ty: RedirType,
+        addr: SocketAddr,
+        reuse_port: bool,
+        ipv6_only: bool,
+    ) -> io::Result<UdpRedirSocket> {
         if ty == RedirType::NotSupported {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -63,7 +77,10 @@ impl UdpRedirSocket {
 
         let sock_addr = SockAddr::from(addr);
 
-        if is_dual_stack_addr(&addr) {
+        if ipv6_only && addr.is_ipv6() {
+            set_ipv6_only(&socket, true)?;
+            socket.bind(&sock_addr)?;
+        } else if is_dual_stack_addr(&addr) {
             // set IP_ORIGDSTADDR before bind()
 
             match set_ipv6_only(&socket, false) {
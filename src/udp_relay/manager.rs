@@ -1,13 +1,18 @@
 use crate::{
+    metrics::METRICS,
+    router::Protocol,
+    service::Shared,
+    session::SessionMeta,
     udp_relay::{
         DEFAULT_UDP_EXPIRY_DURATION, UDP_ASSOCIATION_SEND_CHANNEL_SIZE,
-        send::{BindAddr, UdpSendWorker},
+        route::UdpRoute,
+        send::{AssociationKey, BindAddr, SendBackOpts, UdpSendWorker},
     },
-    utils::socks::BasicSocket,
+    utils::{net::ConnectOpts, socks::BasicSocket},
 };
 use bytes::Bytes;
 use lru_time_cache::LruCache;
-use std::{io, marker::PhantomData, net::SocketAddr};
+use std::{io, marker::PhantomData, net::SocketAddr, sync::Arc};
 use tokio::sync::mpsc;
 
 // pub struct Direct;
@@ -41,43 +46,144 @@ use tokio::sync::mpsc;
 //     }
 // }
 
-pub struct UdpNatManager<T, S> {
-    nat_map: LruCache<SocketAddr, UdpSendWorker>,
-    keep_alive_sender: mpsc::Sender<SocketAddr>,
-    proxy_type: T,
+/// Associations of the clients, for each client and outbound
+///
+/// The packets to a fake IP get an association of their own, see `UdpSendWorker::new`.
+pub struct UdpNatManager<R: UdpRoute<S>, S: BasicSocket> {
+    nat_map: LruCache<AssociationKey<R::Outbound>, UdpSendWorker>,
+    keep_alive_sender: mpsc::Sender<AssociationKey<R::Outbound>>,
+    route: R,
+    listen_addr: SocketAddr,
+    shared: Arc<Shared>,
+    /// Record the DNS responses in `shared.dns_snoop`
+    dns_snoop: bool,
+    send_back: SendBackOpts,
+    connect_opts: ConnectOpts,
     phantom: std::marker::PhantomData<S>,
 }
 
-impl<S, T> UdpNatManager<T, S>
+impl<S, R> UdpNatManager<R, S>
 where
     S: BasicSocket,
-    T: BindAddr<S>,
+    R: UdpRoute<S>,
 {
-    pub fn new(proxy_type: T) -> (Self, mpsc::Receiver<SocketAddr>) {
+    pub fn new(
+        route: R,
+        listen_addr: SocketAddr,
+        shared: Arc<Shared>,
+        dns_snoop: bool,
+        send_back: SendBackOpts,
+        connect_opts: ConnectOpts,
+    ) -> (Self, mpsc::Receiver<AssociationKey<R::Outbound>>) {
         let (keep_alive_sender, keep_alive_receiver) =
-            mpsc::channel::<SocketAddr>(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
+            mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
         (
             UdpNatManager {
                 nat_map: LruCache::with_expiry_duration(DEFAULT_UDP_EXPIRY_DURATION),
                 keep_alive_sender,
-                proxy_type,
+                route,
+                listen_addr,
+                shared,
+                dns_snoop,
+                send_back,
+                connect_opts,
                 phantom: PhantomData,
             },
             keep_alive_receiver,
         )
     }
+
+    /// Send `data` to `target` through the outbound chosen by the route, the packets of
+    /// rejected flows are dropped silently
     pub fn send_to(
         &mut self,
         peer_addr: SocketAddr,
         target: SocketAddr,
         data: Bytes,
     ) -> io::Result<()> {
-        let worker = match self.nat_map.entry(peer_addr) {
+        let fake_ip = self.shared.fake_ip.load();
+        let domain = match fake_ip.as_ref().filter(|pool| pool.contains(target.ip())) {
+            Some(pool) => match pool.domain(target.ip()) {
+                Some(domain) => Some(domain),
+                None => {
+                    log::debug!(
+                        "udp packet {} -> {} is dropped, fake IP is not allocated or expired",
+                        peer_addr,
+                        target
+                    );
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+
+        let Some(outbound) = self.route.route(peer_addr, target, domain.as_deref()) else {
+            log::trace!("udp packet {} -> {} is rejected", peer_addr, target);
+            return Ok(());
+        };
+        let key = (
+            peer_addr,
+            outbound.clone(),
+            domain.is_some().then_some(target),
+        );
+        // the worker stops if its session is closed by the admin API, start a new one
+        if self
+            .nat_map
+            .peek(&key)
+            .is_some_and(|w| w.worker_handle().is_finished())
+        {
+            self.nat_map.remove(&key);
+        }
+        let worker = match self.nat_map.entry(key) {
             lru_time_cache::Entry::Occupied(w) => w.into_mut(),
             lru_time_cache::Entry::Vacant(e) => {
-                log::debug!("created udp association for {}", peer_addr);
-                let worker =
-                    UdpSendWorker::new(peer_addr, self.keep_alive_sender.clone(), self.proxy_type)?;
+                let dns_snoop = self.dns_snoop.then(|| self.shared.dns_snoop.clone());
+                let snooped = match domain {
+                    Some(_) => None,
+                    None => dns_snoop.as_ref().and_then(|s| s.domain(target.ip())),
+                };
+                let flow_domain = domain.clone().or(snooped);
+                let Some(permit) = self.shared.admission.try_acquire() else {
+                    METRICS.inc_rejected(Protocol::Udp);
+                    log::debug!(
+                        "udp packet {} -> {} is dropped, too many flows",
+                        peer_addr,
+                        target
+                    );
+                    return Ok(());
+                };
+                let session = self
+                    .shared
+                    .sessions
+                    .register(SessionMeta {
+                        protocol: Protocol::Udp,
+                        listener: self.listen_addr,
+                        client: peer_addr,
+                        destination: target,
+                        domain: flow_domain.clone(),
+                        outbound: outbound.to_string(),
+                        upstream: outbound.upstream().map(|addr| format!("socks5 {addr}")),
+                    })
+                    .with_permit(permit);
+                let worker = UdpSendWorker::new(
+                    peer_addr,
+                    self.keep_alive_sender.clone(),
+                    outbound,
+                    domain.map(|domain| (target, domain)),
+                    dns_snoop,
+                    session,
+                    &self.send_back,
+                    &self.connect_opts,
+                )?;
+                match flow_domain {
+                    Some(domain) => log::debug!(
+                        "created udp association for {} -> {} ({})",
+                        peer_addr,
+                        target,
+                        domain
+                    ),
+                    None => log::debug!("created udp association for {}", peer_addr),
+                }
                 e.insert(worker)
             }
         };
@@ -87,7 +193,12 @@ where
         self.nat_map.iter();
     }
 
-    pub fn keep_alive(&mut self, peer_addr: &SocketAddr) {
-        self.nat_map.get(peer_addr);
+    pub fn keep_alive(&mut self, key: &AssociationKey<R::Outbound>) {
+        self.nat_map.get(key);
+    }
+
+    /// Check if there is no alive association
+    pub fn is_empty(&self) -> bool {
+        self.nat_map.is_empty()
     }
 }
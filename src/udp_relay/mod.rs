@@ -1,66 +1,141 @@
 use crate::{
+    dns::forward::DnsForwarder,
     redir::redir_ext::UdpSocketRedirExt,
-    udp_relay::{macos::UdpRedirSocket, manager::UdpNatManager, send::BindAddr},
-    utils::socks::BasicSocket,
+    service::Shared,
+    udp_relay::{manager::UdpNatManager, route::UdpRoute, send::SendBackOpts},
+    utils::{net::ConnectOpts, socks::BasicSocket},
 };
 use bytes::Bytes;
-use std::{io, net::SocketAddr, time::Duration};
-use tokio::time;
+use cfg_if::cfg_if;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::watch, time};
 
 pub mod checker;
-pub mod macos;
 pub mod manager;
 pub mod receive;
+pub mod route;
 pub mod send;
 
+cfg_if! {
+    if #[cfg(any(target_os = "linux", target_os = "android"))] {
+        pub mod linux;
+        pub use self::linux::UdpRedirSocket;
+    } else if #[cfg(any(target_os = "macos", target_os = "ios"))] {
+        pub mod macos;
+        pub use self::macos::UdpRedirSocket;
+    }
+}
+
 /// Default UDP association's expire duration
 const DEFAULT_UDP_EXPIRY_DURATION: Duration = Duration::from_secs(5 * 60);
 /// The maximum UDP payload size
 const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 65536;
 /// Default association expire time
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Destination port of the DNS queries, see `DnsForwarder` and `DnsSnoop`
+pub const DNS_PORT: u16 = 53;
 /// Packet size for all UDP associations' send queue
 pub const UDP_ASSOCIATION_SEND_CHANNEL_SIZE: usize = 1024;
 
-pub async fn run<S, T>(listener: UdpRedirSocket, proxy_type: T)
-where
+/// Relay the UDP packets redirected to `listener`, through the outbounds chosen by `route`
+///
+/// Packets to the fake IPs in `shared.fake_ip` are relayed to their domain names. With
+/// `dns_forwarder`, the DNS queries to port 53 are resolved by it instead. With `dns_snoop`, the
+/// DNS responses from port 53 are recorded in `shared.dns_snoop`. Each association is a session
+/// in `shared.sessions`, its outbound sockets are created with `connect_opts`.
+///
+/// Once `shutdown` is set (or its sender is dropped), `listener` and `shutdown` are dropped, so
+/// `watch::Sender::closed()` resolves when the listening address can be bound again.
+/// The existing associations keep working on their old settings until they expire.
+#[allow(clippy::too_many_arguments)]
+pub async fn run<S, R>(
+    listener: UdpRedirSocket,
+    route: R,
+    shared: Arc<Shared>,
+    dns_forwarder: Option<Arc<DnsForwarder>>,
+    dns_snoop: bool,
+    send_back: SendBackOpts,
+    connect_opts: ConnectOpts,
+    mut shutdown: watch::Receiver<bool>,
+) where
     S: BasicSocket,
-    T: BindAddr<S>,
+    R: UdpRoute<S>,
 {
     let mut pkt_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE].into_boxed_slice();
     // NOTE: use default expiry duration, it may be not the best
     let mut cleanup_timer = time::interval(DEFAULT_UDP_EXPIRY_DURATION);
-    let (mut manager, mut keepalive_rx) = UdpNatManager::new(proxy_type);
+    let listen_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            log::error!("get UDP listener address error: {}", e);
+            return;
+        }
+    };
+    let (mut manager, mut keepalive_rx) = UdpNatManager::new(
+        route,
+        listen_addr,
+        shared,
+        dns_snoop,
+        send_back,
+        connect_opts,
+    );
     loop {
         tokio::select! {
             _ = cleanup_timer.tick() => {
                 // cleanup expired associations. iter() will remove expired elements
                 manager.cleanup_expired().await;
             }
-            peer_addr_opt = keepalive_rx.recv() => {
-                let peer_addr = peer_addr_opt.expect("keep-alive channel closed unexpectly");
-                manager.keep_alive(&peer_addr);
+            key_opt = keepalive_rx.recv() => {
+                let key = key_opt.expect("keep-alive channel closed unexpectly");
+                manager.keep_alive(&key);
             }
 
             // receive the redirected udp packet
             recv_result = listener.recv_dest_from(&mut pkt_buf) => {
                 // though we can do zero copy, reuse the buffer seems more efficient
-                handle_recv_result(recv_result, &pkt_buf, &mut manager).await;
+                handle_recv_result(recv_result, &pkt_buf, &mut manager, dns_forwarder.as_ref()).await;
+            }
+
+            _ = shutdown.changed() => break,
+        }
+    }
+
+    drop(listener);
+    drop(shutdown);
+
+    // keep the associations alive until they expire, dropping a worker closes its channel
+    loop {
+        tokio::select! {
+            _ = cleanup_timer.tick() => {
+                manager.cleanup_expired().await;
+                if manager.is_empty() {
+                    break;
+                }
+            }
+            key_opt = keepalive_rx.recv() => {
+                let key = key_opt.expect("keep-alive channel closed unexpectly");
+                manager.keep_alive(&key);
             }
         }
     }
 }
 
-async fn handle_recv_result<S, T>(
+async fn handle_recv_result<S, R>(
     recv_result: io::Result<(usize, SocketAddr, SocketAddr)>,
     pkt_buf: &[u8],
-    manager: &mut UdpNatManager<T, S>,
+    manager: &mut UdpNatManager<R, S>,
+    dns_forwarder: Option<&Arc<DnsForwarder>>,
 ) where
     S: BasicSocket,
-    T: BindAddr<S>,
+    R: UdpRoute<S>,
 {
     log::trace!("recv_dest_from:");
-    let (recv_len, peer, dst) = match recv_result {
+    let (recv_len, mut peer, mut dst) = match recv_result {
         Ok(o) => o,
         Err(err) => {
             log::error!("recv_dest_from failed with err: {}", err);
@@ -92,11 +167,25 @@ async fn handle_recv_result<S, T>(
     }
 
     // Try to convert IPv4 mapped IPv6 address for dual-stack mode.
-    // if let SocketAddr::V6(a) = &dst
-    //     && let Some(v4) = a.ip().to_ipv4_mapped()
-    // {
-    //     dst = SocketAddr::new(IpAddr::from(v4), a.port());
-    // }
+    // Replies are sent from `dst` to `peer`, so both of them must be in the same address family.
+    if let SocketAddr::V6(a) = &dst
+        && let Some(v4) = a.ip().to_ipv4_mapped()
+    {
+        dst = SocketAddr::new(IpAddr::from(v4), a.port());
+    }
+    if let SocketAddr::V6(a) = &peer
+        && let Some(v4) = a.ip().to_ipv4_mapped()
+    {
+        peer = SocketAddr::new(IpAddr::from(v4), a.port());
+    }
+
+    if dst.port() == DNS_PORT
+        && let Some(forwarder) = dns_forwarder
+    {
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move { forwarder.forward(peer, dst, &pkt).await });
+        return;
+    }
 
     if let Err(err) = manager.send_to(peer, dst, pkt) {
         log::debug!(
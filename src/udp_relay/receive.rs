@@ -1,6 +1,7 @@
 use crate::{
-    udp_relay::{DEFAULT_UDP_EXPIRY_DURATION, macos::UdpRedirSocket},
-    utils::expiry_map::ExpiryMap,
+    redir::redir_ext::RedirSocketOpts,
+    udp_relay::{DEFAULT_UDP_EXPIRY_DURATION, UdpRedirSocket},
+    utils::{config::RedirType, expiry_map::ExpiryMap},
 };
 use std::{
     io,
@@ -29,19 +30,21 @@ impl UdpReceiveManager {
         }
     }
 
+    /// Send `data` to `peer_addr` from a socket bound to `remote_addr`
+    ///
+    /// Returns the number of bytes sent
     pub async fn send_to(
+        redir_ty: RedirType,
+        redir_opts: &RedirSocketOpts,
         peer_addr: SocketAddr,
         remote_addr: SocketAddr,
         data: &[u8],
-    ) -> io::Result<()> {
+    ) -> io::Result<usize> {
         let socket = if let Some(socket) = CONTEXT.nat_map.get_mut(&remote_addr) {
             // clone the socket here to avoid awaiting with a lock(very dangerous, may cause deadlock)
             socket.clone()
         } else {
-            let socket = UdpRedirSocket::bind_nonlocal(
-                crate::utils::config::RedirType::PacketFilter,
-                remote_addr,
-            )?;
+            let socket = UdpRedirSocket::bind_nonlocal(redir_ty, remote_addr, redir_opts)?;
             let socket = Arc::new(socket);
             CONTEXT.nat_map.insert(remote_addr, socket.clone());
             socket
@@ -63,10 +66,39 @@ impl UdpReceiveManager {
             remote_addr,
             peer_addr,
         );
-        Ok(())
+        Ok(n)
     }
 
     pub async fn cleanup_expired() {
         CONTEXT.nat_map.cleanup_expired();
     }
 }
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_send_to_nonlocal() {
+    use std::net::Ipv4Addr;
+    use tokio::net::UdpSocket;
+
+    let peer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let remote_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 52345);
+
+    let buf = b"hello nonlocal socket";
+    let n = UdpReceiveManager::send_to(
+        RedirType::TProxy,
+        &RedirSocketOpts::default(),
+        peer_addr,
+        remote_addr,
+        buf,
+    )
+    .await
+    .unwrap();
+    assert_eq!(n, buf.len());
+
+    let mut recv_buf = [0u8; 64];
+    let (recv_n, addr) = peer.recv_from(&mut recv_buf).await.unwrap();
+    assert_eq!(&recv_buf[..recv_n], buf);
+    assert_eq!(addr, remote_addr);
+}
@@ -0,0 +1,171 @@
+//! Choosing the outbound of the redirected UDP packets
+
+use crate::{
+    router::{Flow, Outbound, Protocol},
+    udp_relay::send::{BindAddr, Direct, Proxy},
+    utils::{
+        config::{Config, UpstreamProtocol},
+        net::ConnectOpts,
+        socks::{BasicSocket, udp_client::Socks5UdpClient},
+    },
+};
+use arc_swap::ArcSwap;
+use std::{fmt, io, net::SocketAddr, sync::Arc};
+use tokio::net::UdpSocket;
+
+/// Chooses the outbound of the UDP packets
+pub trait UdpRoute<S: BasicSocket>: Send + Sync + 'static {
+    type Outbound: BindAddr<S>;
+
+    /// Outbound of the packets from `peer_addr` to `target`, `None` if they should be dropped
+    ///
+    /// `domain` is the domain name of `target` if it is a fake IP.
+    fn route(
+        &self,
+        peer_addr: SocketAddr,
+        target: SocketAddr,
+        domain: Option<&str>,
+    ) -> Option<Self::Outbound>;
+}
+
+impl UdpRoute<UdpSocket> for Direct {
+    type Outbound = Direct;
+
+    fn route(
+        &self,
+        _peer_addr: SocketAddr,
+        _target: SocketAddr,
+        _: Option<&str>,
+    ) -> Option<Direct> {
+        Some(Direct)
+    }
+}
+
+impl UdpRoute<Socks5UdpClient> for Proxy {
+    type Outbound = Proxy;
+
+    fn route(&self, _peer_addr: SocketAddr, _target: SocketAddr, _: Option<&str>) -> Option<Proxy> {
+        Some(self.clone())
+    }
+}
+
+/// Outbound chosen by the routing rules of the current config, see `Config::route`
+#[derive(Clone)]
+pub struct Routed {
+    pub config: Arc<ArcSwap<Config>>,
+    pub listen_addr: SocketAddr,
+}
+
+/// `Direct` or a SOCKS5 `Proxy`, chosen by `Routed`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RoutedOutbound {
+    Direct(Direct),
+    Proxy(Proxy),
+}
+
+impl fmt::Display for RoutedOutbound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutedOutbound::Direct(direct) => direct.fmt(f),
+            RoutedOutbound::Proxy(proxy) => proxy.fmt(f),
+        }
+    }
+}
+
+/// Socket of `RoutedOutbound`
+pub enum RoutedSocket {
+    Direct(UdpSocket),
+    Proxy(Socks5UdpClient),
+}
+
+impl UdpRoute<RoutedSocket> for Routed {
+    type Outbound = RoutedOutbound;
+
+    fn route(
+        &self,
+        peer_addr: SocketAddr,
+        target: SocketAddr,
+        domain: Option<&str>,
+    ) -> Option<RoutedOutbound> {
+        let config = self.config.load();
+        let listener = config
+            .listeners
+            .iter()
+            .find(|l| l.addr == self.listen_addr)?;
+        let flow = Flow {
+            protocol: Protocol::Udp,
+            src: peer_addr,
+            dst: target,
+            domain,
+        };
+        match config.route(listener, &flow) {
+            Outbound::Direct => Some(RoutedOutbound::Direct(Direct)),
+            Outbound::Reject => None,
+            Outbound::Proxy(name) => match config.upstreams.get(&name) {
+                Some(upstream) if upstream.protocol == UpstreamProtocol::Socks5 => {
+                    let auth = upstream.auth.clone().map(Arc::new);
+                    Some(RoutedOutbound::Proxy(Proxy(upstream.addr, auth)))
+                }
+                // HTTP CONNECT can not carry UDP
+                _ => None,
+            },
+        }
+    }
+}
+
+impl BindAddr<RoutedSocket> for RoutedOutbound {
+    async fn bind(
+        &self,
+        bind_addr: SocketAddr,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<RoutedSocket> {
+        match self {
+            RoutedOutbound::Direct(direct) => direct
+                .bind(bind_addr, connect_opts)
+                .await
+                .map(RoutedSocket::Direct),
+            RoutedOutbound::Proxy(proxy) => proxy
+                .bind(bind_addr, connect_opts)
+                .await
+                .map(RoutedSocket::Proxy),
+        }
+    }
+
+    fn upstream(&self) -> Option<SocketAddr> {
+        match self {
+            RoutedOutbound::Direct(direct) => direct.upstream(),
+            RoutedOutbound::Proxy(proxy) => proxy.upstream(),
+        }
+    }
+}
+
+impl BasicSocket for RoutedSocket {
+    async fn send_to<A>(&self, buf: &[u8], addr: A) -> io::Result<usize>
+    where
+        SocketAddr: From<A>,
+    {
+        let target = SocketAddr::from(addr);
+        match self {
+            RoutedSocket::Direct(socket) => {
+                BasicSocket::send_to::<SocketAddr>(socket, buf, target).await
+            }
+            RoutedSocket::Proxy(socket) => {
+                BasicSocket::send_to::<SocketAddr>(socket, buf, target).await
+            }
+        }
+    }
+
+    async fn send_to_domain(&self, buf: &[u8], domain: &str, port: u16) -> io::Result<usize> {
+        match self {
+            RoutedSocket::Direct(socket) => socket.send_to_domain(buf, domain, port).await,
+            RoutedSocket::Proxy(socket) => socket.send_to_domain(buf, domain, port).await,
+        }
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            RoutedSocket::Direct(socket) => BasicSocket::recv_from(socket, buf).await,
+            RoutedSocket::Proxy(socket) => BasicSocket::recv_from(socket, buf).await,
+        }
+    }
+}
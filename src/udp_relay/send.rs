@@ -1,43 +1,130 @@
 use crate::{
-    udp_relay::{MAXIMUM_UDP_PAYLOAD_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE, checker::Checker},
+    dns::snoop::DnsSnoop,
+    metrics::{self, METRICS},
+    redir::redir_ext::RedirSocketOpts,
+    session::{CloseReason, SessionGuard},
+    udp_relay::{
+        DNS_PORT, MAXIMUM_UDP_PAYLOAD_SIZE, UDP_ASSOCIATION_SEND_CHANNEL_SIZE, checker::Checker,
+        receive::UdpReceiveManager,
+    },
     utils::{
+        config::{Credentials, RedirType, UdpSendBackType},
+        net::{self, ConnectOpts},
         raw_socket::RawSocket,
         socks::{BasicSocket, udp_client::Socks5UdpClient},
     },
 };
 use bytes::Bytes;
 use std::{
-    io,
-    net::{Ipv4Addr, SocketAddr},
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
     net::UdpSocket,
-    sync::mpsc::{self, Receiver},
+    sync::mpsc::{self, Receiver, error::TrySendError},
     task::JoinHandle,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Direct;
-#[derive(Debug, Clone, Copy)]
-pub struct Proxy(pub SocketAddr);
+/// SOCKS5 server, and the credentials for it
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Proxy(pub SocketAddr, pub Option<Arc<Credentials>>);
+
+impl fmt::Display for Direct {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("direct")
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "socks5 {}", self.0)
+    }
+}
+
+/// Outbound of UDP associations, associations of a client are kept for each outbound
+pub trait BindAddr<S: BasicSocket>: Send + Sync + 'static + Clone + Ord + fmt::Display {
+    /// Socket bound to `bind_addr` with `connect_opts`, see `net::bind_udp`
+    fn bind(
+        &self,
+        bind_addr: SocketAddr,
+        connect_opts: &ConnectOpts,
+    ) -> impl Future<Output = io::Result<S>> + Send;
 
-pub trait BindAddr<S: BasicSocket>: Send + Sync + 'static + Copy {
-    fn bind(&self, bind_addr: SocketAddr) -> impl Future<Output = io::Result<S>> + Send;
+    /// Address of the SOCKS5 server, `None` if the packets are sent directly
+    fn upstream(&self) -> Option<SocketAddr>;
 }
 
 impl BindAddr<UdpSocket> for Direct {
-    async fn bind(&self, bind_addr: SocketAddr) -> io::Result<UdpSocket> {
-        UdpSocket::bind(bind_addr).await
+    async fn bind(
+        &self,
+        bind_addr: SocketAddr,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<UdpSocket> {
+        net::bind_udp(bind_addr, connect_opts)
+    }
+
+    fn upstream(&self) -> Option<SocketAddr> {
+        None
     }
 }
 
 impl BindAddr<Socks5UdpClient> for Proxy {
-    async fn bind(&self, bind_addr: SocketAddr) -> io::Result<Socks5UdpClient> {
-        let mut socket = Socks5UdpClient::bind(bind_addr).await?;
-        socket.associate(self.0).await?;
+    async fn bind(
+        &self,
+        _bind_addr: SocketAddr,
+        connect_opts: &ConnectOpts,
+    ) -> io::Result<Socks5UdpClient> {
+        // the socket only talks to the SOCKS5 server, targets are carried in the UDP header
+        let socket = net::bind_udp(unspecified_addr(&self.0), connect_opts)?;
+        let mut socket = Socks5UdpClient::new(socket);
+        let stream = net::connect_tcp(self.0, connect_opts).await?;
+        socket.associate_on(stream, self.1.as_deref()).await?;
         Ok(socket)
     }
+
+    fn upstream(&self) -> Option<SocketAddr> {
+        Some(self.0)
+    }
+}
+
+/// Key of an association: the client, the outbound, and the fake IP destination if the
+/// association is dedicated to it
+pub type AssociationKey<T> = (SocketAddr, T, Option<SocketAddr>);
+
+/// Options for sending UDP packets back to the redirected clients
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendBackOpts {
+    /// How the packets are sent back
+    pub ty: UdpSendBackType,
+    /// Transparent proxy type of the listener, used by `UdpSendBackType::NonLocal`
+    pub redir_ty: RedirType,
+    /// Options for the non-local send-back sockets
+    pub redir_opts: RedirSocketOpts,
+    /// MTU of the raw socket, packets larger than it are fragmented. Defaults to 1500
+    pub mtu: Option<usize>,
+}
+
+impl SendBackOpts {
+    pub fn new(redir_ty: RedirType) -> Self {
+        Self {
+            ty: UdpSendBackType::default(),
+            redir_ty,
+            redir_opts: RedirSocketOpts::default(),
+            mtu: None,
+        }
+    }
+}
+
+/// Unspecified address `0.0.0.0:0` or `[::]:0` with the same family as `addr`
+fn unspecified_addr(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(..) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(..) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
 }
 
 pub struct UdpSendWorker {
@@ -46,13 +133,33 @@ pub struct UdpSendWorker {
 }
 
 impl UdpSendWorker {
+    /// Association of `peer_addr` through `proxy_type`
+    ///
+    /// With `fake_target`, a fake IP destination and its domain name, the packets are sent to the
+    /// domain, and the replies are sent back from the fake IP. The DNS responses are recorded in
+    /// `dns_snoop` if given. The worker stops when `session` is closed.
+    #[allow(clippy::too_many_arguments)]
     pub fn new<S: BasicSocket, T: BindAddr<S>>(
         peer_addr: SocketAddr,
-        keep_alive_sender: mpsc::Sender<SocketAddr>,
+        keep_alive_sender: mpsc::Sender<AssociationKey<T>>,
         proxy_type: T,
+        fake_target: Option<(SocketAddr, String)>,
+        dns_snoop: Option<Arc<DnsSnoop>>,
+        session: SessionGuard,
+        send_back: &SendBackOpts,
+        connect_opts: &ConnectOpts,
     ) -> io::Result<Self> {
         let (sender, receiver) = mpsc::channel(UDP_ASSOCIATION_SEND_CHANNEL_SIZE);
-        let mut dispatcher = Dispatcher::new(peer_addr, keep_alive_sender, proxy_type)?;
+        let mut dispatcher = Dispatcher::new(
+            peer_addr,
+            keep_alive_sender,
+            proxy_type,
+            fake_target,
+            dns_snoop,
+            session,
+            send_back,
+            connect_opts,
+        )?;
         let worker_handle = tokio::spawn(async move {
             dispatcher.dispatch_packet(receiver).await;
         });
@@ -62,9 +169,12 @@ impl UdpSendWorker {
         })
     }
     pub fn send_to(&self, target: SocketAddr, data: Bytes) -> io::Result<()> {
-        self.sender
-            .try_send((target, data))
-            .map_err(io::Error::other)
+        self.sender.try_send((target, data)).map_err(|e| {
+            if let TrySendError::Full(..) = e {
+                METRICS.inc_udp_queue_full();
+            }
+            io::Error::other(e)
+        })
     }
 
     pub fn worker_handle(&self) -> &JoinHandle<()> {
@@ -72,37 +182,100 @@ impl UdpSendWorker {
     }
 }
 
+/// Sender of the packets from servers to the client
+pub enum ServerToClient {
+    /// Hand-built packets through a raw socket
+    RawSocket(RawSocket),
+    /// Sockets bound to the remote addresses, shared by `UdpReceiveManager`
+    NonLocal(RedirType, RedirSocketOpts),
+}
+
+impl ServerToClient {
+    /// Sender to the clients in the address family of `peer_addr`
+    pub fn new(peer_addr: &SocketAddr, send_back: &SendBackOpts) -> io::Result<Self> {
+        match send_back.ty {
+            UdpSendBackType::RawSocket => {
+                let mut raw = RawSocket::for_addr(peer_addr)
+                    .inspect_err(|_| log::error!("Can not create raw socket!"))?;
+                if let Some(mtu) = send_back.mtu {
+                    raw.set_mtu(mtu);
+                }
+                Ok(ServerToClient::RawSocket(raw))
+            }
+            UdpSendBackType::NonLocal => Ok(ServerToClient::NonLocal(
+                send_back.redir_ty,
+                send_back.redir_opts.clone(),
+            )),
+        }
+    }
+
+    /// Send `data` to `peer_addr` from `remote_addr`, returns the number of bytes sent
+    pub async fn send_to(
+        &self,
+        remote_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        match self {
+            ServerToClient::RawSocket(socket) => socket
+                .send_to(remote_addr, peer_addr, data)
+                .await
+                .inspect_err(|_| METRICS.inc_raw_socket_send_error()),
+            ServerToClient::NonLocal(redir_ty, redir_opts) => {
+                UdpReceiveManager::send_to(*redir_ty, redir_opts, peer_addr, remote_addr, data)
+                    .await
+            }
+        }
+    }
+}
+
 // the servers and clients are N:N, we may need more sockets
 struct Dispatcher<S: BasicSocket, T: BindAddr<S>> {
     peer_addr: SocketAddr,
     client_to_server: Option<S>,
-    server_to_client: RawSocket,
-    keep_alive_sender: mpsc::Sender<SocketAddr>,
+    server_to_client: ServerToClient,
+    /// Options for the outbound sockets
+    connect_opts: ConnectOpts,
+    keep_alive_sender: mpsc::Sender<AssociationKey<T>>,
     buffer: Box<[u8]>,
     proxy_type: T,
+    /// Fake IP destination and its domain name
+    fake_target: Option<(SocketAddr, String)>,
+    dns_snoop: Option<Arc<DnsSnoop>>,
+    session: SessionGuard,
 }
 
 impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         peer_addr: SocketAddr,
-        keep_alive_sender: mpsc::Sender<SocketAddr>,
+        keep_alive_sender: mpsc::Sender<AssociationKey<T>>,
         proxy_type: T,
+        fake_target: Option<(SocketAddr, String)>,
+        dns_snoop: Option<Arc<DnsSnoop>>,
+        session: SessionGuard,
+        send_back: &SendBackOpts,
+        connect_opts: &ConnectOpts,
     ) -> io::Result<Self> {
         let buffer = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE].into_boxed_slice();
-        let server_to_client =
-            RawSocket::new().inspect_err(|_| log::error!("Can not create raw socket!"))?;
+        let server_to_client = ServerToClient::new(&peer_addr, send_back)?;
         Ok(Self {
             peer_addr,
             client_to_server: None,
             server_to_client,
+            connect_opts: connect_opts.clone(),
             keep_alive_sender,
             buffer,
             proxy_type,
+            fake_target,
+            dns_snoop,
+            session,
         })
     }
 
     async fn dispatch_packet(&mut self, mut receiver: Receiver<(SocketAddr, Bytes)>) {
         let mut checker = Checker::new(Duration::from_secs(1));
+        let session = self.session.session().clone();
         loop {
             tokio::select! {
                 // 1. receive and send packets to server
@@ -112,6 +285,7 @@ impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
                         Some(d) => d,
                         None => {
                             log::trace!("udp association for {} -> ... channel closed", self.peer_addr);
+                            session.set_close_reason(CloseReason::Expired, None);
                             break;
                         }
                     };
@@ -131,10 +305,20 @@ impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
                     checker.activate();
                     self.handle_server_packets(remote_addr, n).await;
                 }
-                // 3. keep-alive check
+                // 3. closed by the admin API or the shutdown
+                _ = session.closed() => {
+                    log::debug!("udp association {} is closed ({})", session.id, session.close_reason().0);
+                    break;
+                }
+                // 4. keep-alive check
                 _ = checker.wait() => {
                     log::trace!("send keep alive msg");
-                    if self.keep_alive_sender.try_send(self.peer_addr).is_err() {
+                    let fake_addr = self.fake_target.as_ref().map(|(addr, _)| *addr);
+                    if self
+                        .keep_alive_sender
+                        .try_send((self.peer_addr, self.proxy_type.clone(), fake_addr))
+                        .is_err()
+                    {
                         log::debug!("udp relay {} keep-alive failed, channel full or closed", self.peer_addr);
                         checker.activate();
                     }
@@ -169,13 +353,28 @@ impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
         let socket = match &mut self.client_to_server {
             Some(socket) => socket,
             None => {
-                // create a new socket
-                let bind_addr: SocketAddr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
-                let socket = self.proxy_type.bind(bind_addr).await?;
+                // create a new socket, with the same address family as the target
+                let bind_addr = unspecified_addr(&target_addr);
+                let socket = self
+                    .proxy_type
+                    .bind(bind_addr, &self.connect_opts)
+                    .await
+                    .inspect_err(|e| {
+                        let outbound = self.proxy_type.to_string();
+                        METRICS.inc_connect_failure(&outbound, metrics::io_failure_reason(e));
+                    })?;
                 self.client_to_server.insert(socket)
             }
         };
-        let n = socket.send_to(data, target_addr).await?;
+        let n = match self.fake_target {
+            Some((_, ref domain)) => {
+                socket
+                    .send_to_domain(data, domain, target_addr.port())
+                    .await?
+            }
+            None => socket.send_to(data, target_addr).await?,
+        };
+        self.session.add_uploaded(n);
         if n != data.len() {
             log::warn!(
                 "{} -> {} sent {} bytes != expected {} bytes",
@@ -202,6 +401,11 @@ impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
             target_addr,
             recv_len
         );
+        if target_addr.port() == DNS_PORT
+            && let Some(ref snoop) = self.dns_snoop
+        {
+            snoop.record(&self.buffer[..recv_len]);
+        }
         if let Err(e) = self.send_server_packets(target_addr, recv_len).await {
             log::error!(
                 "udp relay {} <- {} with {} bytes, error: {}",
@@ -218,10 +422,17 @@ impl<S: BasicSocket, T: BindAddr<S>> Dispatcher<S, T> {
         remote_addr: SocketAddr,
         recv_len: usize,
     ) -> io::Result<()> {
+        let data = &self.buffer[..recv_len];
+        // the client only knows the fake IP
+        let remote_addr = match self.fake_target {
+            Some((fake_addr, _)) => fake_addr,
+            None => remote_addr,
+        };
         let n = self
             .server_to_client
-            .send_to(remote_addr, self.peer_addr, &self.buffer[..recv_len])
+            .send_to(remote_addr, self.peer_addr, data)
             .await?;
+        self.session.add_downloaded(n);
         if n != recv_len {
             log::warn!(
                 "udp relay {} <- {} with {} bytes != expected {} bytes",
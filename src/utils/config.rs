@@ -1,9 +1,27 @@
 //! modified from shadowsocks-service/src/config.rs
 
+use crate::{
+    redir::redir_ext::RedirSocketOpts,
+    router::{
+        Flow, Outbound, Protocol, Router, Rule,
+        domain::{DomainMatcher, DomainMatcherBuilder},
+        geoip::GeoIpDatabase,
+    },
+    utils::net::{AcceptOpts, ConnectOpts},
+};
 use cfg_if::cfg_if;
+use ipnet::IpNet;
+use serde::Deserialize;
 use std::{
+    collections::HashMap,
     fmt::{self, Debug, Display, Formatter},
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
 /// Transparent Proxy type
@@ -246,3 +264,1542 @@ impl FromStr for RedirType {
         }
     }
 }
+
+/// The way UDP packets from remote servers are sent back to the redirected clients
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UdpSendBackType {
+    /// Build IP and UDP headers manually and send them through a raw socket (`IP_HDRINCL`)
+    ///
+    /// Works on every platform, but requires raw socket privilege
+    RawSocket,
+
+    /// Send through an ordinary UDP socket bound to the (non-local) remote address
+    ///
+    /// The kernel builds the headers, so checksums, IPv6 and fragmentation are handled properly.
+    /// Requires `IP_TRANSPARENT` on Linux.
+    NonLocal,
+}
+
+impl UdpSendBackType {
+    cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            /// Default UDP send-back solution on this platform
+            pub const fn platform_default() -> UdpSendBackType {
+                UdpSendBackType::NonLocal
+            }
+        } else {
+            /// Default UDP send-back solution on this platform
+            pub const fn platform_default() -> UdpSendBackType {
+                UdpSendBackType::RawSocket
+            }
+        }
+    }
+
+    /// Name of UDP send-back type
+    pub const fn name(self) -> &'static str {
+        match self {
+            UdpSendBackType::RawSocket => "raw",
+            UdpSendBackType::NonLocal => "nonlocal",
+        }
+    }
+}
+
+impl Default for UdpSendBackType {
+    fn default() -> Self {
+        UdpSendBackType::platform_default()
+    }
+}
+
+impl Display for UdpSendBackType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Error type for `UdpSendBackType`'s `FromStr::Err`
+#[derive(Debug)]
+pub struct InvalidUdpSendBackType;
+
+impl Display for InvalidUdpSendBackType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid UdpSendBackType")
+    }
+}
+
+impl FromStr for UdpSendBackType {
+    type Err = InvalidUdpSendBackType;
+
+    fn from_str(s: &str) -> Result<UdpSendBackType, InvalidUdpSendBackType> {
+        match s {
+            "raw" => Ok(UdpSendBackType::RawSocket),
+            "nonlocal" => Ok(UdpSendBackType::NonLocal),
+            _ => Err(InvalidUdpSendBackType),
+        }
+    }
+}
+
+/// How a listener relays the redirected traffic
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// Forward through the listener's upstream proxy
+    Proxy,
+    /// Connect to the original destination directly
+    Direct,
+    /// Choose the outbound of each flow by the routing rules
+    Rule,
+}
+
+/// Protocol of an upstream proxy
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    /// HTTP proxy with `CONNECT` method, TCP only
+    Http,
+    /// SOCKS5 proxy, `CONNECT` for TCP and `UDP ASSOCIATE` for UDP
+    Socks5,
+}
+
+impl Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            UpstreamProtocol::Http => f.write_str("http"),
+            UpstreamProtocol::Socks5 => f.write_str("socks5"),
+        }
+    }
+}
+
+/// Username and password for authenticating to an upstream proxy
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"********")
+            .finish()
+    }
+}
+
+/// Upstream proxy definition
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpstreamConfig {
+    pub protocol: UpstreamProtocol,
+    pub addr: SocketAddr,
+    pub auth: Option<Credentials>,
+}
+
+/// A transparent proxy listener, serving both TCP and UDP on `addr`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub tcp_redir: RedirType,
+    pub udp_redir: RedirType,
+    pub mode: ListenerMode,
+    /// Name of the upstream for TCP connections, required in `ListenerMode::Proxy`
+    pub upstream: Option<String>,
+    /// Name of the SOCKS5 upstream for UDP packets, defaults to `upstream` if it is a SOCKS5 proxy.
+    /// UDP is not served in `ListenerMode::Proxy` without it
+    pub udp_upstream: Option<String>,
+    /// Sniff the domain names of TCP connections from TLS SNI or HTTP `Host` header, which are
+    /// sent to the upstreams and matched by the routing rules
+    pub sniff: bool,
+    /// Resolve the UDP DNS queries to port 53 over TCP through the outbound of the listener,
+    /// instead of relaying them. UDP is served for them even without `udp_upstream`
+    pub dns_forward: bool,
+    /// DNS server of the forwarded queries, their original destination by default
+    pub dns_server: Option<SocketAddr>,
+    /// Learn the domain names of the IPs from the DNS responses relayed by the listener, which
+    /// are used for its TCP connections like the sniffed ones
+    pub dns_snoop: bool,
+}
+
+/// Built-in DNS server answering with fake IPs, see `crate::dns`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DnsConfig {
+    /// Address of the UDP and TCP DNS listeners
+    pub addr: SocketAddr,
+    /// IPv4 network of the fake IPs
+    pub fake_ip_range: IpNet,
+    /// IPv6 network of the fake IPs, AAAA queries get empty answers without it
+    pub fake_ip6_range: Option<IpNet>,
+    /// TTL of the answers
+    pub ttl: u32,
+}
+
+impl DnsConfig {
+    /// Reserved for benchmarking (RFC 2544), and not routed on the Internet
+    pub const DEFAULT_FAKE_IP_RANGE: &str = "198.18.0.0/15";
+    /// Short, so the clients query again soon after a restart lost the fake IPs
+    pub const DEFAULT_TTL: u32 = 1;
+}
+
+/// Listening address of the admin API
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AdminAddr {
+    Tcp(SocketAddr),
+    /// Path of a Unix socket
+    Unix(PathBuf),
+}
+
+impl Display for AdminAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AdminAddr::Tcp(addr) => Display::fmt(addr, f),
+            AdminAddr::Unix(path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+/// Local admin API, see `crate::admin`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdminConfig {
+    pub addr: AdminAddr,
+}
+
+/// Where the access log is written
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AccessLogTarget {
+    Stdout,
+    File(PathBuf),
+}
+
+impl Display for AccessLogTarget {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            AccessLogTarget::Stdout => f.write_str("stdout"),
+            AccessLogTarget::File(path) => Display::fmt(&path.display(), f),
+        }
+    }
+}
+
+/// Record format of the access log
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// A JSON object per line
+    #[default]
+    Json,
+    /// `key=value` pairs per line
+    Logfmt,
+}
+
+/// Access log of the finished sessions, see `crate::access_log`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccessLogConfig {
+    pub target: AccessLogTarget,
+    pub format: AccessLogFormat,
+    /// The file is rotated once it grows over this size, bytes. 0 disables rotation
+    pub max_size: u64,
+    /// Number of the rotated files kept, `<path>.1` is the latest
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    pub const DEFAULT_MAX_SIZE_MB: u64 = 100;
+    pub const DEFAULT_MAX_FILES: usize = 5;
+}
+
+/// Service configuration
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    /// Options for the inbound sockets
+    pub accept_opts: AcceptOpts,
+    /// How UDP packets are sent back to the redirected clients
+    pub udp_send_back: UdpSendBackType,
+    /// Options for the UDP send-back sockets and the outbound sockets
+    pub redir_opts: RedirSocketOpts,
+    /// Routing rules of the listeners in `ListenerMode::Rule`
+    pub router: Router,
+    /// Built-in DNS server, disabled if `None`
+    pub dns: Option<DnsConfig>,
+    /// Admin API, disabled if `None`
+    pub admin: Option<AdminConfig>,
+    /// Access log, disabled if `None`
+    pub access_log: Option<AccessLogConfig>,
+    /// How long the TCP sessions may take to finish on shutdown, see `Service::shutdown`
+    pub shutdown_timeout: Duration,
+    /// Target of `RLIMIT_NOFILE` on startup, the hard limit if `None`
+    pub max_open_files: Option<u64>,
+    /// Timeouts of the TCP sessions
+    pub tcp_timeouts: TcpTimeouts,
+}
+
+/// Timeouts of a TCP session, taken when the session starts
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TcpTimeouts {
+    /// Connecting to the destination or the upstream
+    pub connect: Duration,
+    /// The `CONNECT` or SOCKS5 handshake with the upstream, after connected
+    pub handshake: Duration,
+    /// No data in either direction, unlimited if `None`
+    pub idle: Option<Duration>,
+    /// After one side sends FIN, how long the other side may keep sending, unlimited if `None`
+    pub half_close: Option<Duration>,
+}
+
+impl TcpTimeouts {
+    pub const DEFAULT_CONNECT: Duration = Duration::from_secs(10);
+    pub const DEFAULT_HANDSHAKE: Duration = Duration::from_secs(10);
+    pub const DEFAULT_IDLE: Duration = Duration::from_secs(3600);
+    pub const DEFAULT_HALF_CLOSE: Duration = Duration::from_secs(60);
+}
+
+impl Default for TcpTimeouts {
+    fn default() -> Self {
+        TcpTimeouts {
+            connect: TcpTimeouts::DEFAULT_CONNECT,
+            handshake: TcpTimeouts::DEFAULT_HANDSHAKE,
+            idle: Some(TcpTimeouts::DEFAULT_IDLE),
+            half_close: Some(TcpTimeouts::DEFAULT_HALF_CLOSE),
+        }
+    }
+}
+
+/// Config file format
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigType {
+    Toml,
+    Json,
+}
+
+impl ConfigType {
+    /// Guess the format by file extension, TOML by default
+    pub fn from_path<P: AsRef<Path>>(path: P) -> ConfigType {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("json") => ConfigType::Json,
+            _ => ConfigType::Toml,
+        }
+    }
+}
+
+/// Error while loading config
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{0}")]
+    IoError(#[from] io::Error),
+    #[error("{0}")]
+    TomlError(#[from] toml::de::Error),
+    #[error("{0}")]
+    JsonError(#[from] serde_json::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// Config file layout, validated and converted into `Config`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSConfig {
+    listeners: Vec<SSListenerConfig>,
+    #[serde(default)]
+    upstreams: HashMap<String, SSUpstreamConfig>,
+    #[serde(default)]
+    tcp: SSTcpConfig,
+    #[serde(default)]
+    udp: SSUdpConfig,
+    #[serde(default)]
+    ipv6_only: bool,
+    #[serde(default)]
+    routing: SSRoutingConfig,
+    dns: Option<SSDnsConfig>,
+    admin: Option<SSAdminConfig>,
+    access_log: Option<SSAccessLogConfig>,
+    /// Seconds
+    shutdown_timeout: Option<u64>,
+    max_open_files: Option<u64>,
+    /// `SO_MARK` of the outbound and send-back sockets
+    fwmark: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSListenerConfig {
+    address: SocketAddr,
+    tcp_redir: Option<String>,
+    udp_redir: Option<String>,
+    mode: ListenerMode,
+    upstream: Option<String>,
+    udp_upstream: Option<String>,
+    #[serde(default)]
+    sniff: bool,
+    #[serde(default)]
+    dns_forward: bool,
+    dns_server: Option<SocketAddr>,
+    #[serde(default)]
+    dns_snoop: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSUpstreamConfig {
+    protocol: UpstreamProtocol,
+    address: SocketAddr,
+    username: Option<String>,
+    password: Option<String>,
+    /// Read username from this environment variable
+    username_env: Option<String>,
+    /// Read password from this environment variable
+    password_env: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSTcpConfig {
+    nodelay: Option<bool>,
+    fastopen: Option<bool>,
+    mptcp: Option<bool>,
+    /// Seconds
+    keepalive: Option<u64>,
+    send_buffer_size: Option<u32>,
+    recv_buffer_size: Option<u32>,
+    /// Seconds
+    connect_timeout: Option<u64>,
+    /// Seconds
+    handshake_timeout: Option<u64>,
+    /// Seconds, 0 disables it
+    idle_timeout: Option<u64>,
+    /// Seconds, 0 disables it
+    half_close_timeout: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSUdpConfig {
+    mtu: Option<usize>,
+    allow_fragmentation: Option<bool>,
+    send_back: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSRoutingConfig {
+    #[serde(default)]
+    rules: Vec<SSRuleConfig>,
+    /// Outbound of the flows matching no rule, `direct` by default
+    default: Option<String>,
+    /// MaxMind DB for the `country` conditions, e.g. GeoLite2-Country.mmdb
+    country_db: Option<PathBuf>,
+    /// MaxMind DB for the `asn` conditions, e.g. GeoLite2-ASN.mmdb
+    asn_db: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSDnsConfig {
+    address: SocketAddr,
+    /// `198.18.0.0/15` by default
+    fake_ip_range: Option<String>,
+    fake_ip6_range: Option<String>,
+    ttl: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSAdminConfig {
+    /// `127.0.0.1:9090`, or an absolute path of a Unix socket
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSAccessLogConfig {
+    /// A file path, or `-` for stdout
+    path: String,
+    #[serde(default)]
+    format: AccessLogFormat,
+    /// MiB
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SSRuleConfig {
+    /// CIDRs or IP addresses
+    #[serde(default)]
+    dst: Vec<String>,
+    #[serde(default)]
+    ports: Vec<SSPortRange>,
+    /// CIDRs or IP addresses
+    #[serde(default)]
+    src: Vec<String>,
+    protocol: Option<Protocol>,
+    /// ISO 3166-1 country codes of the destination
+    #[serde(default)]
+    country: Vec<String>,
+    /// Autonomous system numbers of the destination
+    #[serde(default)]
+    asn: Vec<u32>,
+    /// Domain names sniffed from the flows, matching their subdomains too
+    #[serde(default)]
+    domain: Vec<String>,
+    /// Domain names matching exactly, not their subdomains
+    #[serde(default)]
+    domain_full: Vec<String>,
+    /// Keywords contained in the domain names
+    #[serde(default)]
+    domain_keyword: Vec<String>,
+    /// Regular expressions matching the domain names
+    #[serde(default)]
+    domain_regex: Vec<String>,
+    /// Domain list files, one rule per line, e.g. `full:www.example.com`
+    #[serde(default)]
+    domain_list: Vec<PathBuf>,
+    /// gfwlist files, AdBlock syntax encoded with base64
+    #[serde(default)]
+    gfwlist: Vec<PathBuf>,
+    /// `direct`, `reject`, or the name of an upstream
+    outbound: String,
+}
+
+/// A port `443`, or a range `"8000-9000"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SSPortRange {
+    Port(u16),
+    Range(String),
+}
+
+impl Config {
+    pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Default inbound socket options
+    pub fn default_accept_opts() -> AcceptOpts {
+        let mut accept_opts = AcceptOpts::default();
+        accept_opts.tcp.fastopen = true;
+        accept_opts.tcp.nodelay = true;
+        accept_opts.tcp.mptcp = true;
+        accept_opts
+    }
+
+    /// Options of the outbound sockets, the TCP and UDP options are shared with the listeners
+    pub fn connect_opts(&self) -> ConnectOpts {
+        ConnectOpts {
+            tcp: self.accept_opts.tcp.clone(),
+            udp: self.accept_opts.udp.clone(),
+            redir_opts: self.redir_opts.clone(),
+        }
+    }
+
+    /// Load config from file, the format is decided by the extension
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(&path)?;
+        Config::load_from_str(&content, ConfigType::from_path(path))
+    }
+
+    /// Load config from string
+    pub fn load_from_str(s: &str, config_type: ConfigType) -> Result<Config, ConfigError> {
+        let ssconfig: SSConfig = match config_type {
+            ConfigType::Toml => toml::from_str(s)?,
+            ConfigType::Json => serde_json::from_str(s)?,
+        };
+        Config::from_ssconfig(ssconfig)
+    }
+
+    fn from_ssconfig(ssconfig: SSConfig) -> Result<Config, ConfigError> {
+        let mut upstreams = HashMap::with_capacity(ssconfig.upstreams.len());
+        for (name, upstream) in ssconfig.upstreams {
+            if Outbound::is_reserved_name(&name) {
+                return Err(ConfigError::Invalid(format!(
+                    "upstream name \"{name}\" is reserved"
+                )));
+            }
+            let username =
+                read_secret(&name, "username", upstream.username, upstream.username_env)?;
+            let password =
+                read_secret(&name, "password", upstream.password, upstream.password_env)?;
+            let auth = match (username, password) {
+                (Some(username), Some(password)) => Some(Credentials { username, password }),
+                (None, None) => None,
+                _ => {
+                    return Err(ConfigError::Invalid(format!(
+                        "upstream \"{name}\": username and password must be set together"
+                    )));
+                }
+            };
+
+            let upstream = UpstreamConfig {
+                protocol: upstream.protocol,
+                addr: upstream.address,
+                auth,
+            };
+            upstream.check(&name)?;
+            upstreams.insert(name, upstream);
+        }
+
+        let mut listeners = Vec::with_capacity(ssconfig.listeners.len());
+        for listener in ssconfig.listeners {
+            let tcp_redir = parse_redir_type(
+                listener.tcp_redir.as_deref(),
+                RedirType::tcp_default(),
+                RedirType::tcp_available_types(),
+            )?;
+            let udp_redir = parse_redir_type(
+                listener.udp_redir.as_deref(),
+                RedirType::udp_default(),
+                RedirType::udp_available_types(),
+            )?;
+
+            // a SOCKS5 upstream carries both TCP and UDP
+            let udp_upstream = listener.udp_upstream.or_else(|| {
+                listener.upstream.clone().filter(|name| {
+                    upstreams
+                        .get(name)
+                        .is_some_and(|u| u.protocol == UpstreamProtocol::Socks5)
+                })
+            });
+            let listener = ListenerConfig {
+                addr: listener.address,
+                tcp_redir,
+                udp_redir,
+                mode: listener.mode,
+                upstream: listener.upstream,
+                udp_upstream,
+                sniff: listener.sniff,
+                dns_forward: listener.dns_forward,
+                dns_server: listener.dns_server,
+                dns_snoop: listener.dns_snoop,
+            };
+            listener.check(&upstreams)?;
+            listeners.push(listener);
+        }
+
+        if listeners.is_empty() {
+            return Err(ConfigError::Invalid("no listener is configured".to_owned()));
+        }
+        if ssconfig.max_open_files == Some(0) {
+            return Err(ConfigError::Invalid(
+                "max_open_files: must be positive".to_owned(),
+            ));
+        }
+
+        let router = parse_router(ssconfig.routing, &upstreams)?;
+        let dns = ssconfig.dns.map(parse_dns).transpose()?;
+        let admin = ssconfig.admin.map(parse_admin).transpose()?;
+        let access_log = ssconfig.access_log.map(parse_access_log).transpose()?;
+
+        let mut accept_opts = Config::default_accept_opts();
+        let tcp = ssconfig.tcp;
+        if let Some(nodelay) = tcp.nodelay {
+            accept_opts.tcp.nodelay = nodelay;
+        }
+        if let Some(fastopen) = tcp.fastopen {
+            accept_opts.tcp.fastopen = fastopen;
+        }
+        if let Some(mptcp) = tcp.mptcp {
+            accept_opts.tcp.mptcp = mptcp;
+        }
+        accept_opts.tcp.keepalive = tcp.keepalive.map(Duration::from_secs);
+        accept_opts.tcp.send_buffer_size = tcp.send_buffer_size;
+        accept_opts.tcp.recv_buffer_size = tcp.recv_buffer_size;
+        let tcp_timeouts = parse_tcp_timeouts(&tcp)?;
+
+        let udp = ssconfig.udp;
+        accept_opts.udp.mtu = udp.mtu;
+        if let Some(allow_fragmentation) = udp.allow_fragmentation {
+            accept_opts.udp.allow_fragmentation = allow_fragmentation;
+        }
+        accept_opts.ipv6_only = ssconfig.ipv6_only;
+
+        let udp_send_back = match udp.send_back {
+            Some(send_back) => send_back.parse::<UdpSendBackType>().map_err(|_| {
+                ConfigError::Invalid(format!(
+                    "invalid udp send_back \"{send_back}\", expecting \"raw\" or \"nonlocal\""
+                ))
+            })?,
+            None => UdpSendBackType::default(),
+        };
+
+        #[allow(unused_mut)]
+        let mut redir_opts = RedirSocketOpts::default();
+        if let Some(fwmark) = ssconfig.fwmark {
+            cfg_if! {
+                if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                    redir_opts.fwmark = Some(fwmark);
+                } else {
+                    return Err(ConfigError::Invalid(format!(
+                        "fwmark {fwmark} is only supported on Linux"
+                    )));
+                }
+            }
+        }
+
+        let config = Config {
+            listeners,
+            upstreams,
+            accept_opts,
+            udp_send_back,
+            redir_opts,
+            router,
+            dns,
+            admin,
+            access_log,
+            shutdown_timeout: ssconfig
+                .shutdown_timeout
+                .map_or(Config::DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+            max_open_files: ssconfig.max_open_files,
+            tcp_timeouts,
+        };
+        config.check_udp_send_back()?;
+        Ok(config)
+    }
+
+    /// Check that the UDP replies of every listener can be sent back
+    ///
+    /// Only Linux can send IPv6 packets from the original destinations with a raw socket, macOS
+    /// and *BSD build the IPv6 header in kernel from a local source address.
+    pub fn check_udp_send_back(&self) -> Result<(), ConfigError> {
+        if cfg!(any(target_os = "linux", target_os = "android"))
+            || self.udp_send_back != UdpSendBackType::RawSocket
+        {
+            return Ok(());
+        }
+        let unsupported = self
+            .listeners
+            .iter()
+            .find(|l| l.addr.is_ipv6() && crate::service::serves_udp(self, l));
+        match unsupported {
+            Some(listener) => Err(ConfigError::Invalid(format!(
+                "listener {}: UDP on IPv6 is only supported on Linux, the replies can't be sent back with a raw socket",
+                listener.addr
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Outbound of `flow` accepted by `listener`
+    pub fn route(&self, listener: &ListenerConfig, flow: &Flow) -> Outbound {
+        match listener.mode {
+            ListenerMode::Proxy => {
+                let upstream = match flow.protocol {
+                    Protocol::Tcp => &listener.upstream,
+                    Protocol::Udp => &listener.udp_upstream,
+                };
+                upstream.clone().map_or(Outbound::Reject, Outbound::Proxy)
+            }
+            ListenerMode::Direct => Outbound::Direct,
+            ListenerMode::Rule => self.router.route(flow).clone(),
+        }
+    }
+}
+
+impl ListenerConfig {
+    fn check(&self, upstreams: &HashMap<String, UpstreamConfig>) -> Result<(), ConfigError> {
+        let find_upstream =
+            |name: &str, protocol: Option<UpstreamProtocol>| match upstreams.get(name) {
+                Some(upstream) if protocol.is_none_or(|p| p == upstream.protocol) => Ok(()),
+                Some(..) => Err(ConfigError::Invalid(format!(
+                    "listener {}: upstream \"{name}\" is not a {:?} proxy",
+                    self.addr,
+                    protocol.unwrap()
+                ))),
+                None => Err(ConfigError::Invalid(format!(
+                    "listener {}: upstream \"{name}\" is not defined",
+                    self.addr
+                ))),
+            };
+
+        match self.mode {
+            ListenerMode::Proxy => {
+                let Some(ref upstream) = self.upstream else {
+                    return Err(ConfigError::Invalid(format!(
+                        "listener {}: proxy mode requires an upstream",
+                        self.addr
+                    )));
+                };
+                find_upstream(upstream, None)?;
+                if let Some(ref udp_upstream) = self.udp_upstream {
+                    find_upstream(udp_upstream, Some(UpstreamProtocol::Socks5))?;
+                }
+            }
+            ListenerMode::Direct | ListenerMode::Rule => {
+                if self.upstream.is_some() || self.udp_upstream.is_some() {
+                    return Err(ConfigError::Invalid(format!(
+                        "listener {}: {} mode doesn't use upstreams",
+                        self.addr,
+                        if self.mode == ListenerMode::Direct {
+                            "direct"
+                        } else {
+                            "rule"
+                        }
+                    )));
+                }
+            }
+        }
+        if self.dns_server.is_some() && !self.dns_forward {
+            return Err(ConfigError::Invalid(format!(
+                "listener {}: dns_server requires dns_forward",
+                self.addr
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl UpstreamConfig {
+    fn check(&self, name: &str) -> Result<(), ConfigError> {
+        if self.protocol == UpstreamProtocol::Socks5
+            && let Some(ref auth) = self.auth
+        {
+            // RFC 1929: both fields are 1 to 255 octets
+            let valid_len = |s: &str| (1..=u8::MAX as usize).contains(&s.len());
+            if !valid_len(&auth.username) || !valid_len(&auth.password) {
+                return Err(ConfigError::Invalid(format!(
+                    "upstream \"{name}\": SOCKS5 username and password must be 1 to 255 bytes"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read `field` of upstream `name` from config, or from the environment variable `env`
+fn read_secret(
+    name: &str,
+    field: &str,
+    value: Option<String>,
+    env: Option<String>,
+) -> Result<Option<String>, ConfigError> {
+    read_secret_with(name, field, value, env, |env| std::env::var(env))
+}
+
+/// `read_secret` reading the environment variables with `var`
+fn read_secret_with<F>(
+    name: &str,
+    field: &str,
+    value: Option<String>,
+    env: Option<String>,
+    var: F,
+) -> Result<Option<String>, ConfigError>
+where
+    F: FnOnce(&str) -> Result<String, std::env::VarError>,
+{
+    match (value, env) {
+        (Some(..), Some(..)) => Err(ConfigError::Invalid(format!(
+            "upstream \"{name}\": {field} and {field}_env can not be set together"
+        ))),
+        (Some(value), None) => Ok(Some(value)),
+        (None, Some(env)) => match var(&env) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(ConfigError::Invalid(format!(
+                "upstream \"{name}\": read {field} from environment variable {env} error: {e}"
+            ))),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+fn parse_router(
+    routing: SSRoutingConfig,
+    upstreams: &HashMap<String, UpstreamConfig>,
+) -> Result<Router, ConfigError> {
+    let parse_outbound = |name: &str| {
+        let outbound = Outbound::from_name(name);
+        match outbound {
+            Outbound::Proxy(ref name) if !upstreams.contains_key(name) => Err(
+                ConfigError::Invalid(format!("routing: upstream \"{name}\" is not defined")),
+            ),
+            outbound => Ok(outbound),
+        }
+    };
+    let open_db = |path: Option<PathBuf>| {
+        path.map(|path| match GeoIpDatabase::open(&path) {
+            Ok(db) => Ok(Arc::new(db)),
+            Err(e) => Err(ConfigError::Invalid(format!(
+                "routing: open GeoIP database {} error: {e}",
+                path.display()
+            ))),
+        })
+        .transpose()
+    };
+    let country_db = open_db(routing.country_db)?;
+    let asn_db = open_db(routing.asn_db)?;
+
+    let parse_nets = |nets: Vec<String>| {
+        nets.into_iter()
+            .map(|net| parse_net(&net))
+            .collect::<Result<Vec<_>, _>>()
+    };
+
+    let mut rules = Vec::with_capacity(routing.rules.len());
+    for rule in routing.rules {
+        let domain = parse_domain_matcher(&rule)?;
+        let ports = rule
+            .ports
+            .into_iter()
+            .map(parse_port_range)
+            .collect::<Result<Vec<_>, _>>()?;
+        if !rule.country.is_empty() && country_db.is_none() {
+            return Err(ConfigError::Invalid(
+                "routing: country rules require country_db".to_owned(),
+            ));
+        }
+        if !rule.asn.is_empty() && asn_db.is_none() {
+            return Err(ConfigError::Invalid(
+                "routing: asn rules require asn_db".to_owned(),
+            ));
+        }
+        rules.push(Rule {
+            dst: parse_nets(rule.dst)?,
+            ports,
+            src: parse_nets(rule.src)?,
+            protocol: rule.protocol,
+            country: rule
+                .country
+                .iter()
+                .map(|c| c.to_ascii_uppercase())
+                .collect(),
+            asn: rule.asn,
+            domain,
+            outbound: parse_outbound(&rule.outbound)?,
+        });
+    }
+    let default = match routing.default {
+        Some(name) => parse_outbound(&name)?,
+        None => Outbound::Direct,
+    };
+
+    Ok(Router {
+        rules,
+        default,
+        country_db,
+        asn_db,
+    })
+}
+
+/// Matcher of the domain conditions of `rule`, `None` if there is no domain condition
+fn parse_domain_matcher(rule: &SSRuleConfig) -> Result<Option<Arc<DomainMatcher>>, ConfigError> {
+    let lists = rule.domain_list.iter().map(|p| (p, false));
+    let gfwlists = rule.gfwlist.iter().map(|p| (p, true));
+    let has_lists = !rule.domain_list.is_empty() || !rule.gfwlist.is_empty();
+
+    let mut builder = DomainMatcherBuilder::new();
+    for domain in &rule.domain {
+        builder.add_suffix(domain);
+    }
+    for domain in &rule.domain_full {
+        builder.add_full(domain);
+    }
+    for keyword in &rule.domain_keyword {
+        builder.add_keyword(keyword);
+    }
+    for regex in &rule.domain_regex {
+        builder.add_regex(regex);
+    }
+    for (path, gfwlist) in lists.chain(gfwlists) {
+        let loaded = fs::read_to_string(path).and_then(|content| match gfwlist {
+            true => builder.load_gfwlist(&content),
+            false => builder.load_domain_list(&content),
+        });
+        if let Err(e) = loaded {
+            return Err(ConfigError::Invalid(format!(
+                "routing: load domain list {} error: {e}",
+                path.display()
+            )));
+        }
+    }
+    if builder.is_empty() && !has_lists {
+        return Ok(None);
+    }
+
+    let matcher = builder
+        .build()
+        .map_err(|e| ConfigError::Invalid(format!("routing: {e}")))?;
+    log::debug!("routing: loaded {} domain rules", matcher.len());
+    Ok(Some(Arc::new(matcher)))
+}
+
+fn parse_dns(dns: SSDnsConfig) -> Result<DnsConfig, ConfigError> {
+    // at least 2 usable addresses, without the network and broadcast addresses
+    let parse_range = |range: &str, ipv6: bool| match range.parse::<IpNet>() {
+        Ok(net)
+            if net.network().is_ipv6() == ipv6 && net.max_prefix_len() - net.prefix_len() >= 2 =>
+        {
+            Ok(net.trunc())
+        }
+        _ => Err(ConfigError::Invalid(format!(
+            "dns: invalid fake IP range \"{range}\""
+        ))),
+    };
+    let fake_ip_range = dns
+        .fake_ip_range
+        .as_deref()
+        .unwrap_or(DnsConfig::DEFAULT_FAKE_IP_RANGE);
+    Ok(DnsConfig {
+        addr: dns.address,
+        fake_ip_range: parse_range(fake_ip_range, false)?,
+        fake_ip6_range: dns
+            .fake_ip6_range
+            .map(|range| parse_range(&range, true))
+            .transpose()?,
+        ttl: dns.ttl.unwrap_or(DnsConfig::DEFAULT_TTL),
+    })
+}
+
+fn parse_admin(admin: SSAdminConfig) -> Result<AdminConfig, ConfigError> {
+    let addr = match admin.address.parse::<SocketAddr>() {
+        Ok(addr) => AdminAddr::Tcp(addr),
+        Err(..) if Path::new(&admin.address).is_absolute() => {
+            AdminAddr::Unix(PathBuf::from(admin.address))
+        }
+        Err(..) => {
+            return Err(ConfigError::Invalid(format!(
+                "admin: invalid address \"{}\", expecting a socket address or an absolute path",
+                admin.address
+            )));
+        }
+    };
+    Ok(AdminConfig { addr })
+}
+
+fn parse_access_log(access_log: SSAccessLogConfig) -> Result<AccessLogConfig, ConfigError> {
+    let target = match access_log.path.as_str() {
+        "-" => AccessLogTarget::Stdout,
+        "" => return Err(ConfigError::Invalid("access_log: empty path".to_owned())),
+        path => AccessLogTarget::File(PathBuf::from(path)),
+    };
+    let max_size = access_log
+        .max_size
+        .unwrap_or(AccessLogConfig::DEFAULT_MAX_SIZE_MB)
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| ConfigError::Invalid("access_log: max_size is too large".to_owned()))?;
+    Ok(AccessLogConfig {
+        target,
+        format: access_log.format,
+        max_size,
+        max_files: access_log
+            .max_files
+            .unwrap_or(AccessLogConfig::DEFAULT_MAX_FILES),
+    })
+}
+
+fn parse_tcp_timeouts(tcp: &SSTcpConfig) -> Result<TcpTimeouts, ConfigError> {
+    let positive = |name: &str, secs: Option<u64>, default: Duration| match secs {
+        Some(0) => Err(ConfigError::Invalid(format!(
+            "tcp: {name} must be positive"
+        ))),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Ok(default),
+    };
+    let optional = |secs: Option<u64>, default: Duration| match secs {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(default),
+    };
+    Ok(TcpTimeouts {
+        connect: positive(
+            "connect_timeout",
+            tcp.connect_timeout,
+            TcpTimeouts::DEFAULT_CONNECT,
+        )?,
+        handshake: positive(
+            "handshake_timeout",
+            tcp.handshake_timeout,
+            TcpTimeouts::DEFAULT_HANDSHAKE,
+        )?,
+        idle: optional(tcp.idle_timeout, TcpTimeouts::DEFAULT_IDLE),
+        half_close: optional(tcp.half_close_timeout, TcpTimeouts::DEFAULT_HALF_CLOSE),
+    })
+}
+
+/// CIDR `10.0.0.0/8`, or a single address `10.0.0.1`
+fn parse_net(s: &str) -> Result<IpNet, ConfigError> {
+    s.parse::<IpNet>()
+        .map(|net| net.trunc())
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| ConfigError::Invalid(format!("routing: invalid network \"{s}\"")))
+}
+
+fn parse_port_range(range: SSPortRange) -> Result<RangeInclusive<u16>, ConfigError> {
+    let range = match range {
+        SSPortRange::Port(port) => return Ok(port..=port),
+        SSPortRange::Range(range) => range,
+    };
+    let parsed = match range.split_once('-') {
+        Some((start, end)) => start.trim().parse().and_then(|start| {
+            let end = end.trim().parse()?;
+            Ok(start..=end)
+        }),
+        None => range.trim().parse().map(|port| port..=port),
+    };
+    match parsed {
+        Ok(r) if !r.is_empty() => Ok(r),
+        _ => Err(ConfigError::Invalid(format!(
+            "routing: invalid port range \"{range}\""
+        ))),
+    }
+}
+
+fn parse_redir_type(
+    name: Option<&str>,
+    default: RedirType,
+    available: &[&str],
+) -> Result<RedirType, ConfigError> {
+    let Some(name) = name else {
+        return Ok(default);
+    };
+    match name.parse::<RedirType>() {
+        Ok(ty) if available.contains(&ty.name()) => Ok(ty),
+        _ => Err(ConfigError::Invalid(format!(
+            "invalid redir type \"{name}\", available types: {available:?}"
+        ))),
+    }
+}
+
+#[test]
+fn test_load_toml_config() {
+    let config = Config::load_from_str(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "http"
+        udp_upstream = "socks5"
+
+        [[listeners]]
+        address = "[::1]:12346"
+        mode = "direct"
+
+        [upstreams.http]
+        protocol = "http"
+        address = "127.0.0.1:20172"
+
+        [upstreams.socks5]
+        protocol = "socks5"
+        address = "127.0.0.1:20170"
+
+        [tcp]
+        nodelay = false
+        keepalive = 30
+        connect_timeout = 5
+        idle_timeout = 0
+
+        [udp]
+        mtu = 1400
+        send_back = "raw"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+
+    assert_eq!(config.listeners.len(), 2);
+    let proxy = &config.listeners[0];
+    assert_eq!(proxy.addr, "127.0.0.1:12345".parse::<SocketAddr>().unwrap());
+    assert_eq!(proxy.mode, ListenerMode::Proxy);
+    assert_eq!(proxy.tcp_redir, RedirType::tcp_default());
+    assert_eq!(proxy.udp_redir, RedirType::udp_default());
+    assert_eq!(proxy.upstream.as_deref(), Some("http"));
+    assert_eq!(config.listeners[1].mode, ListenerMode::Direct);
+    assert_eq!(
+        config.upstreams["socks5"].protocol,
+        UpstreamProtocol::Socks5
+    );
+
+    assert!(!config.accept_opts.tcp.nodelay);
+    assert!(config.accept_opts.tcp.fastopen);
+    assert_eq!(
+        config.accept_opts.tcp.keepalive,
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(
+        config.tcp_timeouts,
+        TcpTimeouts {
+            connect: Duration::from_secs(5),
+            idle: None,
+            ..TcpTimeouts::default()
+        }
+    );
+    assert_eq!(config.accept_opts.udp.mtu, Some(1400));
+    assert_eq!(config.udp_send_back, UdpSendBackType::RawSocket);
+}
+
+#[test]
+fn test_load_json_config() {
+    let config = Config::load_from_str(
+        r#"{
+            "listeners": [
+                { "address": "127.0.0.1:12345", "mode": "proxy", "upstream": "http" }
+            ],
+            "upstreams": {
+                "http": { "protocol": "http", "address": "127.0.0.1:20172" }
+            }
+        }"#,
+        ConfigType::Json,
+    )
+    .unwrap();
+
+    assert_eq!(config.listeners[0].udp_upstream, None);
+    assert_eq!(config.udp_send_back, UdpSendBackType::default());
+}
+
+#[test]
+fn test_socks5_upstream_for_tcp_and_udp() {
+    let config = Config::load_from_str(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "socks5"
+
+        [upstreams.socks5]
+        protocol = "socks5"
+        address = "127.0.0.1:20170"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+
+    assert_eq!(config.listeners[0].upstream.as_deref(), Some("socks5"));
+    assert_eq!(config.listeners[0].udp_upstream.as_deref(), Some("socks5"));
+}
+
+#[test]
+fn test_upstream_credentials() {
+    let load = |s: &str| Config::load_from_str(s, ConfigType::Toml);
+
+    let config = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "socks5"
+
+        [upstreams.socks5]
+        protocol = "socks5"
+        address = "127.0.0.1:20170"
+        username = "user"
+        password = "pass"
+        "#,
+    )
+    .unwrap();
+    let auth = config.upstreams["socks5"].auth.as_ref().unwrap();
+    assert_eq!(auth.username, "user");
+    assert_eq!(auth.password, "pass");
+
+    let err = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "socks5"
+
+        [upstreams.socks5]
+        protocol = "socks5"
+        address = "127.0.0.1:20170"
+        username = "user"
+        "#,
+    );
+    assert!(matches!(err, Err(ConfigError::Invalid(..))));
+}
+
+#[test]
+fn test_read_secret() {
+    use std::env::VarError;
+
+    let var = |env: &str| match env {
+        "RUSTSOCKS_PASSWORD" => Ok("pass".to_owned()),
+        _ => Err(VarError::NotPresent),
+    };
+    let read = |value: Option<&str>, env: Option<&str>| {
+        let (value, env) = (value.map(str::to_owned), env.map(str::to_owned));
+        read_secret_with("socks5", "password", value, env, var)
+    };
+
+    assert_eq!(
+        read(None, Some("RUSTSOCKS_PASSWORD")).unwrap().unwrap(),
+        "pass"
+    );
+    assert_eq!(read(Some("literal"), None).unwrap().unwrap(), "literal");
+    assert!(read(None, None).unwrap().is_none());
+    assert!(matches!(
+        read(None, Some("RUSTSOCKS_MISSING")),
+        Err(ConfigError::Invalid(..))
+    ));
+    assert!(matches!(
+        read(Some("literal"), Some("RUSTSOCKS_PASSWORD")),
+        Err(ConfigError::Invalid(..))
+    ));
+}
+
+#[test]
+fn test_routing_config() {
+    let config = Config::load_from_str(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "rule"
+
+        [upstreams.socks5]
+        protocol = "socks5"
+        address = "127.0.0.1:20170"
+
+        [routing]
+        default = "socks5"
+
+        [[routing.rules]]
+        dst = ["10.0.0.0/8", "fd00::1"]
+        outbound = "direct"
+
+        [[routing.rules]]
+        domain = ["example.com"]
+        domain_keyword = ["ads"]
+        domain_regex = ['^track[0-9]+\.']
+        outbound = "reject"
+
+        [[routing.rules]]
+        src = ["192.168.1.0/24"]
+        ports = [53, "8000-9000"]
+        protocol = "udp"
+        outbound = "reject"
+        "#,
+        ConfigType::Toml,
+    )
+    .unwrap();
+
+    let router = &config.router;
+    assert_eq!(router.default, Outbound::Proxy("socks5".to_owned()));
+    assert_eq!(router.rules.len(), 3);
+    assert_eq!(
+        router.rules[0].dst[1],
+        "fd00::1/128".parse::<IpNet>().unwrap()
+    );
+    assert_eq!(router.rules[1].domain.as_ref().unwrap().len(), 3);
+    assert_eq!(router.rules[2].ports, vec![53..=53, 8000..=9000]);
+    assert_eq!(router.rules[2].protocol, Some(Protocol::Udp));
+
+    let flow = Flow {
+        protocol: Protocol::Tcp,
+        src: "192.168.1.2:1234".parse().unwrap(),
+        dst: "10.0.0.1:443".parse().unwrap(),
+        domain: None,
+    };
+    assert_eq!(config.route(&config.listeners[0], &flow), Outbound::Direct);
+
+    let load = |s: &str| Config::load_from_str(s, ConfigType::Toml);
+    for routing in [
+        r#"default = "socks5""#,
+        r#"rules = [{ dst = ["10.0.0.0/33"], outbound = "direct" }]"#,
+        r#"rules = [{ ports = ["9000-8000"], outbound = "direct" }]"#,
+        r#"rules = [{ country = ["CN"], outbound = "direct" }]"#,
+        r#"country_db = "/nonexistent/GeoLite2-Country.mmdb""#,
+        r#"rules = [{ domain_regex = ["("], outbound = "direct" }]"#,
+        r#"rules = [{ gfwlist = ["/nonexistent/gfwlist.txt"], outbound = "direct" }]"#,
+    ] {
+        let err = load(&format!(
+            r#"
+            [[listeners]]
+            address = "127.0.0.1:12345"
+            mode = "rule"
+
+            [routing]
+            {routing}
+            "#
+        ));
+        assert!(matches!(err, Err(ConfigError::Invalid(..))));
+    }
+}
+
+#[test]
+fn test_dns_config() {
+    let load = |dns: &str| {
+        Config::load_from_str(
+            &format!(
+                r#"
+                [[listeners]]
+                address = "127.0.0.1:12345"
+                mode = "direct"
+
+                [dns]
+                address = "127.0.0.1:5353"
+                {dns}
+                "#
+            ),
+            ConfigType::Toml,
+        )
+    };
+
+    let dns = load("").unwrap().dns.unwrap();
+    assert_eq!(dns.fake_ip_range, "198.18.0.0/15".parse::<IpNet>().unwrap());
+    assert_eq!(dns.fake_ip6_range, None);
+    assert_eq!(dns.ttl, DnsConfig::DEFAULT_TTL);
+
+    let dns = load("fake_ip_range = \"10.1.2.3/16\"\nfake_ip6_range = \"fc00::/18\"\nttl = 60")
+        .unwrap()
+        .dns
+        .unwrap();
+    assert_eq!(dns.fake_ip_range, "10.1.0.0/16".parse::<IpNet>().unwrap());
+    assert_eq!(dns.fake_ip6_range, Some("fc00::/18".parse().unwrap()));
+    assert_eq!(dns.ttl, 60);
+
+    for dns in [
+        "fake_ip_range = \"fc00::/18\"",
+        "fake_ip_range = \"198.18.0.0/31\"",
+        "fake_ip6_range = \"198.18.0.0/15\"",
+    ] {
+        assert!(matches!(load(dns), Err(ConfigError::Invalid(..))));
+    }
+}
+
+#[test]
+fn test_admin_config() {
+    let load = |address: &str| {
+        Config::load_from_str(
+            &format!(
+                r#"
+                [[listeners]]
+                address = "127.0.0.1:12345"
+                mode = "direct"
+
+                [admin]
+                address = "{address}"
+                "#
+            ),
+            ConfigType::Toml,
+        )
+        .map(|config| config.admin.unwrap().addr)
+    };
+
+    assert_eq!(
+        load("127.0.0.1:9090").unwrap(),
+        AdminAddr::Tcp("127.0.0.1:9090".parse().unwrap())
+    );
+    assert_eq!(
+        load("/run/rustsocks.sock").unwrap(),
+        AdminAddr::Unix(PathBuf::from("/run/rustsocks.sock"))
+    );
+    assert!(matches!(
+        load("localhost:9090"),
+        Err(ConfigError::Invalid(..))
+    ));
+}
+
+#[test]
+fn test_access_log_config() {
+    let load = |access_log: &str| {
+        Config::load_from_str(
+            &format!(
+                r#"
+                [[listeners]]
+                address = "127.0.0.1:12345"
+                mode = "direct"
+
+                [access_log]
+                {access_log}
+                "#
+            ),
+            ConfigType::Toml,
+        )
+        .map(|config| config.access_log.unwrap())
+    };
+
+    let config = load(r#"path = "-""#).unwrap();
+    assert_eq!(config.target, AccessLogTarget::Stdout);
+    assert_eq!(config.format, AccessLogFormat::Json);
+    assert_eq!(config.max_size, 100 * 1024 * 1024);
+    let config = load(
+        r#"
+        path = "/var/log/rustsocks/access.log"
+        format = "logfmt"
+        max_size = 0
+        max_files = 2
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.target,
+        AccessLogTarget::File(PathBuf::from("/var/log/rustsocks/access.log"))
+    );
+    assert_eq!(
+        (config.format, config.max_size, config.max_files),
+        (AccessLogFormat::Logfmt, 0, 2)
+    );
+    assert!(load(r#"path = """#).is_err());
+    assert!(load("path = \"-\"\nformat = \"csv\"").is_err());
+}
+
+#[test]
+fn test_invalid_config() {
+    let load = |s: &str| Config::load_from_str(s, ConfigType::Toml);
+
+    // undefined upstream
+    let err = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "http"
+        "#,
+    );
+    assert!(matches!(err, Err(ConfigError::Invalid(..))));
+
+    // HTTP proxy can not relay UDP
+    let err = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "proxy"
+        upstream = "http"
+        udp_upstream = "http"
+
+        [upstreams.http]
+        protocol = "http"
+        address = "127.0.0.1:20172"
+        "#,
+    );
+    assert!(matches!(err, Err(ConfigError::Invalid(..))));
+
+    // DNS server without forwarding
+    let err = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "direct"
+        dns_server = "8.8.8.8:53"
+        "#,
+    );
+    assert!(matches!(err, Err(ConfigError::Invalid(..))));
+
+    // unknown field
+    let err = load(
+        r#"
+        [[listeners]]
+        address = "127.0.0.1:12345"
+        mode = "direct"
+        unknown = 1
+        "#,
+    );
+    assert!(matches!(err, Err(ConfigError::TomlError(..))));
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[test]
+fn test_ipv6_udp_send_back() {
+    let load = |dns_forward: bool| {
+        Config::load_from_str(
+            &format!(
+                r#"
+                [[listeners]]
+                address = "[::1]:12345"
+                mode = "proxy"
+                upstream = "http"
+                dns_forward = {dns_forward}
+
+                [upstreams.http]
+                protocol = "http"
+                address = "127.0.0.1:20172"
+                "#
+            ),
+            ConfigType::Toml,
+        )
+    };
+    // TCP only
+    assert!(load(false).is_ok());
+    // serves UDP for the forwarded DNS queries
+    assert!(matches!(load(true), Err(ConfigError::Invalid(..))));
+}
@@ -1,5 +1,7 @@
 use dashmap::{DashMap, mapref::one::RefMut};
 use std::{
+    borrow::Borrow,
+    hash::Hash,
     ops::{Deref, DerefMut},
     time::{Duration, Instant},
 };
@@ -49,7 +51,11 @@ where
         self.map.insert(key, (now, value));
     }
 
-    pub fn get_mut<'a>(&'a self, key: &K) -> Option<ExpiryRefMut<'a, K, V>> {
+    pub fn get_mut<'a, Q>(&'a self, key: &Q) -> Option<ExpiryRefMut<'a, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         if let Some(mut entry) = self.map.get_mut(key) {
             let (ins, _) = entry.value_mut();
             // update the timestamp
@@ -60,17 +66,36 @@ where
         None
     }
 
-    pub fn remove(&self, key: &K) {
+    /// Check if `key` is in the map, without updating its timestamp
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(key)
+    }
+
+    pub fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         self.map.remove(key);
     }
 
+    /// Number of the entries, including the expired ones not cleaned up yet
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     pub fn cleanup_expired(&self) {
         let now = Instant::now();
-        for entry in self.map.iter() {
-            let (ins, _) = entry.value();
-            if now.duration_since(*ins) > self.expiry_duration {
-                self.map.remove(entry.key());
-            }
-        }
+        // NOTE: removing while iterating would deadlock on the shard lock
+        self.map
+            .retain(|_, (ins, _)| now.duration_since(*ins) <= self.expiry_duration);
     }
 }
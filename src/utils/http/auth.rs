@@ -0,0 +1,264 @@
+//! `Proxy-Authorization` with Basic (RFC 7617) and Digest (RFC 7616) schemes
+
+use std::{collections::HashMap, fmt::Write};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::utils::{config::Credentials, http::Error};
+
+/// A challenge in `Proxy-Authenticate` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    /// Lowercase scheme name, e.g. `basic`, `digest`
+    pub scheme: String,
+    /// Auth parameters with lowercase names
+    pub params: HashMap<String, String>,
+}
+
+impl Challenge {
+    /// Parse the challenges of a `Proxy-Authenticate` header value,
+    /// e.g. `Basic realm="proxy", Digest realm="proxy", nonce="abc", qop="auth"`
+    ///
+    /// A new challenge starts at every token which is not followed by `=`.
+    pub fn parse_list(value: &str) -> Vec<Challenge> {
+        let mut challenges: Vec<Challenge> = Vec::new();
+        let mut chars = value.chars().peekable();
+
+        loop {
+            while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '=' && *c != ',') {
+                token.push(c);
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+            if chars.next_if_eq(&'=').is_none() {
+                if !token.is_empty() {
+                    challenges.push(Challenge {
+                        scheme: token.to_ascii_lowercase(),
+                        params: HashMap::new(),
+                    });
+                } else if chars.peek().is_none() {
+                    break;
+                }
+                continue;
+            }
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+            let mut value = String::new();
+            if chars.next_if_eq(&'"').is_some() {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => value.extend(chars.next()),
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(c) = chars.next_if(|c| *c != ',') {
+                    value.push(c);
+                }
+            }
+
+            // parameters before any scheme are ignored
+            if let Some(challenge) = challenges.last_mut() {
+                challenge
+                    .params
+                    .insert(token.to_ascii_lowercase(), value.trim_end().to_owned());
+            }
+        }
+
+        challenges
+    }
+}
+
+/// Build `Proxy-Authorization` for `challenges`, Digest is preferred over Basic
+pub fn authorization(
+    challenges: &[Challenge],
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+) -> Result<String, Error> {
+    if let Some(challenge) = challenges.iter().find(|c| c.scheme == "digest") {
+        let cnonce = format!("{:016x}", rand::random::<u64>());
+        return digest_authorization(challenge, credentials, method, uri, &cnonce);
+    }
+    if challenges.iter().any(|c| c.scheme == "basic") {
+        return Ok(basic_authorization(credentials));
+    }
+
+    let schemes = challenges
+        .iter()
+        .map(|c| c.scheme.as_str())
+        .collect::<Vec<_>>();
+    Err(Error::UnsupportedAuth(format!("schemes {schemes:?}")))
+}
+
+/// `Basic base64(username:password)`
+pub fn basic_authorization(credentials: &Credentials) -> String {
+    let token = STANDARD.encode(format!("{}:{}", credentials.username, credentials.password));
+    format!("Basic {token}")
+}
+
+fn hex_digest<D: Digest>(data: &str) -> String {
+    let hash = D::digest(data.as_bytes());
+    let mut hex = String::with_capacity(hash.len() * 2);
+    for b in hash {
+        let _ = write!(hex, "{b:02x}");
+    }
+    hex
+}
+
+/// Digest response for `challenge`, with client nonce `cnonce`
+fn digest_authorization(
+    challenge: &Challenge,
+    credentials: &Credentials,
+    method: &str,
+    uri: &str,
+    cnonce: &str,
+) -> Result<String, Error> {
+    let param = |name: &str| challenge.params.get(name).map(String::as_str);
+    let realm = param("realm").unwrap_or_default();
+    let Some(nonce) = param("nonce") else {
+        return Err(Error::UnsupportedAuth(
+            "digest challenge without nonce".to_owned(),
+        ));
+    };
+
+    let algorithm = param("algorithm").unwrap_or("MD5");
+    let (hash, sess): (fn(&str) -> String, bool) = match algorithm.to_ascii_uppercase().as_str() {
+        "MD5" => (hex_digest::<Md5>, false),
+        "MD5-SESS" => (hex_digest::<Md5>, true),
+        "SHA-256" => (hex_digest::<Sha256>, false),
+        "SHA-256-SESS" => (hex_digest::<Sha256>, true),
+        _ => {
+            return Err(Error::UnsupportedAuth(format!(
+                "digest algorithm {algorithm}"
+            )));
+        }
+    };
+
+    // qop is a list of options, only "auth" is supported
+    let qop = match param("qop") {
+        Some(qop)
+            if qop
+                .split(',')
+                .any(|q| q.trim().eq_ignore_ascii_case("auth")) =>
+        {
+            Some("auth")
+        }
+        Some(qop) => return Err(Error::UnsupportedAuth(format!("digest qop {qop}"))),
+        None => None,
+    };
+    // every challenge is answered only once, so the nonce count is always 1
+    let nc = "00000001";
+
+    let mut ha1 = hash(&format!(
+        "{}:{}:{}",
+        credentials.username, realm, credentials.password
+    ));
+    if sess {
+        ha1 = hash(&format!("{ha1}:{nonce}:{cnonce}"));
+    }
+    let ha2 = hash(&format!("{method}:{uri}"));
+    let response = match qop {
+        Some(qop) => hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:{qop}:{ha2}")),
+        None => hash(&format!("{ha1}:{nonce}:{ha2}")),
+    };
+
+    let mut value = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}, response=\"{}\"",
+        credentials.username, realm, nonce, uri, algorithm, response
+    );
+    if let Some(opaque) = param("opaque") {
+        let _ = write!(value, ", opaque=\"{opaque}\"");
+    }
+    if let Some(qop) = qop {
+        let _ = write!(value, ", qop={qop}, nc={nc}, cnonce=\"{cnonce}\"");
+    }
+    Ok(value)
+}
+
+#[test]
+fn test_parse_challenge() {
+    let challenges = Challenge::parse_list(
+        r#"Digest realm="proxy, inc", nonce="abc\"d", qop="auth,auth-int", stale=FALSE"#,
+    );
+    assert_eq!(challenges.len(), 1);
+    let challenge = &challenges[0];
+    assert_eq!(challenge.scheme, "digest");
+    assert_eq!(challenge.params["realm"], "proxy, inc");
+    assert_eq!(challenge.params["nonce"], "abc\"d");
+    assert_eq!(challenge.params["qop"], "auth,auth-int");
+    assert_eq!(challenge.params["stale"], "FALSE");
+
+    let challenges = Challenge::parse_list("Basic realm=proxy");
+    assert_eq!(challenges.len(), 1);
+    assert_eq!(challenges[0].scheme, "basic");
+    assert_eq!(challenges[0].params["realm"], "proxy");
+
+    // several challenges in one header
+    let challenges = Challenge::parse_list(
+        r#"Basic realm="x", Negotiate, Digest realm="y", nonce="n", qop="auth""#,
+    );
+    let schemes = challenges
+        .iter()
+        .map(|c| c.scheme.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(schemes, ["basic", "negotiate", "digest"]);
+    assert_eq!(challenges[0].params.len(), 1);
+    assert_eq!(challenges[0].params["realm"], "x");
+    assert!(challenges[1].params.is_empty());
+    assert_eq!(challenges[2].params["realm"], "y");
+    assert_eq!(challenges[2].params["nonce"], "n");
+
+    // Digest is preferred
+    let credentials = Credentials {
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+    };
+    let value = authorization(&challenges, &credentials, "CONNECT", "1.2.3.4:443").unwrap();
+    assert!(value.starts_with(r#"Digest username="user", realm="y", nonce="n""#));
+
+    assert!(Challenge::parse_list(" , ").is_empty());
+}
+
+#[test]
+fn test_basic_authorization() {
+    // RFC 7617, section 2
+    let credentials = Credentials {
+        username: "Aladdin".to_owned(),
+        password: "open sesame".to_owned(),
+    };
+    assert_eq!(
+        basic_authorization(&credentials),
+        "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+    );
+}
+
+#[test]
+fn test_digest_authorization() {
+    // RFC 2617, section 3.5
+    let challenge = Challenge::parse_list(
+        r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+    )
+    .remove(0);
+    let credentials = Credentials {
+        username: "Mufasa".to_owned(),
+        password: "Circle Of Life".to_owned(),
+    };
+    let value = digest_authorization(
+        &challenge,
+        &credentials,
+        "GET",
+        "/dir/index.html",
+        "0a4f113b",
+    )
+    .unwrap();
+    assert!(value.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+    assert!(value.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+    assert!(value.contains(r#"qop=auth, nc=00000001, cnonce="0a4f113b""#));
+}
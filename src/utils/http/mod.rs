@@ -0,0 +1,50 @@
+//! HTTP proxy client
+
+use std::io;
+
+pub mod auth;
+pub mod tcp_client;
+
+/// HTTP proxy error
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{0}")]
+    IoError(#[from] io::Error),
+    #[error("invalid HTTP response, {0}")]
+    InvalidResponse(#[from] httparse::Error),
+    #[error("HTTP response header is larger than {0} bytes")]
+    HeaderTooLarge(usize),
+    #[error("connection closed before HTTP response header completes")]
+    UnexpectedEof,
+    #[error("proxy authentication required")]
+    AuthRequired,
+    #[error("proxy authentication failed")]
+    AuthFailed,
+    #[error("unsupported proxy authentication, {0}")]
+    UnsupportedAuth(String),
+    #[error("HTTP proxy responded {0} {1}")]
+    Status(u16, String),
+}
+
+impl Error {
+    /// Reason label of the metrics, see `crate::metrics::io_failure_reason`
+    pub fn failure_reason(&self) -> &'static str {
+        match self {
+            Error::IoError(err) => crate::metrics::io_failure_reason(err),
+            Error::AuthRequired | Error::AuthFailed | Error::UnsupportedAuth(..) => "auth",
+            Error::Status(..) => "rejected",
+            Error::InvalidResponse(..) | Error::HeaderTooLarge(..) | Error::UnexpectedEof => {
+                "protocol"
+            }
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::IoError(err) => err,
+            e => Self::other(e),
+        }
+    }
+}
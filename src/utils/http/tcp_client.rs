@@ -0,0 +1,269 @@
+//! HTTP/1.x `CONNECT` tunnel client
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{self, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use log::trace;
+use pin_project::pin_project;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+
+use crate::utils::{
+    config::Credentials,
+    http::{
+        Error,
+        auth::{self, Challenge},
+    },
+    socks::socks5::Address,
+};
+
+/// Maximum length of the response header block
+const MAXIMUM_HEADER_SIZE: usize = 64 * 1024;
+/// Maximum number of the response headers
+const MAXIMUM_HEADERS: usize = 64;
+
+/// Status line and the interesting headers of a response
+struct Response {
+    status: u16,
+    reason: String,
+    challenges: Vec<Challenge>,
+}
+
+/// HTTP proxy client, a tunnel established with `CONNECT`
+#[pin_project]
+pub struct HttpTcpClient {
+    #[pin]
+    stream: TcpStream,
+    // bytes received after the response header, they belong to the tunnel
+    leftover: Bytes,
+}
+
+impl HttpTcpClient {
+    /// Connects to `addr` via `proxy`
+    ///
+    /// If `proxy` requires authentication (407) and `auth` is provided, connects again with
+    /// `Proxy-Authorization` of the scheme that the proxy asks for.
+    pub async fn connect<A>(
+        addr: A,
+        proxy: SocketAddr,
+        auth: Option<&Credentials>,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Address>,
+    {
+        let stream = TcpStream::connect(proxy).await?;
+        Self::connect_on(stream, addr, auth, || TcpStream::connect(proxy)).await
+    }
+
+    /// Connects to `addr` via the proxy that `stream` is connected to
+    ///
+    /// `reconnect` connects to the proxy again, to retry with `auth` after 407.
+    pub async fn connect_on<A, R, F>(
+        stream: TcpStream,
+        addr: A,
+        auth: Option<&Credentials>,
+        mut reconnect: R,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Address>,
+        R: FnMut() -> F,
+        F: Future<Output = io::Result<TcpStream>>,
+    {
+        let authority = addr.into().to_string();
+        let mut authorization: Option<String> = None;
+
+        let mut first = Some(stream);
+        loop {
+            let mut stream = match first.take() {
+                Some(stream) => stream,
+                None => reconnect().await?,
+            };
+
+            let mut req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+            if let Some(ref authorization) = authorization {
+                req.push_str("Proxy-Authorization: ");
+                req.push_str(authorization);
+                req.push_str("\r\n");
+            }
+            req.push_str("\r\n");
+            trace!("going to connect, req: {:?}", req);
+            stream.write_all(req.as_bytes()).await?;
+
+            let (resp, leftover) = read_response(&mut stream).await?;
+            trace!("got response: {} {}", resp.status, resp.reason);
+            match resp.status {
+                200..=299 => return Ok(Self { stream, leftover }),
+                407 => {
+                    let Some(auth) = auth else {
+                        return Err(Error::AuthRequired);
+                    };
+                    if authorization.is_some() {
+                        return Err(Error::AuthFailed);
+                    }
+                    // the proxy may close the connection after 407, retry with a new one
+                    authorization = Some(auth::authorization(
+                        &resp.challenges,
+                        auth,
+                        "CONNECT",
+                        &authority,
+                    )?);
+                }
+                status => return Err(Error::Status(status, resp.reason)),
+            }
+        }
+    }
+}
+
+/// Read the response header, returns the response and the bytes after it
+async fn read_response(stream: &mut TcpStream) -> Result<(Response, Bytes), Error> {
+    let mut buf = BytesMut::with_capacity(1024);
+
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(Error::UnexpectedEof);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; MAXIMUM_HEADERS];
+        let mut resp = httparse::Response::new(&mut headers);
+        let header_len = match resp.parse(&buf)? {
+            httparse::Status::Complete(n) => n,
+            httparse::Status::Partial => {
+                if buf.len() >= MAXIMUM_HEADER_SIZE {
+                    return Err(Error::HeaderTooLarge(MAXIMUM_HEADER_SIZE));
+                }
+                continue;
+            }
+        };
+
+        let response = Response {
+            // status is always set in a complete response
+            status: resp.code.unwrap_or_default(),
+            reason: resp.reason.unwrap_or_default().to_owned(),
+            challenges: resp
+                .headers
+                .iter()
+                .filter(|h| h.name.eq_ignore_ascii_case("Proxy-Authenticate"))
+                .filter_map(|h| std::str::from_utf8(h.value).ok())
+                .flat_map(Challenge::parse_list)
+                .collect(),
+        };
+        buf.advance(header_len);
+        return Ok((response, buf.freeze()));
+    }
+}
+
+impl AsyncRead for HttpTcpClient {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        let this = self.project();
+        if !this.leftover.is_empty() {
+            let n = this.leftover.len().min(buf.remaining());
+            buf.put_slice(&this.leftover.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        this.stream.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HttpTcpClient {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+    ) -> Poll<Result<(), io::Error>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_connect_with_digest_auth() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // asks for authentication, in two segments
+        let (mut s, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = s.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"CONNECT 1.2.3.4:443 HTTP/1.1\r\n"));
+        s.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"p\"\r\n")
+            .await
+            .unwrap();
+        s.write_all(b"Proxy-Authenticate: Digest realm=\"p\", nonce=\"n\", qop=\"auth\"\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        drop(s);
+
+        // accepts with HTTP/1.0, and sends tunnel data along with the header
+        let (mut s, _) = listener.accept().await.unwrap();
+        let n = s.read(&mut buf).await.unwrap();
+        let req = String::from_utf8_lossy(&buf[..n]);
+        assert!(req.contains("Proxy-Authorization: Digest username=\"user\""));
+        s.write_all(b"HTTP/1.0 200 Connection established\r\n\r\nhello")
+            .await
+            .unwrap();
+        s.write_all(b" world").await.unwrap();
+    });
+
+    let auth = Credentials {
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+    };
+    let target: SocketAddr = "1.2.3.4:443".parse().unwrap();
+    let mut client = HttpTcpClient::connect(target, proxy_addr, Some(&auth))
+        .await
+        .unwrap();
+    let mut data = String::new();
+    client.read_to_string(&mut data).await.unwrap();
+    assert_eq!(data, "hello world");
+}
+
+#[tokio::test]
+async fn test_connect_errors() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 1024];
+        for resp in [
+            &b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"[..],
+            b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\n",
+        ] {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let _ = s.read(&mut buf).await.unwrap();
+            s.write_all(resp).await.unwrap();
+        }
+    });
+
+    let target: SocketAddr = "1.2.3.4:443".parse().unwrap();
+    let err = HttpTcpClient::connect(target, proxy_addr, None).await;
+    assert!(matches!(err, Err(Error::AuthRequired)));
+    let err = HttpTcpClient::connect(target, proxy_addr, None).await;
+    assert!(matches!(err, Err(Error::Status(502, ..))));
+    let err = HttpTcpClient::connect(target, proxy_addr, None).await;
+    assert!(matches!(err, Err(Error::UnexpectedEof)));
+}
@@ -1,5 +1,6 @@
 pub mod config;
 pub mod expiry_map;
+pub mod http;
 pub mod net;
 pub mod raw_socket;
 pub mod socks;
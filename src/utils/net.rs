@@ -1,11 +1,20 @@
 //! Options for connecting to remote server
 //! modified from shadowsocks/src/net/option.rs
 
+use crate::redir::redir_ext::RedirSocketOpts;
+use cfg_if::cfg_if;
 use log::error;
-use std::{io, mem, net::SocketAddr, os::unix::io::AsRawFd, time::Duration};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::{fd::AsFd, unix::io::AsRawFd},
+    time::Duration,
+};
+use tokio::net::{TcpSocket, TcpStream, ToSocketAddrs, UdpSocket, lookup_host};
 
 /// Options for connecting to TCP remote server
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct TcpSocketOpts {
     /// TCP socket's `SO_SNDBUF`
     pub send_buffer_size: Option<u32>,
@@ -33,19 +42,28 @@ pub struct TcpSocketOpts {
 }
 
 /// Options for UDP server
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UdpSocketOpts {
     /// Maximum Transmission Unit (MTU) for UDP socket `recv`
     ///
     /// NOTE: MTU includes IP header, UDP header, UDP payload
     pub mtu: Option<usize>,
 
-    /// Outbound UDP socket allows IP fragmentation
+    /// Outbound UDP socket allows IP fragmentation, the DF flag is set if it is disabled
     pub allow_fragmentation: bool,
 }
 
+impl Default for UdpSocketOpts {
+    fn default() -> Self {
+        UdpSocketOpts {
+            mtu: None,
+            allow_fragmentation: true,
+        }
+    }
+}
+
 /// Inbound connection options
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct AcceptOpts {
     /// TCP options
     pub tcp: TcpSocketOpts,
@@ -57,6 +75,19 @@ pub struct AcceptOpts {
     pub ipv6_only: bool,
 }
 
+/// Options of the outbound sockets, connecting to the destinations or the upstreams
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectOpts {
+    /// TCP options, except `fastopen`
+    pub tcp: TcpSocketOpts,
+
+    /// UDP options, except `mtu`
+    pub udp: UdpSocketOpts,
+
+    /// `SO_MARK` to keep the outbound sockets out of the transparent proxy rules
+    pub redir_opts: RedirSocketOpts,
+}
+
 /// Check if `SocketAddr` could be used for creating dual-stack sockets
 pub fn is_dual_stack_addr(addr: &SocketAddr) -> bool {
     if let SocketAddr::V6(ref v6) = *addr {
@@ -92,3 +123,182 @@ pub fn set_tcp_fastopen<S: AsRawFd>(socket: &S) -> io::Result<()> {
 
     Ok(())
 }
+
+/// Set the options of an accepted or outbound TCP stream, `fastopen` and `mptcp` only take
+/// effect on creating the socket
+pub fn set_tcp_opts<S: AsFd>(socket: &S, opts: &TcpSocketOpts) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    if let Some(size) = opts.send_buffer_size {
+        socket.set_send_buffer_size(size as usize)?;
+    }
+    if let Some(size) = opts.recv_buffer_size {
+        socket.set_recv_buffer_size(size as usize)?;
+    }
+    if opts.nodelay {
+        socket.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = opts.keepalive {
+        let params = TcpKeepalive::new().with_time(keepalive);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "macos",
+            target_os = "ios"
+        ))]
+        let params = params.with_interval(keepalive);
+        socket.set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// TCP socket for connecting to `addr`, Multipath TCP if `opts.mptcp` is set and the kernel
+/// supports it (Linux only)
+fn new_tcp_socket(addr: &SocketAddr, opts: &TcpSocketOpts) -> io::Result<TcpSocket> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if opts.mptcp {
+        match Socket::new(
+            Domain::for_address(*addr),
+            Type::STREAM,
+            Some(Protocol::MPTCP),
+        ) {
+            Ok(socket) => {
+                socket.set_nonblocking(true)?;
+                return Ok(TcpSocket::from_std_stream(socket.into()));
+            }
+            // MPTCP is disabled or not built in, use TCP instead
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EPROTONOSUPPORT | libc::ENOPROTOOPT | libc::EINVAL)
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match addr {
+        SocketAddr::V4(..) => TcpSocket::new_v4(),
+        SocketAddr::V6(..) => TcpSocket::new_v6(),
+    }
+}
+
+/// Connect to `addr` from a socket with `opts`, including `SO_MARK` to keep the outbound
+/// connections out of the transparent proxy rules
+pub async fn connect_tcp<A: ToSocketAddrs>(addr: A, opts: &ConnectOpts) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in lookup_host(addr).await? {
+        let socket = new_tcp_socket(&addr, &opts.tcp)?;
+        set_outbound_opts(&socket, &opts.redir_opts)?;
+        set_tcp_opts(&socket, &opts.tcp)?;
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+/// Bind an outbound UDP socket to `addr` with `opts`, see `connect_tcp`
+pub fn bind_udp(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    set_outbound_opts(&socket, &opts.redir_opts)?;
+    if !opts.udp.allow_fragmentation {
+        set_dont_fragment(&socket, &addr)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[allow(unused_variables)]
+fn set_outbound_opts<S: AsRawFd>(socket: &S, redir_opts: &RedirSocketOpts) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(mark) = redir_opts.fwmark {
+        crate::redir::sys::set_fwmark(socket, mark)?;
+    }
+    Ok(())
+}
+
+/// Set the DF flag of the packets sent by a UDP socket bound to `addr`, they are dropped instead
+/// of fragmented if they exceed the path MTU
+#[allow(unused_variables)]
+fn set_dont_fragment<S: AsRawFd>(socket: &S, addr: &SocketAddr) -> io::Result<()> {
+    cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "android"))] {
+            let (level, opt, value) = match addr {
+                SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO),
+                SocketAddr::V6(..) => {
+                    (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+                }
+            };
+        } else if #[cfg(any(target_os = "freebsd", target_os = "macos", target_os = "ios"))] {
+            let (level, opt, value) = match addr {
+                SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_DONTFRAG, 1),
+                SocketAddr::V6(..) => (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1),
+            };
+        } else {
+            return Ok(());
+        }
+    }
+
+    let value: libc::c_int = value;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            opt,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_outbound_fwmark() {
+    let opts = ConnectOpts {
+        redir_opts: RedirSocketOpts { fwmark: Some(255) },
+        ..ConnectOpts::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = connect_tcp(listener.local_addr().unwrap(), &opts)
+        .await
+        .unwrap();
+    assert_eq!(SockRef::from(&stream).mark().unwrap(), 255);
+
+    let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &opts).unwrap();
+    assert_eq!(SockRef::from(&socket).mark().unwrap(), 255);
+}
+
+#[tokio::test]
+async fn test_outbound_opts() {
+    let opts = ConnectOpts {
+        tcp: TcpSocketOpts {
+            nodelay: true,
+            keepalive: Some(Duration::from_secs(30)),
+            ..TcpSocketOpts::default()
+        },
+        ..ConnectOpts::default()
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = connect_tcp(listener.local_addr().unwrap(), &opts)
+        .await
+        .unwrap();
+    let socket = SockRef::from(&stream);
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+
+    let (accepted, _) = listener.accept().await.unwrap();
+    assert!(!SockRef::from(&accepted).nodelay().unwrap());
+    set_tcp_opts(&accepted, &opts.tcp).unwrap();
+    assert!(SockRef::from(&accepted).nodelay().unwrap());
+}
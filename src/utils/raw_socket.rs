@@ -1,10 +1,12 @@
+use cfg_if::cfg_if;
 use core::slice;
-use libc::{in_addr, size_t, sockaddr, sockaddr_in, socklen_t};
+use libc::{size_t, socklen_t};
+use socket2::SockAddr;
 use std::{
     alloc::{Layout, alloc_zeroed, dealloc, handle_alloc_error},
     ffi::c_void,
     io, mem,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     os::fd::RawFd,
     ptr::copy_nonoverlapping,
 };
@@ -13,31 +15,79 @@ use tokio::io::unix::AsyncFd;
 struct Packet {
     data: *mut u8,
     layout: Layout,
+    ip_header_len: usize,
 }
 
 // SAFETY: Packet manage its own memory and does not share it across threads
 unsafe impl Send for Packet {}
+// SAFETY: Packet can only be mutated through `&mut self`
+unsafe impl Sync for Packet {}
 
 impl Packet {
+    /// Allocate an IPv4 packet with `buf` as UDP payload
     fn new(buf: &[u8]) -> Self {
+        Self::alloc(IP_HEADER_LEN, IP_HEADER_LEN + UDP_HEADER_LEN, buf)
+    }
+
+    /// Allocate an IPv6 packet with `buf` as UDP payload
+    fn new_v6(buf: &[u8]) -> Self {
+        Self::alloc(IPV6_HEADER_LEN, IPV6_HEADER_LEN + UDP_HEADER_LEN, buf)
+    }
+
+    /// Allocate a packet with `header_len` zeroed bytes reserved before `buf`
+    fn alloc(ip_header_len: usize, header_len: usize, buf: &[u8]) -> Self {
         unsafe {
-            let header_len = mem::size_of::<IpHeader>() + mem::size_of::<UdpHeader>();
             let layout = Layout::from_size_align_unchecked(header_len + buf.len(), 4);
             let data = alloc_zeroed(layout);
             if data.is_null() {
                 handle_alloc_error(layout);
             }
             copy_nonoverlapping(buf.as_ptr(), data.add(header_len), buf.len());
-            Packet { data, layout }
+            Packet {
+                data,
+                layout,
+                ip_header_len,
+            }
         }
     }
+
     fn as_headers(&mut self) -> (&mut IpHeader, &mut UdpHeader) {
+        debug_assert_eq!(self.ip_header_len, IP_HEADER_LEN);
         let ip_header = unsafe { &mut *(self.data as *mut IpHeader) };
-        let udp_header =
-            unsafe { &mut *(self.data.add(mem::size_of::<IpHeader>()) as *mut UdpHeader) };
+        let udp_header = unsafe { &mut *(self.data.add(IP_HEADER_LEN) as *mut UdpHeader) };
         (ip_header, udp_header)
     }
 
+    fn as_v6_headers(&mut self) -> (&mut Ipv6Header, &mut UdpHeader) {
+        debug_assert_eq!(self.ip_header_len, IPV6_HEADER_LEN);
+        let ip_header = unsafe { &mut *(self.data as *mut Ipv6Header) };
+        let udp_header = unsafe { &mut *(self.data.add(IPV6_HEADER_LEN) as *mut UdpHeader) };
+        (ip_header, udp_header)
+    }
+
+    /// IPv4 header of a fragment, which has no UDP header
+    fn as_fragment_header(&mut self) -> &mut IpHeader {
+        debug_assert_eq!(self.ip_header_len, IP_HEADER_LEN);
+        unsafe { &mut *(self.data as *mut IpHeader) }
+    }
+
+    /// IPv6 header and fragment extension header of a fragment
+    fn as_v6_fragment_headers(&mut self) -> (&mut Ipv6Header, &mut Ipv6FragmentHeader) {
+        debug_assert_eq!(
+            self.ip_header_len,
+            IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN
+        );
+        let ip_header = unsafe { &mut *(self.data as *mut Ipv6Header) };
+        let frag_header =
+            unsafe { &mut *(self.data.add(IPV6_HEADER_LEN) as *mut Ipv6FragmentHeader) };
+        (ip_header, frag_header)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        // SAFETY: data is allocated with layout.size() bytes
+        unsafe { slice::from_raw_parts(self.data, self.len()) }
+    }
+
     fn len(&self) -> usize {
         self.layout.size()
     }
@@ -66,6 +116,27 @@ struct IpHeader {
     daddr: u32,
 }
 
+#[repr(C)]
+struct Ipv6Header {
+    /// version(4 bits), traffic class(8 bits), flow label(20 bits)
+    ver_tc_flow: u32,
+    payload_len: u16,
+    next_header: u8,
+    hop_limit: u8,
+    saddr: [u8; 16],
+    daddr: [u8; 16],
+}
+
+/// IPv6 fragment extension header
+#[repr(C)]
+struct Ipv6FragmentHeader {
+    next_header: u8,
+    reserved: u8,
+    /// fragment offset(13 bits), reserved(2 bits), M flag(1 bit)
+    frag_off: u16,
+    id: u32,
+}
+
 #[repr(C)]
 struct UdpHeader {
     sport: u16,
@@ -76,38 +147,123 @@ struct UdpHeader {
 
 const UDP_HEADER_LEN: usize = mem::size_of::<UdpHeader>();
 const IP_HEADER_LEN: usize = mem::size_of::<IpHeader>();
-const HEADER_LEN: usize = UDP_HEADER_LEN + IP_HEADER_LEN;
+const IPV6_HEADER_LEN: usize = mem::size_of::<Ipv6Header>();
+const IPV6_FRAGMENT_HEADER_LEN: usize = mem::size_of::<Ipv6FragmentHeader>();
+
+/// IPv4 "more fragments" flag
+const IP_MF: u16 = 0x2000;
+/// IPv6 fragment header's "more fragments" flag
+const IPV6_MF: u16 = 0x0001;
+/// IPv6 next header value of the fragment extension header
+const IPPROTO_FRAGMENT: u8 = 44;
+
+/// Default MTU when it is not configured, the MTU of Ethernet
+pub const DEFAULT_MTU: usize = 1500;
+/// Minimum MTU of IPv4 (RFC 791)
+const MINIMUM_MTU_V4: usize = 68;
+/// Minimum MTU of IPv6 (RFC 8200)
+const MINIMUM_MTU_V6: usize = 1280;
 
 struct SendParam {
-    len: size_t,
-    addr: sockaddr_in,
-    addrlen: socklen_t,
-    packet: Packet,
+    addr: SockAddr,
+    /// A complete packet, or fragments of it if it exceeds the MTU
+    packets: Vec<Packet>,
 }
 
 impl UdpHeader {
-    /// TODO: we may need this to compute checksum later
-    #[allow(dead_code)]
     fn as_slice_mut(&mut self) -> &mut [u8] {
         // SAFETY: there is always only one mutable reference
         unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, mem::size_of::<UdpHeader>()) }
     }
+
+    /// Fill in the checksum, which covers the pseudo header, this header and the payload.
+    /// Must be called after all the other fields are set in network byte order.
+    fn finish(&mut self, pseudo_header: &[u8], payload: &[u8]) {
+        self.check = 0;
+        let mut checksum = Checksum::default();
+        checksum.add(pseudo_header);
+        checksum.add(self.as_slice_mut());
+        checksum.add(payload);
+        self.check = checksum.finish_udp().to_be();
+    }
 }
 
 impl IpHeader {
-    /// TODO: we may need this to compute checksum later
-    #[allow(dead_code)]
     fn as_slice_mut(&mut self) -> &mut [u8] {
         // SAFETY: there is always only one mutable reference
         unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, mem::size_of::<IpHeader>()) }
     }
+
+    /// Fill in the header checksum. Must be called after all the other fields are set in network byte order.
+    fn finish(&mut self) {
+        self.check = 0;
+        let mut checksum = Checksum::default();
+        checksum.add(self.as_slice_mut());
+        self.check = checksum.finish().to_be();
+
+        cfg_if! {
+            if #[cfg(not(any(target_os = "linux", target_os = "android")))] {
+                // macOS and freebsd(before 11.0) use host byte order for ip_len and ip_off,
+                // they are converted back to network byte order (and the checksum is recomputed) by the kernel
+                self.tot_len = u16::from_be(self.tot_len);
+                self.frag_off = u16::from_be(self.frag_off);
+            }
+        }
+    }
+}
+
+/// Internet checksum (RFC 1071) accumulator
+#[derive(Default)]
+struct Checksum {
+    sum: u64,
+    /// the trailing byte of the previous odd-length slice
+    pending: Option<u8>,
+}
+
+impl Checksum {
+    fn add(&mut self, mut data: &[u8]) {
+        if let Some(hi) = self.pending.take()
+            && let Some((&lo, rest)) = data.split_first()
+        {
+            self.sum += u16::from_be_bytes([hi, lo]) as u64;
+            data = rest;
+        }
+        let mut chunks = data.chunks_exact(2);
+        for chunk in &mut chunks {
+            self.sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u64;
+        }
+        if let [last] = chunks.remainder() {
+            self.pending = Some(*last);
+        }
+    }
+
+    /// Fold the sum and return the one's complement, in host byte order
+    fn finish(mut self) -> u16 {
+        if let Some(hi) = self.pending.take() {
+            self.sum += u16::from_be_bytes([hi, 0]) as u64;
+        }
+        while self.sum >> 16 != 0 {
+            self.sum = (self.sum & 0xffff) + (self.sum >> 16);
+        }
+        !(self.sum as u16)
+    }
+
+    /// UDP checksum, 0 means no checksum so it is transmitted as all ones (RFC 768)
+    fn finish_udp(self) -> u16 {
+        match self.finish() {
+            0 => 0xffff,
+            c => c,
+        }
+    }
 }
 
 pub struct RawSocket {
     fd: AsyncFd<RawFd>,
+    mtu: usize,
 }
 
 impl RawSocket {
+    /// Create a raw socket for sending IPv4 packets with `IP_HDRINCL`
     pub fn new() -> std::io::Result<Self> {
         let fd = unsafe {
             let fd = libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_UDP);
@@ -123,41 +279,112 @@ impl RawSocket {
                 mem::size_of::<libc::c_int>() as socklen_t,
             ) < 0
             {
+                let err = std::io::Error::last_os_error();
                 libc::close(fd);
-                return Err(std::io::Error::last_os_error());
+                return Err(err);
             }
-            // according to the man page, these two operations will never fail
-            let flags = libc::fcntl(fd, libc::F_GETFL);
-            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+            Self::set_nonblocking(fd);
             fd
         };
-        let async_fd = AsyncFd::new(fd)?;
-        Ok(RawSocket { fd: async_fd })
+        Self::from_raw_fd(fd)
+    }
+
+    /// Create a raw socket for sending IPv6 packets with the IPv6 header included
+    ///
+    /// IPv6 doesn't have `IP_HDRINCL`, but `IPPROTO_RAW` sockets imply it on Linux.
+    /// Other platforms (*BSD, macOS) always build the IPv6 header in kernel, so it is not supported there.
+    pub fn new_v6() -> std::io::Result<Self> {
+        cfg_if! {
+            if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                let fd = unsafe {
+                    let fd = libc::socket(libc::AF_INET6, libc::SOCK_RAW, libc::IPPROTO_RAW);
+                    if fd < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Self::set_nonblocking(fd);
+                    fd
+                };
+                Self::from_raw_fd(fd)
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "IPv6 raw socket with header included is not supported on this platform",
+                ))
+            }
+        }
+    }
+
+    /// Create a raw socket that is able to send packets to `addr`
+    pub fn for_addr(addr: &SocketAddr) -> std::io::Result<Self> {
+        match addr {
+            SocketAddr::V4(..) => Self::new(),
+            SocketAddr::V6(..) => Self::new_v6(),
+        }
+    }
+
+    unsafe fn set_nonblocking(fd: RawFd) {
+        // according to the man page, these two operations will never fail
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+    }
+
+    fn from_raw_fd(fd: RawFd) -> io::Result<Self> {
+        match AsyncFd::new(fd) {
+            Ok(fd) => Ok(RawSocket {
+                fd,
+                mtu: DEFAULT_MTU,
+            }),
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Set the MTU of the link to the destinations, packets larger than it will be fragmented
+    ///
+    /// NOTE: MTU includes IP header, UDP header, UDP payload
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
     }
 
     /// send raw UDP packet
+    ///
+    /// Returns the length of UDP payload sent, which is `buf.len()` if every fragment is sent
     pub async fn send_to(
         &self,
         src_addr: SocketAddr,
         dst_addr: SocketAddr,
         buf: &[u8],
     ) -> io::Result<usize> {
-        if buf.len() > 1500 - 28 {
-            log::warn!(
-                "UDP packet len is longger than 1500, but fragment is not supported for now"
-            );
+        let param = Self::build_packet(src_addr, dst_addr, buf, self.mtu)?;
+        let total_len: usize = param.packets.iter().map(Packet::len).sum();
+        let mut sent_len = 0;
+        for packet in &param.packets {
+            let n = self.send_packet(packet, &param.addr).await?;
+            sent_len += n;
+            if n != packet.len() {
+                // the rest of fragments are useless
+                break;
+            }
         }
-        let param = Self::build_packet(src_addr, dst_addr, buf);
+        // headers are not counted
+        Ok(buf.len().saturating_sub(total_len - sent_len))
+    }
+
+    async fn send_packet(&self, packet: &Packet, addr: &SockAddr) -> io::Result<usize> {
         loop {
             let mut guard = self.fd.writable().await?;
             let n = unsafe {
                 libc::sendto(
                     *guard.get_inner(),
-                    param.packet.data as *const c_void,
-                    param.len,
+                    packet.data as *const c_void,
+                    packet.len() as size_t,
                     0,
-                    &param.addr as *const sockaddr_in as *const sockaddr,
-                    param.addrlen,
+                    addr.as_ptr(),
+                    addr.len(),
                 )
             };
             if n < 0 {
@@ -169,62 +396,207 @@ impl RawSocket {
                     return Err(err);
                 }
             } else {
-                return Ok((n as usize).saturating_sub(HEADER_LEN));
+                return Ok(n as usize);
             }
         }
     }
 
-    /// build raw UDP packet with IP and UDP headers
-    fn build_packet(src_addr: SocketAddr, dst_addr: SocketAddr, buf: &[u8]) -> SendParam {
-        let (src_ip, dst_ip) = match (src_addr.ip(), dst_addr.ip()) {
-            (std::net::IpAddr::V4(a), std::net::IpAddr::V4(b)) => (a, b),
-            _ => unimplemented!("not support IPv6 yet!"),
+    /// build raw UDP packet with IP and UDP headers, fragmented by `mtu`
+    fn build_packet(
+        src_addr: SocketAddr,
+        dst_addr: SocketAddr,
+        buf: &[u8],
+        mtu: usize,
+    ) -> io::Result<SendParam> {
+        let max_len = match dst_addr {
+            SocketAddr::V4(..) => u16::MAX as usize - IP_HEADER_LEN - UDP_HEADER_LEN,
+            SocketAddr::V6(..) => u16::MAX as usize - UDP_HEADER_LEN,
         };
-        let src_port = src_addr.port();
-        let dst_port = dst_addr.port();
+        if buf.len() > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("UDP payload too large, {} > {} bytes", buf.len(), max_len),
+            ));
+        }
+        match (src_addr.ip(), dst_addr.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => Ok(Self::build_packet_v4(
+                (src_ip, src_addr.port()),
+                (dst_ip, dst_addr.port()),
+                buf,
+                mtu.max(MINIMUM_MTU_V4),
+            )),
+            (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => Ok(Self::build_packet_v6(
+                (src_ip, src_addr.port()),
+                (dst_ip, dst_addr.port()),
+                buf,
+                mtu.max(MINIMUM_MTU_V6),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("address family mismatch, source: {src_addr}, destination: {dst_addr}"),
+            )),
+        }
+    }
+
+    fn build_packet_v4(
+        src: (Ipv4Addr, u16),
+        dst: (Ipv4Addr, u16),
+        buf: &[u8],
+        mtu: usize,
+    ) -> SendParam {
+        let (src_ip, src_port) = src;
+        let (dst_ip, dst_port) = dst;
+        let udp_len = (UDP_HEADER_LEN + buf.len()) as u16;
         let mut packet = Packet::new(buf);
+
         let (ip_header, udp_header) = packet.as_headers();
         // build UDP header
         // UDP header length is 8 bytes
         udp_header.sport = src_port.to_be();
         udp_header.dport = dst_port.to_be();
-        udp_header.len = ((mem::size_of::<UdpHeader>() + buf.len()) as u16).to_be();
-        // For simplicity, we set checksum to 0 (no checksum)
-        udp_header.check = 0;
+        udp_header.len = udp_len.to_be();
+        let mut pseudo_header = [0u8; 12];
+        pseudo_header[..4].copy_from_slice(&src_ip.octets());
+        pseudo_header[4..8].copy_from_slice(&dst_ip.octets());
+        pseudo_header[9] = libc::IPPROTO_UDP as u8;
+        pseudo_header[10..].copy_from_slice(&udp_len.to_be_bytes());
+        udp_header.finish(&pseudo_header, buf);
+
         // build IP header
         // version=4, IHL=5 (20 bytes)
         ip_header.ver_ihl = (4 << 4) | 5;
-        // macOS and freebsd(before 11.0) use host byte order for ip_len and ip_off
-        ip_header.tot_len =
-            (mem::size_of::<UdpHeader>() + mem::size_of::<IpHeader>() + buf.len()) as u16;
+        ip_header.tot_len = ((IP_HEADER_LEN + udp_len as usize) as u16).to_be();
         // 0 means kernel set appropriate value
         ip_header.id = 0;
-        // no fragmentation
         ip_header.frag_off = 0;
         ip_header.ttl = 64;
         ip_header.protocol = libc::IPPROTO_UDP as u8;
-        // not computing IP checksum for simplicity
-        ip_header.check = 0;
         ip_header.saddr = u32::from_be_bytes(src_ip.octets()).to_be();
         ip_header.daddr = u32::from_be_bytes(dst_ip.octets()).to_be();
 
         // build dst sockaddr_in
-        let dst = sockaddr_in {
-            sin_len: mem::size_of::<sockaddr_in>() as u8,
-            sin_family: libc::AF_INET as u8,
-            // not strictly used for raw
-            sin_port: 0,
-            // not used by kernel, but we still need to fill it, 0 will cause `NotConnected` error
-            sin_addr: in_addr {
-                s_addr: u32::from_be_bytes(dst_ip.octets()).to_be(),
-            },
-            sin_zero: [0i8; 8],
-        };
-        SendParam {
-            len: packet.len() as size_t,
-            addr: dst,
-            addrlen: mem::size_of::<sockaddr_in>() as socklen_t,
-            packet,
+        // port is not used by raw socket
+        // address is not used by kernel, but we still need to fill it, 0 will cause `NotConnected` error
+        let addr = SockAddr::from(SocketAddr::new(dst_ip.into(), 0));
+
+        if packet.len() <= mtu {
+            packet.as_headers().0.finish();
+            return SendParam {
+                addr,
+                packets: vec![packet],
+            };
+        }
+
+        // Kernel doesn't fragment packets with IP_HDRINCL, it returns EMSGSIZE instead
+        // Every fragment except the last one carries a multiple of 8 bytes
+        let chunk_len = (mtu - IP_HEADER_LEN) & !7;
+        // the identification must be the same in all fragments, and it must not be 0
+        let id = rand::random_range(1..=u16::MAX);
+        let ip_payload = &packet.as_slice()[IP_HEADER_LEN..];
+        let packets = ip_payload
+            .chunks(chunk_len)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let offset = i * chunk_len;
+                let more = offset + chunk.len() < ip_payload.len();
+
+                let mut fragment = Packet::alloc(IP_HEADER_LEN, IP_HEADER_LEN, chunk);
+                let header = fragment.as_fragment_header();
+                header
+                    .as_slice_mut()
+                    .copy_from_slice(&packet.as_slice()[..IP_HEADER_LEN]);
+                header.tot_len = ((IP_HEADER_LEN + chunk.len()) as u16).to_be();
+                header.id = id.to_be();
+                header.frag_off = ((offset / 8) as u16 | if more { IP_MF } else { 0 }).to_be();
+                header.finish();
+                fragment
+            })
+            .collect();
+
+        SendParam { addr, packets }
+    }
+
+    fn build_packet_v6(
+        src: (Ipv6Addr, u16),
+        dst: (Ipv6Addr, u16),
+        buf: &[u8],
+        mtu: usize,
+    ) -> SendParam {
+        let (src_ip, src_port) = src;
+        let (dst_ip, dst_port) = dst;
+        let udp_len = (UDP_HEADER_LEN + buf.len()) as u16;
+        let mut packet = Packet::new_v6(buf);
+
+        let (ip_header, udp_header) = packet.as_v6_headers();
+        // build UDP header
+        udp_header.sport = src_port.to_be();
+        udp_header.dport = dst_port.to_be();
+        udp_header.len = udp_len.to_be();
+        // UDP checksum is mandatory for IPv6 (RFC 8200, section 8.1)
+        let mut pseudo_header = [0u8; 40];
+        pseudo_header[..16].copy_from_slice(&src_ip.octets());
+        pseudo_header[16..32].copy_from_slice(&dst_ip.octets());
+        pseudo_header[32..36].copy_from_slice(&(udp_len as u32).to_be_bytes());
+        pseudo_header[39] = libc::IPPROTO_UDP as u8;
+        udp_header.finish(&pseudo_header, buf);
+
+        // build IPv6 header
+        // version=6, traffic class=0, flow label=0
+        ip_header.ver_tc_flow = (6u32 << 28).to_be();
+        ip_header.payload_len = udp_len.to_be();
+        ip_header.next_header = libc::IPPROTO_UDP as u8;
+        ip_header.hop_limit = 64;
+        ip_header.saddr = src_ip.octets();
+        ip_header.daddr = dst_ip.octets();
+
+        // port must be 0 (or the protocol number) for IPv6 raw sockets
+        let addr = SockAddr::from(SocketAddr::new(dst_ip.into(), 0));
+
+        if packet.len() <= mtu {
+            return SendParam {
+                addr,
+                packets: vec![packet],
+            };
+        }
+
+        // IPv6 routers never fragment, the source has to insert fragment headers (RFC 8200, section 4.5)
+        let chunk_len = (mtu - IPV6_HEADER_LEN - IPV6_FRAGMENT_HEADER_LEN) & !7;
+        let id = rand::random::<u32>();
+        let fragmentable = &packet.as_slice()[IPV6_HEADER_LEN..];
+        let packets = fragmentable
+            .chunks(chunk_len)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let offset = i * chunk_len;
+                let more = offset + chunk.len() < fragmentable.len();
+
+                let header_len = IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN;
+                let mut fragment = Packet::alloc(header_len, header_len, chunk);
+                let (ip_header, frag_header) = fragment.as_v6_fragment_headers();
+                ip_header.ver_tc_flow = (6u32 << 28).to_be();
+                ip_header.payload_len = ((IPV6_FRAGMENT_HEADER_LEN + chunk.len()) as u16).to_be();
+                ip_header.next_header = IPPROTO_FRAGMENT;
+                ip_header.hop_limit = 64;
+                ip_header.saddr = src_ip.octets();
+                ip_header.daddr = dst_ip.octets();
+
+                frag_header.next_header = libc::IPPROTO_UDP as u8;
+                frag_header.reserved = 0;
+                // offset is in 8-octet units, starting from bit 3
+                frag_header.frag_off = (offset as u16 | if more { IPV6_MF } else { 0 }).to_be();
+                frag_header.id = id.to_be();
+                fragment
+            })
+            .collect();
+
+        SendParam { addr, packets }
+    }
+}
+
+impl Drop for RawSocket {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(*self.fd.get_ref());
         }
     }
 }
@@ -246,20 +618,123 @@ fn test_ub() {
 
 #[test]
 fn test_ub_2() {
-    use std::net::Ipv4Addr;
     let src_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 52345);
     let dst_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 52346);
     let buf = b"hello raw socket";
-    let param = RawSocket::build_packet(src_addr, dst_addr, buf);
-    let buf = param.packet.data;
-    let buf_slice = unsafe { slice::from_raw_parts(buf as *const u8, param.len) };
+    let param = RawSocket::build_packet(src_addr, dst_addr, buf, DEFAULT_MTU).unwrap();
+    let buf_slice = param.packets[0].as_slice();
     println!("built packet: {:?}", buf_slice);
 }
 
+#[test]
+fn test_build_packet_v4_checksum() {
+    let src_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 53);
+    let dst_addr = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 100).into(), 52346);
+    let buf = b"hello raw socket!";
+    let param = RawSocket::build_packet(src_addr, dst_addr, buf, DEFAULT_MTU).unwrap();
+    assert_eq!(param.packets.len(), 1);
+    let packet = param.packets[0].as_slice();
+    assert_eq!(packet.len(), IP_HEADER_LEN + UDP_HEADER_LEN + buf.len());
+
+    // verifying the checksum over IP header results in 0
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut checksum = Checksum::default();
+        checksum.add(&packet[..IP_HEADER_LEN]);
+        assert_eq!(checksum.finish(), 0);
+    }
+
+    // verifying the checksum over pseudo header + UDP datagram results in 0
+    let mut checksum = Checksum::default();
+    checksum.add(&packet[12..20]);
+    checksum.add(&[0, libc::IPPROTO_UDP as u8]);
+    checksum.add(&((UDP_HEADER_LEN + buf.len()) as u16).to_be_bytes());
+    checksum.add(&packet[IP_HEADER_LEN..]);
+    assert_eq!(checksum.finish(), 0);
+}
+
+#[test]
+fn test_build_packet_v4_fragments() {
+    let src_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 53);
+    let dst_addr = SocketAddr::new(Ipv4Addr::new(192, 168, 1, 100).into(), 52346);
+    let buf = (0..3000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let param = RawSocket::build_packet(src_addr, dst_addr, &buf, 1400).unwrap();
+    assert_eq!(param.packets.len(), 3);
+
+    let mut reassembled = Vec::new();
+    let id = param.packets[0].as_slice()[4..6].to_vec();
+    assert_ne!(id, [0, 0]);
+    for (i, packet) in param.packets.iter().enumerate() {
+        let packet = packet.as_slice();
+        assert!(packet.len() <= 1400);
+        assert_eq!(&packet[4..6], &id[..]);
+        cfg_if! {
+            if #[cfg(any(target_os = "linux", target_os = "android"))] {
+                let frag_off = u16::from_be_bytes([packet[6], packet[7]]);
+            } else {
+                let frag_off = u16::from_ne_bytes([packet[6], packet[7]]);
+            }
+        }
+        assert_eq!((frag_off & 0x1fff) as usize * 8, reassembled.len());
+        assert_eq!(frag_off & IP_MF != 0, i + 1 < param.packets.len());
+        reassembled.extend_from_slice(&packet[IP_HEADER_LEN..]);
+    }
+    assert_eq!(reassembled.len(), UDP_HEADER_LEN + buf.len());
+    assert_eq!(&reassembled[UDP_HEADER_LEN..], &buf[..]);
+}
+
+#[test]
+fn test_build_packet_v6() {
+    let src_ip = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+    let src_addr = SocketAddr::new(src_ip.into(), 53);
+    let dst_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 52346);
+    let buf = b"hello raw socket";
+    let param = RawSocket::build_packet(src_addr, dst_addr, buf, DEFAULT_MTU).unwrap();
+    let packet = param.packets[0].as_slice();
+    assert_eq!(packet.len(), IPV6_HEADER_LEN + UDP_HEADER_LEN + buf.len());
+    assert_eq!(packet[0] >> 4, 6);
+    assert_eq!(&packet[8..24], &src_ip.octets());
+    assert_eq!(&packet[IPV6_HEADER_LEN + UDP_HEADER_LEN..], buf);
+
+    // verifying the checksum over pseudo header + UDP datagram results in 0
+    let mut checksum = Checksum::default();
+    checksum.add(&packet[8..40]);
+    checksum.add(&((UDP_HEADER_LEN + buf.len()) as u32).to_be_bytes());
+    checksum.add(&[0, 0, 0, libc::IPPROTO_UDP as u8]);
+    checksum.add(&packet[IPV6_HEADER_LEN..]);
+    assert_eq!(checksum.finish(), 0);
+
+    let src_v4 = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 53);
+    assert!(RawSocket::build_packet(src_v4, dst_addr, buf, DEFAULT_MTU).is_err());
+}
+
+#[test]
+fn test_build_packet_v6_fragments() {
+    let src_addr = SocketAddr::new("2001:db8::1".parse::<Ipv6Addr>().unwrap().into(), 53);
+    let dst_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 52346);
+    let buf = (0..3000u32).map(|i| i as u8).collect::<Vec<_>>();
+    // smaller than the minimum MTU of IPv6, it should be raised to 1280
+    let param = RawSocket::build_packet(src_addr, dst_addr, &buf, 576).unwrap();
+    assert_eq!(param.packets.len(), 3);
+
+    let header_len = IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN;
+    let mut reassembled = Vec::new();
+    for (i, packet) in param.packets.iter().enumerate() {
+        let packet = packet.as_slice();
+        assert!(packet.len() <= MINIMUM_MTU_V6);
+        assert_eq!(packet[6], IPPROTO_FRAGMENT);
+        assert_eq!(packet[IPV6_HEADER_LEN], libc::IPPROTO_UDP as u8);
+        let frag_off = u16::from_be_bytes([packet[42], packet[43]]);
+        assert_eq!((frag_off & !7) as usize, reassembled.len());
+        assert_eq!(frag_off & IPV6_MF != 0, i + 1 < param.packets.len());
+        reassembled.extend_from_slice(&packet[header_len..]);
+    }
+    assert_eq!(&reassembled[UDP_HEADER_LEN..], &buf[..]);
+}
+
 #[ignore = "this test needs root privilege"]
 #[tokio::test]
 async fn test_sendto() {
-    use std::net::Ipv4Addr;
     use tokio::net::UdpSocket;
     let raw_socket = RawSocket::new().unwrap();
     let src_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 52345);
@@ -279,3 +754,39 @@ async fn test_sendto() {
     assert_eq!(*buf, recv_buf);
     assert_eq!(n, recv_n);
 }
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_sendto_v6() {
+    use tokio::net::UdpSocket;
+    let raw_socket = RawSocket::new_v6().unwrap();
+    let src_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 52347);
+    let dst_addr = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 52348);
+    let socket = UdpSocket::bind(dst_addr).await.unwrap();
+    let buf = b"hello raw socket";
+    let n = raw_socket.send_to(src_addr, dst_addr, buf).await.unwrap();
+    assert_eq!(n, buf.len());
+    let mut recv_buf = [0u8; 64];
+    let (recv_n, addr) = socket.recv_from(&mut recv_buf).await.unwrap();
+    assert_eq!(&recv_buf[..recv_n], buf);
+    assert_eq!(addr, src_addr);
+}
+
+#[ignore = "this test needs root privilege"]
+#[tokio::test]
+async fn test_sendto_fragments() {
+    use tokio::net::UdpSocket;
+    let mut raw_socket = RawSocket::new().unwrap();
+    raw_socket.set_mtu(DEFAULT_MTU);
+    let src_addr = SocketAddr::new(Ipv4Addr::new(1, 2, 2, 2).into(), 52349);
+    let dst_addr = SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 52350);
+    let socket = UdpSocket::bind(dst_addr).await.unwrap();
+    let buf = (0..4000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let n = raw_socket.send_to(src_addr, dst_addr, &buf).await.unwrap();
+    assert_eq!(n, buf.len());
+    let mut recv_buf = [0u8; 8192];
+    let (recv_n, addr) = socket.recv_from(&mut recv_buf).await.unwrap();
+    assert_eq!(&recv_buf[..recv_n], &buf[..]);
+    assert_eq!(addr, src_addr);
+}
@@ -1,5 +1,5 @@
 use std::{io, net::SocketAddr};
-use tokio::net::UdpSocket;
+use tokio::net::{UdpSocket, lookup_host};
 
 use crate::utils::socks::udp_client::Socks5UdpClient;
 
@@ -12,6 +12,13 @@ pub trait BasicSocket: Sized + Send + 'static {
     where
         SocketAddr: From<A>,
         A: Send;
+    /// Send `buf` to `domain:port`, the domain name is resolved by the proxy, or locally
+    fn send_to_domain(
+        &self,
+        buf: &[u8],
+        domain: &str,
+        port: u16,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
     fn recv_from(
         &self,
         buf: &mut [u8],
@@ -26,6 +33,20 @@ impl BasicSocket for UdpSocket {
         let addr: SocketAddr = addr.into();
         self.send_to(buf, addr).await
     }
+    async fn send_to_domain(&self, buf: &[u8], domain: &str, port: u16) -> io::Result<usize> {
+        // resolved on every packet, the system resolver is expected to cache it
+        let ipv4 = self.local_addr()?.is_ipv4();
+        let addr = lookup_host((domain, port))
+            .await?
+            .find(|addr| addr.is_ipv4() == ipv4)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no address of {domain} in the socket's address family"),
+                )
+            })?;
+        self.send_to(buf, addr).await
+    }
     async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         self.recv_from(buf).await
     }
@@ -36,7 +57,12 @@ impl BasicSocket for Socks5UdpClient {
     where
         SocketAddr: From<A>,
     {
-        let n = self.send_to(0, buf, addr).await?;
+        let n = self.send_to(0, buf, SocketAddr::from(addr)).await?;
+        Ok(n)
+    }
+    async fn send_to_domain(&self, buf: &[u8], domain: &str, port: u16) -> io::Result<usize> {
+        let target = socks5::Address::DomainNameAddress(domain.to_owned(), port);
+        let n = self.send_to(0, buf, target).await?;
         Ok(n)
     }
     async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
@@ -170,6 +170,10 @@ pub enum Error {
     UnsupportedPasswdAuthVersion(u8),
     #[error("username/password authentication invalid request")]
     PasswdAuthInvalidRequest,
+    #[error("authentication method {0:#x} is not acceptable")]
+    AuthMethodNotAcceptable(u8),
+    #[error("username/password authentication failed with status {0:#x}")]
+    PasswdAuthFailure(u8),
     #[error("{0}")]
     Reply(Reply),
 }
@@ -197,9 +201,24 @@ impl Error {
             Self::UnsupportedCommand(..) => Reply::CommandNotSupported,
             Self::UnsupportedPasswdAuthVersion(..) => Reply::GeneralFailure,
             Self::PasswdAuthInvalidRequest => Reply::GeneralFailure,
+            Self::AuthMethodNotAcceptable(..) => Reply::GeneralFailure,
+            Self::PasswdAuthFailure(..) => Reply::GeneralFailure,
             Self::Reply(r) => r,
         }
     }
+
+    /// Reason label of the metrics, see `crate::metrics::io_failure_reason`
+    pub fn failure_reason(&self) -> &'static str {
+        match self {
+            Self::IoError(err) => crate::metrics::io_failure_reason(err),
+            Self::UnsupportedPasswdAuthVersion(..)
+            | Self::PasswdAuthInvalidRequest
+            | Self::AuthMethodNotAcceptable(..)
+            | Self::PasswdAuthFailure(..) => "auth",
+            Self::Reply(..) => "rejected",
+            _ => "protocol",
+        }
+    }
 }
 
 /// SOCKS5 address type
@@ -971,7 +990,7 @@ impl PasswdAuthResponse {
         R: AsyncRead + Unpin,
     {
         let mut buf = [0u8; 2];
-        let _ = r.read_exact(&mut buf).await;
+        let _ = r.read_exact(&mut buf).await?;
 
         if buf[0] != 0x01 {
             return Err(Error::UnsupportedPasswdAuthVersion(buf[0]));
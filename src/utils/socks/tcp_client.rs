@@ -7,9 +7,12 @@ use std::{
     task::{self, Poll},
 };
 
-use crate::utils::socks::socks5::{
-    self, Address, Command, Error, HandshakeRequest, HandshakeResponse, Reply, TcpRequestHeader,
-    TcpResponseHeader,
+use crate::utils::{
+    config::Credentials,
+    socks::socks5::{
+        self, Address, Command, Error, HandshakeRequest, HandshakeResponse, PasswdAuthRequest,
+        PasswdAuthResponse, Reply, TcpRequestHeader, TcpResponseHeader,
+    },
 };
 use log::trace;
 use pin_project::pin_project;
@@ -32,18 +35,35 @@ impl Socks5TcpClient {
         A: Into<Address>,
         P: ToSocketAddrs,
     {
-        let mut s = TcpStream::connect(proxy).await?;
-
-        // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("client connected, going to send handshake: {:?}", hs);
-
-        hs.write_to(&mut s).await?;
+        Self::connect_with_auth(addr, proxy, None).await
+    }
 
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
+    /// Connects to `addr` via `proxy`, authenticates with username/password if `auth` is provided
+    pub async fn connect_with_auth<A, P>(
+        addr: A,
+        proxy: P,
+        auth: Option<&Credentials>,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Address>,
+        P: ToSocketAddrs,
+    {
+        let s = TcpStream::connect(proxy).await?;
+        Self::connect_on(s, addr, auth).await
+    }
 
-        trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+    /// Connects to `addr` via the proxy that `s` is connected to, authenticates with
+    /// username/password if `auth` is provided
+    pub async fn connect_on<A>(
+        mut s: TcpStream,
+        addr: A,
+        auth: Option<&Credentials>,
+    ) -> Result<Self, Error>
+    where
+        A: Into<Address>,
+    {
+        // 1. Handshake
+        handshake(&mut s, auth).await?;
 
         // 2. Send request header
         let h = TcpRequestHeader::new(Command::TcpConnect, addr.into());
@@ -69,18 +89,35 @@ impl Socks5TcpClient {
         A: Into<Address>,
         P: ToSocketAddrs,
     {
-        let mut s = TcpStream::connect(proxy).await?;
-
-        // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("client connected, going to send handshake: {:?}", hs);
-
-        hs.write_to(&mut s).await?;
+        Self::udp_associate_with_auth(addr, proxy, None).await
+    }
 
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
+    /// UDP Associate `addr` via `proxy`, authenticates with username/password if `auth` is provided
+    pub async fn udp_associate_with_auth<A, P>(
+        addr: A,
+        proxy: P,
+        auth: Option<&Credentials>,
+    ) -> Result<(Self, Address), Error>
+    where
+        A: Into<Address>,
+        P: ToSocketAddrs,
+    {
+        let s = TcpStream::connect(proxy).await?;
+        Self::udp_associate_on(s, addr, auth).await
+    }
 
-        trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+    /// UDP Associate `addr` via the proxy that `s` is connected to, authenticates with
+    /// username/password if `auth` is provided
+    pub async fn udp_associate_on<A>(
+        mut s: TcpStream,
+        addr: A,
+        auth: Option<&Credentials>,
+    ) -> Result<(Self, Address), Error>
+    where
+        A: Into<Address>,
+    {
+        // 1. Handshake
+        handshake(&mut s, auth).await?;
 
         // 2. Send request header
         let h = TcpRequestHeader::new(Command::UdpAssociate, addr.into());
@@ -99,6 +136,51 @@ impl Socks5TcpClient {
     }
 }
 
+/// Negotiate the authentication method, and authenticate with username/password (RFC 1929)
+/// if the server chooses it
+async fn handshake(s: &mut TcpStream, auth: Option<&Credentials>) -> Result<(), Error> {
+    let methods = match auth {
+        Some(..) => vec![
+            socks5::SOCKS5_AUTH_METHOD_NONE,
+            socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+        ],
+        None => vec![socks5::SOCKS5_AUTH_METHOD_NONE],
+    };
+    let hs = HandshakeRequest::new(methods);
+    trace!("client connected, going to send handshake: {:?}", hs);
+
+    hs.write_to(s).await?;
+
+    let hsp = HandshakeResponse::read_from(s).await?;
+
+    trace!("got handshake response: {:?}", hsp);
+    match (hsp.chosen_method, auth) {
+        (socks5::SOCKS5_AUTH_METHOD_NONE, _) => Ok(()),
+        (socks5::SOCKS5_AUTH_METHOD_PASSWORD, Some(auth)) => {
+            // RFC 1929: both fields are 1 to 255 octets
+            let valid_len = |s: &str| (1..=u8::MAX as usize).contains(&s.len());
+            if !valid_len(&auth.username) || !valid_len(&auth.password) {
+                return Err(Error::PasswdAuthInvalidRequest);
+            }
+
+            let req = PasswdAuthRequest::new(auth.username.as_bytes(), auth.password.as_bytes());
+            req.write_to(s).await?;
+
+            let resp = PasswdAuthResponse::read_from(s).await?;
+            trace!(
+                "got username/password authentication status: {:#x}",
+                resp.status
+            );
+            match resp.status {
+                // RFC 1929: A STATUS field of X'00' indicates success
+                0x00 => Ok(()),
+                status => Err(Error::PasswdAuthFailure(status)),
+            }
+        }
+        (method, _) => Err(Error::AuthMethodNotAcceptable(method)),
+    }
+}
+
 impl AsyncRead for Socks5TcpClient {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -129,3 +211,42 @@ impl AsyncWrite for Socks5TcpClient {
         self.project().stream.poll_shutdown(cx)
     }
 }
+
+#[tokio::test]
+async fn test_handshake_auth_failure() {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        // chooses username/password, and rejects the credentials
+        let (mut s, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        s.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0x05, 2, 0x00, 0x02]);
+        s.write_all(&[0x05, 0x02]).await.unwrap();
+        let req = PasswdAuthRequest::read_from(&mut s).await.unwrap();
+        assert_eq!(req.uname, b"user");
+        assert_eq!(req.passwd, b"pass");
+        s.write_all(&[0x01, 0x01]).await.unwrap();
+
+        // no acceptable methods
+        let (mut s, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 3];
+        s.read_exact(&mut buf).await.unwrap();
+        s.write_all(&[0x05, 0xff]).await.unwrap();
+    });
+
+    let auth = Credentials {
+        username: "user".to_owned(),
+        password: "pass".to_owned(),
+    };
+    let target: std::net::SocketAddr = "127.0.0.1:80".parse().unwrap();
+    let err = Socks5TcpClient::connect_with_auth(target, proxy_addr, Some(&auth)).await;
+    assert!(matches!(err, Err(Error::PasswdAuthFailure(0x01))));
+    let err = Socks5TcpClient::connect(target, proxy_addr).await;
+    assert!(matches!(err, Err(Error::AuthMethodNotAcceptable(0xff))));
+}
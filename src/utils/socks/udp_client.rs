@@ -1,15 +1,15 @@
 //! modified from shadowsocks-service/src/local/socks/client/socks5/udp_client.rs
 //! UDP relay client
 
-use std::{
-    io::{self, Cursor},
-    net::SocketAddr,
-};
+use std::io::{self, Cursor};
 
 use bytes::{BufMut, BytesMut};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
 
-use crate::utils::socks::socks5::{Address, Error, UdpAssociateHeader};
+use crate::utils::{
+    config::Credentials,
+    socks::socks5::{Address, Error, UdpAssociateHeader},
+};
 
 use super::tcp_client::Socks5TcpClient;
 
@@ -28,10 +28,15 @@ impl Socks5UdpClient {
     where
         A: ToSocketAddrs,
     {
-        Ok(Self {
-            socket: UdpSocket::bind(addrs).await?,
+        Ok(Self::new(UdpSocket::bind(addrs).await?))
+    }
+
+    /// Create a new UDP associate client on a bound `socket`
+    pub fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
             assoc_client: None,
-        })
+        }
     }
 
     /// Create a new UDP associate to `proxy`
@@ -39,6 +44,29 @@ impl Socks5UdpClient {
     where
         P: ToSocketAddrs,
     {
+        self.associate_with_auth(proxy, None).await
+    }
+
+    /// Create a new UDP associate to `proxy`, authenticates with username/password if `auth` is provided
+    pub async fn associate_with_auth<P>(
+        &mut self,
+        proxy: P,
+        auth: Option<&Credentials>,
+    ) -> Result<(), Error>
+    where
+        P: ToSocketAddrs,
+    {
+        let stream = TcpStream::connect(proxy).await?;
+        self.associate_on(stream, auth).await
+    }
+
+    /// Create a new UDP associate to the proxy that `stream` is connected to, authenticates with
+    /// username/password if `auth` is provided
+    pub async fn associate_on(
+        &mut self,
+        stream: TcpStream,
+        auth: Option<&Credentials>,
+    ) -> Result<(), Error> {
         if self.assoc_client.is_some() {
             let err = io::Error::other("udp is associated");
             return Err(err.into());
@@ -47,7 +75,8 @@ impl Socks5UdpClient {
         // The actual bind address, tell the proxy that I am going to send packets from this address
         let local_addr = self.socket.local_addr()?;
 
-        let (assoc_client, proxy_addr) = Socks5TcpClient::udp_associate(local_addr, proxy).await?;
+        let (assoc_client, proxy_addr) =
+            Socks5TcpClient::udp_associate_on(stream, local_addr, auth).await?;
         match proxy_addr {
             Address::SocketAddress(sa) => self.socket.connect(sa).await?,
             // FIXME: `connect` will use tokio's builtin DNS resolver.
@@ -65,10 +94,10 @@ impl Socks5UdpClient {
     /// Returns a future that sends data on the socket to the given address.
     pub async fn send_to<A>(&self, frag: u8, buf: &[u8], target: A) -> Result<usize, Error>
     where
-        SocketAddr: From<A>,
+        A: Into<Address>,
     {
         self.check_associated()?;
-        let target = Address::SocketAddress(target.into());
+        let target = target.into();
         let header = UdpAssociateHeader::new(frag, target);
         let header_len = header.serialized_len();
         let mut send_buf = BytesMut::with_capacity(header.serialized_len() + buf.len());